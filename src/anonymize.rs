@@ -0,0 +1,310 @@
+use bson::doc;
+use serenity::model::id::{GuildId, UserId};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archived_message::{
+        effective_content, normalize_content, ArchivedMessage, ArchivedMessageIteration,
+    },
+    config::{Config, ConfigLoadSaveError},
+    mong::{
+        get_mong, messages_collection, system_events_collection, to_stored_document, SYSTEM_EVENTS,
+    },
+    system_event::SystemEvent,
+    MainError,
+};
+
+/// Replace every user id in a guild's archive with a pseudonym derived from
+/// the configured salt, so who talked to whom stays visible but not who they
+/// are, in the collections of all bots
+///
+/// The same user gets the same pseudonym everywhere in the guild, documents
+/// already anonymized are skipped so running it again is harmless.
+pub async fn run(config: Config, guild_id: GuildId, redact_content: bool) -> Result<(), MainError> {
+    let salt = config
+        .anonymization_salt
+        .as_deref()
+        .ok_or(ConfigLoadSaveError::Missing {
+            field: "anonymization_salt",
+        })?;
+    let pseudonymize = |user_id: UserId| pseudonym(salt, guild_id, user_id);
+    let mong = get_mong(&config.mong_connstring).await?;
+
+    for location in config.message_locations() {
+        let messages = messages_collection(&mong, &location);
+        let filter = doc! {
+            "guild_id": guild_id.to_string(),
+            "anonymized": { "$ne": true },
+        };
+        let mut cursor = messages.find(filter, None).await?;
+        let mut count = 0u64;
+        while cursor.advance().await? {
+            let mut message = cursor.deserialize_current()?;
+            anonymize_message(&mut message, pseudonymize, redact_content);
+            let mut update = to_stored_document(&message).map_err(mongodb::error::Error::from)?;
+            update.insert("anonymized", true);
+            messages
+                .update_one(
+                    doc! { "id": message.id().to_string() },
+                    doc! { "$set": update },
+                    None,
+                )
+                .await?;
+            count += 1;
+        }
+        println!(
+            "Anonymized {count} messages of guild {guild_id} in {}",
+            location.collection
+        );
+    }
+
+    // Member joins are the only system events that name a user
+    let system_events = system_events_collection(&mong, &config.collection_location(SYSTEM_EVENTS));
+    let filter = doc! {
+        "event_type": "MemberJoin",
+        "guild_id": guild_id.to_string(),
+        "anonymized": { "$ne": true },
+    };
+    let mut cursor = system_events.find(filter, None).await?;
+    let mut count = 0u64;
+    while cursor.advance().await? {
+        let SystemEvent::MemberJoin(mut join) = cursor.deserialize_current()? else {
+            continue;
+        };
+        join.user_id = pseudonymize(join.user_id);
+        system_events
+            .update_one(
+                doc! { "event_type": "MemberJoin", "message_id": join.message_id.to_string() },
+                doc! { "$set": { "user_id": join.user_id.to_string(), "anonymized": true } },
+                None,
+            )
+            .await?;
+        count += 1;
+    }
+    println!("Anonymized {count} system events of guild {guild_id}");
+
+    Ok(())
+}
+
+/// A stable stand-in for a user within one guild
+pub fn pseudonym(salt: &str, guild_id: GuildId, user_id: UserId) -> UserId {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(guild_id.0.to_be_bytes())
+        .chain_update(user_id.0.to_be_bytes())
+        .finalize();
+    UserId(u64::from_be_bytes(
+        digest[..8]
+            .try_into()
+            .expect("SHA-256 digests are 32 bytes"),
+    ))
+}
+
+fn anonymize_message(
+    message: &mut ArchivedMessage,
+    pseudonymize: impl Fn(UserId) -> UserId + Copy,
+    redact_content: bool,
+) {
+    match message {
+        ArchivedMessage::Full(m) => {
+            m.author_id = pseudonymize(m.author_id);
+            if let Some(interaction) = &mut m.interaction {
+                anonymize_user(&mut interaction.user, pseudonymize);
+            }
+            if let Some(referenced) = &mut m.referenced_message {
+                referenced.author_id = pseudonymize(referenced.author_id);
+                referenced.content =
+                    anonymize_content(&referenced.content, pseudonymize, redact_content);
+            }
+            anonymize_iterations(&mut m.iterations, pseudonymize, redact_content);
+        }
+        ArchivedMessage::FullDeleted(m) => {
+            m.author_id = pseudonymize(m.author_id);
+            if let Some(interaction) = &mut m.interaction {
+                anonymize_user(&mut interaction.user, pseudonymize);
+            }
+            if let Some(referenced) = &mut m.referenced_message {
+                referenced.author_id = pseudonymize(referenced.author_id);
+                referenced.content =
+                    anonymize_content(&referenced.content, pseudonymize, redact_content);
+            }
+            anonymize_iterations(&mut m.iterations, pseudonymize, redact_content);
+        }
+        ArchivedMessage::Incomplete(m) => {
+            m.author_id = m.author_id.map(pseudonymize);
+            anonymize_iterations(&mut m.iterations, pseudonymize, redact_content);
+        }
+        ArchivedMessage::IncompleteDeleted(m) => {
+            m.author_id = m.author_id.map(pseudonymize);
+            anonymize_iterations(&mut m.iterations, pseudonymize, redact_content);
+        }
+        ArchivedMessage::UnknownDeleted(_) => {}
+    }
+}
+
+fn anonymize_user(user: &mut serenity::model::user::User, pseudonymize: impl Fn(UserId) -> UserId) {
+    user.id = pseudonymize(user.id);
+    user.name = user.id.to_string();
+    user.discriminator = 0;
+    user.avatar = None;
+    user.banner = None;
+}
+
+fn anonymize_iterations(
+    iterations: &mut [ArchivedMessageIteration],
+    pseudonymize: impl Fn(UserId) -> UserId + Copy,
+    redact_content: bool,
+) {
+    for iteration in iterations {
+        iteration.content = anonymize_content(&iteration.content, pseudonymize, redact_content);
+        if iteration.normalized_content.is_some() {
+            iteration.normalized_content = Some(normalize_content(&iteration.content));
+        }
+        if redact_content {
+            iteration.embeds.clear();
+        }
+        if iteration.effective_content.is_some() {
+            iteration.effective_content = Some(effective_content(iteration));
+        }
+    }
+}
+
+/// Blank the content, or swap the ids in user mentions for pseudonyms
+fn anonymize_content(
+    content: &str,
+    pseudonymize: impl Fn(UserId) -> UserId,
+    redact_content: bool,
+) -> String {
+    if redact_content {
+        return String::new();
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("<@") {
+        out.push_str(&rest[..start]);
+        let mention = &rest[start + 2..];
+        let (prefix, digits) = match mention.strip_prefix('!') {
+            Some(digits) => ("<@!", digits),
+            None => ("<@", mention),
+        };
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        match (digits[..end].parse::<u64>(), digits[end..].starts_with('>')) {
+            (Ok(id), true) => {
+                out.push_str(&format!("{prefix}{}>", pseudonymize(UserId(id))));
+                rest = &digits[end + 1..];
+            }
+            _ => {
+                out.push_str("<@");
+                rest = mention;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    const SALT: &str = "salt";
+    const GUILD: GuildId = GuildId(30);
+
+    fn pseudonymize(user_id: UserId) -> UserId {
+        pseudonym(SALT, GUILD, user_id)
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_within_a_guild() {
+        assert_eq!(pseudonymize(UserId(100)), pseudonymize(UserId(100)));
+        assert_ne!(pseudonymize(UserId(100)), UserId(100));
+        assert_ne!(pseudonymize(UserId(100)), pseudonymize(UserId(101)));
+    }
+
+    #[test]
+    fn pseudonyms_differ_between_guilds_and_salts() {
+        let user = UserId(100);
+        assert_ne!(
+            pseudonym(SALT, GUILD, user),
+            pseudonym(SALT, GuildId(31), user)
+        );
+        assert_ne!(
+            pseudonym(SALT, GUILD, user),
+            pseudonym("pepper", GUILD, user)
+        );
+    }
+
+    #[test]
+    fn mentions_get_pseudonyms() {
+        let content = anonymize_content("hi <@100> and <@!101>, <@nope>", pseudonymize, false);
+        assert_eq!(
+            content,
+            format!(
+                "hi <@{}> and <@!{}>, <@nope>",
+                pseudonymize(UserId(100)),
+                pseudonymize(UserId(101))
+            )
+        );
+    }
+
+    #[test]
+    fn removes_user_ids_from_messages() {
+        let message = test_util::message(
+            1,
+            json!({
+                "content": "ping <@101>",
+                "interaction": {
+                    "id": "5",
+                    "type": 2,
+                    "name": "command",
+                    "user": {
+                        "id": "101",
+                        "username": "invoker",
+                        "discriminator": "1234",
+                        "avatar": "abc",
+                    },
+                },
+            }),
+        );
+        let session = Session::new(None);
+        let mut message =
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        anonymize_message(&mut message, pseudonymize, false);
+
+        let ArchivedMessage::Full(message) = &message else {
+            unreachable!()
+        };
+        assert_eq!(message.author_id, pseudonymize(UserId(100)));
+        assert_eq!(
+            message.iterations[0].content,
+            format!("ping <@{}>", pseudonymize(UserId(101)))
+        );
+        let user = &message.interaction.as_ref().unwrap().user;
+        assert_eq!(user.id, pseudonymize(UserId(101)));
+        assert_eq!(user.name, user.id.to_string());
+        assert_eq!(user.discriminator, 0);
+        assert_eq!(user.avatar, None);
+
+        // Nothing of the original ids is left anywhere in the document
+        let document = bson::to_document(message).unwrap().to_string();
+        assert!(!document.contains("\"100\""));
+        assert!(!document.contains("\"101\""));
+        assert!(!document.contains("<@101>"));
+        assert!(!document.contains("invoker"));
+    }
+
+    #[test]
+    fn redacting_blanks_content() {
+        let message = test_util::message(1, json!({ "content": "secret" }));
+        let session = Session::new(None);
+        let mut message =
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        anonymize_message(&mut message, pseudonymize, true);
+        assert_eq!(message.latest_iteration().unwrap().content, "");
+    }
+}
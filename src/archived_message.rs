@@ -5,16 +5,22 @@ use chrono::{
 use serde::{Deserialize, Serialize};
 use serenity::model::{
     application::{component::ActionRow, interaction::MessageInteraction},
-    channel::{Attachment, Embed, Message, MessageType},
+    channel::{Attachment, Channel, ChannelType, Embed, Message, MessageType},
     event::MessageUpdateEvent,
     id::*,
     prelude::MessageReference,
     sticker::StickerItem,
     timestamp::Timestamp as SerenityTimestamp,
 };
-use thiserror::Error;
 use uuid::Uuid;
 
+use crate::{
+    attachment_download::StoredAttachment,
+    reference::{CrosspostSource, ReferencedMessage},
+    session::Session,
+    voice_message::VoiceAttachment,
+};
+
 pub type Timestamp = DateTime<Utc>;
 
 pub fn convert_ts(ts: SerenityTimestamp) -> Timestamp {
@@ -25,6 +31,83 @@ pub fn convert_ts(ts: SerenityTimestamp) -> Timestamp {
     )
 }
 
+/// Stores a timestamp as a BSON date, so Mongo's date operators and TTL
+/// indexes work on it, and as milliseconds in human readable formats like
+/// JSON exports
+///
+/// Only serializers that aren't human readable write dates, like the driver's
+/// and [`crate::mong::to_stored_bson`], plain `bson::to_bson` writes
+/// milliseconds. Older documents have milliseconds, or an RFC 3339 string for
+/// the deletion time of `UnknownDeleted` messages, which are still read.
+pub mod ts_bson_datetime {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Timestamp;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredTimestamp {
+        Date(bson::DateTime),
+        Millis(i64),
+        Text(String),
+    }
+
+    impl StoredTimestamp {
+        fn into_timestamp<E: serde::de::Error>(self) -> Result<Timestamp, E> {
+            match self {
+                Self::Date(date) => Ok(date.to_chrono()),
+                Self::Millis(millis) => Ok(bson::DateTime::from_millis(millis).to_chrono()),
+                Self::Text(text) => text.parse().map_err(E::custom),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(ts: &Timestamp, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            ts.timestamp_millis().serialize(serializer)
+        } else {
+            bson::DateTime::from_chrono(*ts).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        StoredTimestamp::deserialize(deserializer)?.into_timestamp()
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            ts: &Option<Timestamp>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match ts {
+                Some(ts) => serializer.serialize_some(&Stored(ts)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        /// Lets an optional timestamp go through the same serializer
+        struct Stored<'a>(&'a Timestamp);
+
+        impl Serialize for Stored<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Timestamp>, D::Error> {
+            Option::<StoredTimestamp>::deserialize(deserializer)?
+                .map(StoredTimestamp::into_timestamp)
+                .transpose()
+        }
+    }
+}
+
+pub use ts_bson_datetime::option as ts_bson_datetime_option;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "archive_type")]
 pub enum ArchivedMessage {
@@ -35,18 +118,190 @@ pub enum ArchivedMessage {
     UnknownDeleted(ArchivedMessageUnknownDeleted),
 }
 
+impl ArchivedMessage {
+    pub fn id(&self) -> MessageId {
+        match self {
+            Self::Full(m) => m.id,
+            Self::FullDeleted(m) => m.id,
+            Self::Incomplete(m) => m.id,
+            Self::IncompleteDeleted(m) => m.id,
+            Self::UnknownDeleted(m) => m.id,
+        }
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        match self {
+            Self::Full(m) => m.channel_id,
+            Self::FullDeleted(m) => m.channel_id,
+            Self::Incomplete(m) => m.channel_id,
+            Self::IncompleteDeleted(m) => m.channel_id,
+            Self::UnknownDeleted(m) => m.channel_id,
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<GuildId> {
+        match self {
+            Self::Full(m) => m.guild_id,
+            Self::FullDeleted(m) => m.guild_id,
+            Self::Incomplete(m) => m.guild_id,
+            Self::IncompleteDeleted(m) => m.guild_id,
+            Self::UnknownDeleted(m) => m.guild_id,
+        }
+    }
+
+    pub fn crosspost_source(&self) -> Option<&CrosspostSource> {
+        match self {
+            Self::Full(m) => m.crosspost_source.as_ref(),
+            Self::FullDeleted(m) => m.crosspost_source.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn started_thread_id(&self) -> Option<ChannelId> {
+        match self {
+            Self::Full(m) => m.started_thread_id,
+            Self::FullDeleted(m) => m.started_thread_id,
+            _ => None,
+        }
+    }
+
+    /// The message this one replies to
+    ///
+    /// Pins, crossposts and thread starters reference messages too, only
+    /// replies count.
+    pub fn replied_to(&self) -> Option<MessageId> {
+        let (kind, reference) = match self {
+            Self::Full(m) => (m.kind, m.message_reference.as_ref()),
+            Self::FullDeleted(m) => (m.kind, m.message_reference.as_ref()),
+            _ => return None,
+        };
+        match kind {
+            ArchivedMessageType::InlineReply => reference?.message_id,
+            _ => None,
+        }
+    }
+
+    pub fn latest_iteration(&self) -> Option<&ArchivedMessageIteration> {
+        match self {
+            Self::Full(m) => m.iterations.last(),
+            Self::FullDeleted(m) => m.iterations.last(),
+            Self::Incomplete(m) => m.iterations.last(),
+            Self::IncompleteDeleted(m) => m.iterations.last(),
+            Self::UnknownDeleted(_) => None,
+        }
+    }
+
+    /// Fill in the normalized content of iterations that don't have it yet
+    pub fn normalize_content(&mut self) {
+        let iterations = match self {
+            Self::Full(m) => &mut m.iterations,
+            Self::FullDeleted(m) => &mut m.iterations,
+            Self::Incomplete(m) => &mut m.iterations,
+            Self::IncompleteDeleted(m) => &mut m.iterations,
+            Self::UnknownDeleted(_) => return,
+        };
+        for iteration in iterations
+            .iter_mut()
+            .filter(|i| i.normalized_content.is_none())
+        {
+            iteration.normalized_content = Some(normalize_content(&iteration.content));
+        }
+    }
+
+    /// Fill in the effective content of every iteration, embeds can be added
+    /// to the latest one after the fact
+    pub fn index_embed_text(&mut self) {
+        let iterations = match self {
+            Self::Full(m) => &mut m.iterations,
+            Self::FullDeleted(m) => &mut m.iterations,
+            Self::Incomplete(m) => &mut m.iterations,
+            Self::IncompleteDeleted(m) => &mut m.iterations,
+            Self::UnknownDeleted(_) => return,
+        };
+        for iteration in iterations {
+            iteration.effective_content = Some(effective_content(iteration));
+        }
+    }
+
+    /// How often and when the message was edited, see [`EditStats`]
+    pub fn edit_stats(&self) -> EditStats {
+        let (iterations, deleted, first_is_edit) = match self {
+            Self::Full(m) => (m.iterations.as_slice(), false, false),
+            Self::FullDeleted(m) => (m.iterations.as_slice(), true, false),
+            // We first heard of these through an update, so even their first
+            // iteration is an edit
+            Self::Incomplete(m) => (m.iterations.as_slice(), false, true),
+            Self::IncompleteDeleted(m) => (m.iterations.as_slice(), true, true),
+            Self::UnknownDeleted(_) => ([].as_slice(), true, false),
+        };
+        let edits: Vec<&ArchivedMessageIteration> = iterations
+            .iter()
+            .skip(if first_is_edit { 0 } else { 1 })
+            .filter(|iteration| !iteration.auto_embed && !iteration.may_contain_gap)
+            .collect();
+        EditStats {
+            edit_count: edits.len(),
+            first_edit_at: edits.first().map(|iteration| iteration.timestamp),
+            last_edit_at: edits.last().map(|iteration| iteration.timestamp),
+            deleted,
+        }
+    }
+}
+
+/// Edits of a message as far as we saw them
+///
+/// Iterations added by link unfurling or recorded after a possible gap don't
+/// count, since they aren't known to be edits by the author.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EditStats {
+    pub edit_count: usize,
+    pub first_edit_at: Option<Timestamp>,
+    pub last_edit_at: Option<Timestamp>,
+    pub deleted: bool,
+}
+
+/// The Discord URL of a message, DMs use `@me` in place of the guild
+pub fn message_link(message: &ArchivedMessage) -> String {
+    let guild = match message.guild_id() {
+        Some(guild_id) => guild_id.to_string(),
+        None => "@me".to_string(),
+    };
+    format!(
+        "https://discord.com/channels/{guild}/{}/{}",
+        message.channel_id(),
+        message.id()
+    )
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ArchivedMessageFull {
     // Assumed to be static
     pub id: MessageId,
     pub channel_id: ChannelId,
+    /// What kind of channel the message was sent in, as of archiving it
+    #[serde(default)]
+    pub channel_type: ArchivedChannelType,
     pub guild_id: Option<GuildId>,
     pub author_id: UserId,
-    #[serde(with = "ts_milliseconds")]
+    #[serde(with = "ts_bson_datetime")]
     pub timestamp: Timestamp,
     #[serde(rename = "type")]
     pub kind: ArchivedMessageType,
+    /// The type number Discord sent, kept for types we don't know yet
+    #[serde(default)]
+    pub raw_message_type: Option<i64>,
     pub message_reference: Option<MessageReference>,
+    /// The referenced message as it was when this one was archived, if
+    /// resolving references is enabled
+    #[serde(default)]
+    pub referenced_message: Option<ReferencedMessage>,
+    /// The original of a crossposted announcement, which lives in another
+    /// guild
+    #[serde(default)]
+    pub crosspost_source: Option<CrosspostSource>,
+    /// The thread created from this message, which shares its id
+    #[serde(default)]
+    pub started_thread_id: Option<ChannelId>,
     pub webhook_id: Option<WebhookId>,
     pub application_id: Option<ApplicationId>,
     pub interaction: Option<MessageInteraction>,
@@ -56,33 +311,63 @@ pub struct ArchivedMessageFull {
     /// the full history
     pub iterations: Vec<ArchivedMessageIteration>,
     pub marked_as_edited: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    /// When the channel containing this message was deleted
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub channel_deleted_at: Option<Timestamp>,
+    /// When we stopped archiving the guild of this message, because the bot
+    /// was removed from it
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub archive_stopped_at: Option<Timestamp>,
+    /// Deletions that were undone by a later edit
+    #[serde(default)]
+    pub earlier_deletions: Vec<EarlierDeletion>,
 }
 
 impl ArchivedMessageFull {
-    pub fn from_gateway(message: Message, session_id: Uuid) -> Self {
+    pub fn from_gateway(message: Message, session: &Session) -> Self {
         Self {
             id: message.id,
             channel_id: message.channel_id,
+            channel_type: ArchivedChannelType::Unknown,
             guild_id: message.guild_id,
             author_id: message.author.id,
             timestamp: convert_ts(message.timestamp),
             kind: message.kind.into(),
+            raw_message_type: raw_message_type(message.kind),
+            crosspost_source: CrosspostSource::from_message(&message),
+            started_thread_id: message.thread.as_ref().map(|thread| thread.id),
             message_reference: message.message_reference,
+            referenced_message: None,
             webhook_id: message.webhook_id,
             application_id: message.application_id,
             interaction: message.interaction,
             iterations: vec![ArchivedMessageIteration {
                 timestamp: convert_ts(message.timestamp),
                 may_contain_gap: false,
-                session_id,
+                auto_embed: false,
+                clock_adjusted: false,
+                session_id: session.session_id,
+                session_label: session.label.clone(),
 
                 content: message.content,
+                normalized_content: None,
+                effective_content: None,
                 attachments: message.attachments,
                 embeds: message.embeds,
                 components: message.components,
                 sticker_items: message.sticker_items,
+                voice_attachments: vec![],
+                attachment_changes: AttachmentChanges::default(),
+                lost_attachments: vec![],
+                stored_attachments: vec![],
             }],
             marked_as_edited: message.edited_timestamp.is_some(), // kept because why not
+            pinned: message.pinned,
+            channel_deleted_at: None,
+            archive_stopped_at: None,
+            earlier_deletions: vec![],
         }
     }
 
@@ -90,17 +375,27 @@ impl ArchivedMessageFull {
         ArchivedMessageFullDeleted {
             id: self.id,
             channel_id: self.channel_id,
+            channel_type: self.channel_type,
             guild_id: self.guild_id,
             author_id: self.author_id,
             timestamp: self.timestamp,
             kind: self.kind.into(),
+            raw_message_type: self.raw_message_type,
             message_reference: self.message_reference,
+            referenced_message: self.referenced_message,
+            crosspost_source: self.crosspost_source,
+            started_thread_id: self.started_thread_id,
             webhook_id: self.webhook_id,
             application_id: self.application_id,
             interaction: self.interaction,
             iterations: self.iterations,
             marked_as_edited: self.marked_as_edited,
+            pinned: self.pinned,
             deleted_timestamp: timestamp,
+            deletion_inferred: false,
+            channel_deleted_at: self.channel_deleted_at,
+            archive_stopped_at: self.archive_stopped_at,
+            earlier_deletions: self.earlier_deletions,
         }
     }
 }
@@ -110,13 +405,30 @@ pub struct ArchivedMessageFullDeleted {
     // Assumed to be static
     pub id: MessageId,
     pub channel_id: ChannelId,
+    /// What kind of channel the message was sent in, as of archiving it
+    #[serde(default)]
+    pub channel_type: ArchivedChannelType,
     pub guild_id: Option<GuildId>,
     pub author_id: UserId,
-    #[serde(with = "ts_milliseconds")]
+    #[serde(with = "ts_bson_datetime")]
     pub timestamp: Timestamp,
     #[serde(rename = "type")]
     pub kind: ArchivedMessageType,
+    /// The type number Discord sent, kept for types we don't know yet
+    #[serde(default)]
+    pub raw_message_type: Option<i64>,
     pub message_reference: Option<MessageReference>,
+    /// The referenced message as it was when this one was archived, if
+    /// resolving references is enabled
+    #[serde(default)]
+    pub referenced_message: Option<ReferencedMessage>,
+    /// The original of a crossposted announcement, which lives in another
+    /// guild
+    #[serde(default)]
+    pub crosspost_source: Option<CrosspostSource>,
+    /// The thread created from this message, which shares its id
+    #[serde(default)]
+    pub started_thread_id: Option<ChannelId>,
     pub webhook_id: Option<WebhookId>,
     pub application_id: Option<ApplicationId>,
     pub interaction: Option<MessageInteraction>,
@@ -126,8 +438,24 @@ pub struct ArchivedMessageFullDeleted {
     /// the full history
     pub iterations: Vec<ArchivedMessageIteration>,
     pub marked_as_edited: bool,
-    #[serde(with = "ts_milliseconds_option")]
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(with = "ts_bson_datetime_option")]
     pub deleted_timestamp: Option<Timestamp>,
+    /// The deletion wasn't seen, the message was missing from its channel
+    /// when checked after a restart
+    #[serde(default)]
+    pub deletion_inferred: bool,
+    /// When the channel containing this message was deleted
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub channel_deleted_at: Option<Timestamp>,
+    /// When we stopped archiving the guild of this message, because the bot
+    /// was removed from it
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub archive_stopped_at: Option<Timestamp>,
+    /// Deletions that were undone by a later edit
+    #[serde(default)]
+    pub earlier_deletions: Vec<EarlierDeletion>,
 }
 
 impl ArchivedMessageFullDeleted {
@@ -135,6 +463,38 @@ impl ArchivedMessageFullDeleted {
     pub fn from_undeleted(message: ArchivedMessageFull, timestamp: Option<Timestamp>) -> Self {
         message.into_deleted(timestamp)
     }
+
+    /// Undo the deletion, keeping it in `earlier_deletions`
+    pub fn into_undeleted(self, resurrected_at: Timestamp) -> ArchivedMessageFull {
+        let mut earlier_deletions = self.earlier_deletions;
+        earlier_deletions.push(EarlierDeletion {
+            deleted_timestamp: self.deleted_timestamp,
+            resurrected_at,
+        });
+        ArchivedMessageFull {
+            id: self.id,
+            channel_id: self.channel_id,
+            channel_type: self.channel_type,
+            guild_id: self.guild_id,
+            author_id: self.author_id,
+            timestamp: self.timestamp,
+            kind: self.kind,
+            raw_message_type: self.raw_message_type,
+            message_reference: self.message_reference,
+            referenced_message: self.referenced_message,
+            crosspost_source: self.crosspost_source,
+            started_thread_id: self.started_thread_id,
+            webhook_id: self.webhook_id,
+            application_id: self.application_id,
+            interaction: self.interaction,
+            iterations: self.iterations,
+            marked_as_edited: self.marked_as_edited,
+            pinned: self.pinned,
+            channel_deleted_at: self.channel_deleted_at,
+            archive_stopped_at: self.archive_stopped_at,
+            earlier_deletions,
+        }
+    }
 }
 
 /// We have first heard of this message when it was updated
@@ -143,52 +503,61 @@ pub struct ArchivedMessageIncomplete {
     // Assumed to be static
     pub id: MessageId,
     pub channel_id: ChannelId,
+    /// What kind of channel the message was sent in, as of archiving it
+    #[serde(default)]
+    pub channel_type: ArchivedChannelType,
     pub guild_id: Option<GuildId>,
-    pub author_id: UserId,
-    #[serde(with = "ts_milliseconds")]
+    /// Unknown for some interaction responses
+    pub author_id: Option<UserId>,
+    #[serde(with = "ts_bson_datetime")]
     pub timestamp: Timestamp,
+    /// The type number Discord sent, if the update had one
+    #[serde(default)]
+    pub raw_message_type: Option<i64>,
 
     // Tracked when editing or deleting
     /// The original body and subsequent modifications, may or may not contain
     /// the full history
     pub iterations: Vec<ArchivedMessageIteration>,
     pub marked_as_edited: bool,
-}
-
-#[derive(Debug, Error)]
-pub enum ArchivedMessageIncompleteFromSerenityError {
-    #[error("no author in message update event")]
-    NoAuthor,
-
-    #[error("no timestamp in message update event")]
-    NoTimestamp,
+    /// When the channel containing this message was deleted
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub channel_deleted_at: Option<Timestamp>,
+    /// When we stopped archiving the guild of this message, because the bot
+    /// was removed from it
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub archive_stopped_at: Option<Timestamp>,
+    /// Deletions that were undone by a later edit
+    #[serde(default)]
+    pub earlier_deletions: Vec<EarlierDeletion>,
 }
 
 impl ArchivedMessageIncomplete {
+    /// Deferred interaction responses and followups can be updated without an
+    /// author or timestamp, so the author is left unknown and the timestamp is
+    /// taken from the message id
     pub fn from_gateway(
         update: MessageUpdateEvent,
         timestamp: Timestamp,
-        session_id: Uuid,
-    ) -> Result<Self, ArchivedMessageIncompleteFromSerenityError> {
+        session: &Session,
+    ) -> Self {
         let update2 = update.clone();
-        Ok(Self {
+        Self {
             id: update.id,
             channel_id: update.channel_id,
+            channel_type: ArchivedChannelType::Unknown,
             guild_id: update.guild_id,
-            author_id: update
-                .author
-                .ok_or(ArchivedMessageIncompleteFromSerenityError::NoAuthor)?
-                .id,
-            timestamp: convert_ts(
-                update
-                    .timestamp
-                    .ok_or(ArchivedMessageIncompleteFromSerenityError::NoTimestamp)?,
-            ),
+            author_id: update.author.map(|author| author.id),
+            timestamp: convert_ts(update.timestamp.unwrap_or_else(|| update.id.created_at())),
+            raw_message_type: update.kind.and_then(raw_message_type),
             iterations: vec![ArchivedMessageIteration::from_gateway(
-                update2, timestamp, session_id,
+                update2, None, timestamp, session,
             )],
             marked_as_edited: update.edited_timestamp.is_some(),
-        })
+            channel_deleted_at: None,
+            archive_stopped_at: None,
+            earlier_deletions: vec![],
+        }
     }
 }
 
@@ -197,12 +566,18 @@ impl ArchivedMessageIncomplete {
         ArchivedMessageIncompleteDeleted {
             id: self.id,
             channel_id: self.channel_id,
+            channel_type: self.channel_type,
             guild_id: self.guild_id,
             author_id: self.author_id,
             timestamp: self.timestamp,
+            raw_message_type: self.raw_message_type,
             iterations: self.iterations,
             marked_as_edited: self.marked_as_edited,
             deleted_timestamp: timestamp,
+            deletion_inferred: false,
+            channel_deleted_at: self.channel_deleted_at,
+            archive_stopped_at: self.archive_stopped_at,
+            earlier_deletions: self.earlier_deletions,
         }
     }
 }
@@ -213,18 +588,39 @@ pub struct ArchivedMessageIncompleteDeleted {
     // Assumed to be static
     pub id: MessageId,
     pub channel_id: ChannelId,
+    /// What kind of channel the message was sent in, as of archiving it
+    #[serde(default)]
+    pub channel_type: ArchivedChannelType,
     pub guild_id: Option<GuildId>,
-    pub author_id: UserId,
-    #[serde(with = "ts_milliseconds")]
+    /// Unknown for some interaction responses
+    pub author_id: Option<UserId>,
+    #[serde(with = "ts_bson_datetime")]
     pub timestamp: Timestamp,
+    /// The type number Discord sent, if the update had one
+    #[serde(default)]
+    pub raw_message_type: Option<i64>,
 
     // Tracked when editing or deleting
     /// The original body and subsequent modifications, may or may not contain
     /// the full history
     pub iterations: Vec<ArchivedMessageIteration>,
     pub marked_as_edited: bool,
-    #[serde(with = "ts_milliseconds_option")]
+    #[serde(with = "ts_bson_datetime_option")]
     pub deleted_timestamp: Option<Timestamp>,
+    /// The deletion wasn't seen, the message was missing from its channel
+    /// when checked after a restart
+    #[serde(default)]
+    pub deletion_inferred: bool,
+    /// When the channel containing this message was deleted
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub channel_deleted_at: Option<Timestamp>,
+    /// When we stopped archiving the guild of this message, because the bot
+    /// was removed from it
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub archive_stopped_at: Option<Timestamp>,
+    /// Deletions that were undone by a later edit
+    #[serde(default)]
+    pub earlier_deletions: Vec<EarlierDeletion>,
 }
 
 impl ArchivedMessageIncompleteDeleted {
@@ -235,6 +631,39 @@ impl ArchivedMessageIncompleteDeleted {
     ) -> Self {
         undeleted.into_deleted(timestamp)
     }
+
+    /// Undo the deletion, keeping it in `earlier_deletions`
+    pub fn into_undeleted(self, resurrected_at: Timestamp) -> ArchivedMessageIncomplete {
+        let mut earlier_deletions = self.earlier_deletions;
+        earlier_deletions.push(EarlierDeletion {
+            deleted_timestamp: self.deleted_timestamp,
+            resurrected_at,
+        });
+        ArchivedMessageIncomplete {
+            id: self.id,
+            channel_id: self.channel_id,
+            channel_type: self.channel_type,
+            guild_id: self.guild_id,
+            author_id: self.author_id,
+            timestamp: self.timestamp,
+            raw_message_type: self.raw_message_type,
+            iterations: self.iterations,
+            marked_as_edited: self.marked_as_edited,
+            channel_deleted_at: self.channel_deleted_at,
+            archive_stopped_at: self.archive_stopped_at,
+            earlier_deletions,
+        }
+    }
+}
+
+/// A deletion that turned out not to be final, because the message was
+/// edited after it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EarlierDeletion {
+    #[serde(with = "ts_milliseconds_option")]
+    pub deleted_timestamp: Option<Timestamp>,
+    #[serde(with = "ts_milliseconds")]
+    pub resurrected_at: Timestamp,
 }
 
 /// We have very little data on this message and it has been deleted
@@ -243,7 +672,15 @@ pub struct ArchivedMessageUnknownDeleted {
     pub id: MessageId,
     pub channel_id: ChannelId,
     pub guild_id: Option<GuildId>,
+    #[serde(default, with = "ts_bson_datetime_option")]
     pub deleted_timestamp: Option<Timestamp>,
+    /// When the channel containing this message was deleted
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub channel_deleted_at: Option<Timestamp>,
+    /// When we stopped archiving the guild of this message, because the bot
+    /// was removed from it
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub archive_stopped_at: Option<Timestamp>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -254,34 +691,281 @@ pub struct ArchivedMessageIteration {
     /// Did we listen for this event ourselves or did we backfill it using the
     /// latest available version
     pub may_contain_gap: bool,
+    /// Discord added embeds for links in the message, this wasn't an edit by
+    /// the author
+    #[serde(default)]
+    pub auto_embed: bool,
+    /// The timestamp was moved forward to keep the iterations in order,
+    /// because our clock was behind the one that timed the previous iteration
+    #[serde(default)]
+    pub clock_adjusted: bool,
     /// Which session originally saved this iteration, used for determining if
     /// we *might* be missing some history
     pub session_id: Uuid,
+    /// The label of that session, if the operator gave it one
+    #[serde(default)]
+    pub session_label: Option<String>,
 
     // The things that changed
     pub content: String,
+    /// The content trimmed and with repeated newlines collapsed, if
+    /// configured, `content` itself stays as sent
+    #[serde(default)]
+    pub normalized_content: Option<String>,
+    /// The content followed by the text of the embeds, if configured, for
+    /// searching link-only messages
+    #[serde(default)]
+    pub effective_content: Option<String>,
     pub attachments: Vec<Attachment>,
     pub embeds: Vec<Embed>,
     pub components: Vec<ActionRow>,
     pub sticker_items: Vec<StickerItem>,
+    /// Duration and waveform of the attachments that are voice recordings
+    #[serde(default)]
+    pub voice_attachments: Vec<VoiceAttachment>,
+    /// Which attachments were added or removed since the previous iteration
+    #[serde(default)]
+    pub attachment_changes: AttachmentChanges,
+    /// Attachments that can't be fetched anymore because the message was
+    /// deleted, set by recover-attachments mode
+    #[serde(default)]
+    pub lost_attachments: Vec<AttachmentId>,
+    /// Attachments whose content was downloaded into GridFS
+    #[serde(default)]
+    pub stored_attachments: Vec<StoredAttachment>,
+}
+
+/// Attachments that appeared or disappeared between two iterations, by id
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AttachmentChanges {
+    pub added: Vec<AttachmentId>,
+    pub removed: Vec<AttachmentId>,
+}
+
+impl AttachmentChanges {
+    pub fn between(previous: &[Attachment], current: &[Attachment]) -> Self {
+        let previous: Vec<AttachmentId> = previous.iter().map(|a| a.id).collect();
+        let current: Vec<AttachmentId> = current.iter().map(|a| a.id).collect();
+        Self::between_ids(&previous, &current)
+    }
+
+    fn between_ids(previous: &[AttachmentId], current: &[AttachmentId]) -> Self {
+        Self {
+            added: current
+                .iter()
+                .filter(|id| !previous.contains(id))
+                .copied()
+                .collect(),
+            removed: previous
+                .iter()
+                .filter(|id| !current.contains(id))
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
 }
 
+/// Nitro raised the limit from 2000 to 4000 characters, anything longer is
+/// unexpected and worth a look, but still stored in full
+pub const MAX_CONTENT_CHARS: usize = 4000;
+
 impl ArchivedMessageIteration {
     pub fn from_gateway(
         update: MessageUpdateEvent,
+        previous: Option<&ArchivedMessageIteration>,
         timestamp: Timestamp,
-        session_id: Uuid,
+        session: &Session,
     ) -> Self {
+        // Edits don't always resend the attachments, which doesn't mean they
+        // were removed
+        let attachments = match (&update.attachments, previous) {
+            (Some(attachments), _) => attachments.clone(),
+            (None, Some(previous)) => previous.attachments.clone(),
+            (None, None) => vec![],
+        };
+        let attachment_changes = previous
+            .map(|previous| AttachmentChanges::between(&previous.attachments, &attachments))
+            .unwrap_or_default();
+        // Voice messages can't be edited, but their attachment stays around
+        let voice_attachments = previous
+            .map(|previous| {
+                previous
+                    .voice_attachments
+                    .iter()
+                    .filter(|voice| attachments.iter().any(|a| a.id == voice.attachment_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let stored_attachments = previous
+            .map(|previous| {
+                previous
+                    .stored_attachments
+                    .iter()
+                    .filter(|stored| attachments.iter().any(|a| a.id == stored.attachment_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
         Self {
             timestamp,
             may_contain_gap: false,
-            session_id,
+            auto_embed: Self::is_auto_embed(&update, previous),
+            clock_adjusted: false,
+            session_id: session.session_id,
+            session_label: session.label.clone(),
 
             content: update.content.unwrap_or_default(),
-            attachments: update.attachments.unwrap_or_default(),
+            normalized_content: None,
+            effective_content: None,
+            attachments,
             embeds: update.embeds.unwrap_or_default(),
             components: update.components.unwrap_or_default(),
             sticker_items: update.sticker_items.unwrap_or_default(),
+            voice_attachments,
+            attachment_changes,
+            lost_attachments: vec![],
+            stored_attachments,
+        }
+    }
+
+    pub fn is_content_oversized(&self) -> bool {
+        self.content.chars().count() > MAX_CONTENT_CHARS
+    }
+
+    /// Whether the update carries content different from this iteration's
+    pub fn is_content_changed_by(&self, update: &MessageUpdateEvent) -> bool {
+        update
+            .content
+            .as_ref()
+            .is_some_and(|content| *content != self.content)
+    }
+
+    /// Take over the fields an update sent besides the content
+    pub fn apply_noncontent_update(&mut self, update: MessageUpdateEvent) {
+        if let Some(attachments) = update.attachments {
+            self.attachments = attachments;
+        }
+        if let Some(embeds) = update.embeds {
+            self.embeds = embeds;
+        }
+        if let Some(components) = update.components {
+            self.components = components;
+        }
+        if let Some(sticker_items) = update.sticker_items {
+            self.sticker_items = sticker_items;
+        }
+    }
+
+    /// Link unfurling sends an update with new embeds, but without an edit
+    /// timestamp or a change in content
+    fn is_auto_embed(
+        update: &MessageUpdateEvent,
+        previous: Option<&ArchivedMessageIteration>,
+    ) -> bool {
+        if update.edited_timestamp.is_some() || update.embeds.is_none() {
+            return false;
+        }
+        match (&update.content, previous) {
+            (None, _) => true,
+            (Some(content), Some(previous)) => *content == previous.content,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Trim the content and collapse runs of newlines, along with the
+/// whitespace at the ends of lines, into a single newline
+pub fn normalize_content(content: &str) -> String {
+    content
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// The content and the title and description of every embed, one per line,
+/// leaving out the parts that are missing or empty
+pub fn effective_content(iteration: &ArchivedMessageIteration) -> String {
+    std::iter::once(iteration.content.as_str())
+        .chain(iteration.embeds.iter().flat_map(|embed| {
+            [embed.title.as_deref(), embed.description.as_deref()]
+                .into_iter()
+                .flatten()
+        }))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Append an iteration, never letting its timestamp go before the previous one
+pub fn push_iteration(
+    iterations: &mut Vec<ArchivedMessageIteration>,
+    mut iteration: ArchivedMessageIteration,
+) {
+    if let Some(previous) = iterations.last() {
+        if iteration.timestamp < previous.timestamp {
+            iteration.timestamp = previous.timestamp;
+            iteration.clock_adjusted = true;
+        }
+    }
+    iterations.push(iteration);
+}
+
+/// The kind of channel a message was sent in, stored by name so it can be
+/// filtered on directly
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchivedChannelType {
+    Text,
+    Private,
+    Voice,
+    Category,
+    News,
+    NewsThread,
+    /// Forum posts are public threads too
+    PublicThread,
+    PrivateThread,
+    Stage,
+    Directory,
+    /// Archived before channel types were stored, or the channel couldn't be
+    /// fetched
+    #[default]
+    Unknown,
+}
+
+impl From<ChannelType> for ArchivedChannelType {
+    fn from(value: ChannelType) -> Self {
+        match value {
+            ChannelType::Text => Self::Text,
+            ChannelType::Private => Self::Private,
+            ChannelType::Voice => Self::Voice,
+            ChannelType::Category => Self::Category,
+            ChannelType::News => Self::News,
+            ChannelType::NewsThread => Self::NewsThread,
+            ChannelType::PublicThread => Self::PublicThread,
+            ChannelType::PrivateThread => Self::PrivateThread,
+            ChannelType::Stage => Self::Stage,
+            ChannelType::Directory => Self::Directory,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl From<&Channel> for ArchivedChannelType {
+    fn from(value: &Channel) -> Self {
+        match value {
+            Channel::Guild(channel) => channel.kind.into(),
+            Channel::Private(channel) => channel.kind.into(),
+            Channel::Category(channel) => channel.kind.into(),
+            _ => Self::Unknown,
         }
     }
 }
@@ -350,6 +1034,15 @@ impl From<MessageType> for ArchivedMessageType {
     }
 }
 
+/// The number of a message type, `None` for types serenity doesn't know,
+/// whose number it doesn't keep
+pub fn raw_message_type(kind: MessageType) -> Option<i64> {
+    match kind {
+        MessageType::Unknown => None,
+        kind => Some(kind.num() as i64),
+    }
+}
+
 /* #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CachedUser {
     pub id: UserId,
@@ -373,3 +1066,478 @@ impl From<User> for CachedUser {
         }
     }
 } */
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{mong::to_stored_document, test_util};
+
+    fn sent_at() -> Timestamp {
+        Utc.timestamp_millis_opt(1_700_000_000_000).unwrap()
+    }
+
+    fn unknown_deleted(deleted_timestamp: Option<Timestamp>) -> ArchivedMessage {
+        ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
+            id: MessageId(1),
+            channel_id: ChannelId(20),
+            guild_id: None,
+            deleted_timestamp,
+            channel_deleted_at: None,
+            archive_stopped_at: None,
+        })
+    }
+
+    #[test]
+    fn stores_timestamps_as_dates() {
+        let session = Session::new(None);
+        let message = test_util::message(1, json!({}));
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        let stored = to_stored_document(&message).unwrap();
+        assert_eq!(
+            stored.get("timestamp"),
+            Some(&Bson::DateTime(bson::DateTime::from_chrono(sent_at())))
+        );
+
+        let stored = to_stored_document(&unknown_deleted(Some(sent_at()))).unwrap();
+        assert!(matches!(
+            stored.get("deleted_timestamp"),
+            Some(Bson::DateTime(_))
+        ));
+        let stored = to_stored_document(&unknown_deleted(None)).unwrap();
+        assert_eq!(stored.get("deleted_timestamp"), Some(&Bson::Null));
+    }
+
+    #[test]
+    fn writes_milliseconds_to_json() {
+        let json = serde_json::to_value(unknown_deleted(Some(sent_at()))).unwrap();
+        assert_eq!(json["deleted_timestamp"], 1_700_000_000_000_i64);
+    }
+
+    #[test]
+    fn reads_every_stored_timestamp_format() {
+        let stored = [
+            Bson::DateTime(bson::DateTime::from_chrono(sent_at())),
+            Bson::Int64(1_700_000_000_000),
+            Bson::String("2023-11-14T22:13:20Z".to_string()),
+        ];
+        for deleted_timestamp in stored {
+            let document = bson::doc! {
+                "archive_type": "UnknownDeleted",
+                "id": "1",
+                "channel_id": "20",
+                "deleted_timestamp": deleted_timestamp.clone(),
+            };
+            let message: ArchivedMessage = bson::from_document(document).unwrap();
+            let ArchivedMessage::UnknownDeleted(message) = message else {
+                unreachable!()
+            };
+            assert_eq!(
+                message.deleted_timestamp,
+                Some(sent_at()),
+                "reading {deleted_timestamp:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn stored_dates_fall_in_date_ranges() {
+        // What a `{ "$gte": from, "$lt": to }` filter compares against
+        let stored = to_stored_document(&unknown_deleted(Some(sent_at()))).unwrap();
+        let stored = stored.get_datetime("deleted_timestamp").unwrap();
+        let from = bson::DateTime::from_chrono(sent_at() - chrono::Duration::minutes(1));
+        let to = bson::DateTime::from_chrono(sent_at() + chrono::Duration::minutes(1));
+        assert!(from <= *stored && *stored < to);
+    }
+
+    #[test]
+    fn stores_the_thread_a_message_started() {
+        let session = Session::new(None);
+        let message = test_util::message(
+            1,
+            json!({
+                "thread": {
+                    "id": "1",
+                    "type": 11,
+                    "guild_id": "30",
+                    "parent_id": "20",
+                    "name": "a thread",
+                },
+            }),
+        );
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        assert_eq!(message.started_thread_id(), Some(ChannelId(1)));
+        let stored = to_stored_document(&message).unwrap();
+        assert_eq!(stored.get_str("started_thread_id"), Ok("1"));
+
+        let message = test_util::message(2, json!({}));
+        let message = ArchivedMessageFull::from_gateway(message, &session);
+        assert_eq!(message.started_thread_id, None);
+    }
+
+    fn first_iteration(content: &str) -> ArchivedMessageIteration {
+        let message = test_util::message(1, json!({ "content": content }));
+        let message = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        message.iterations.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn unfurled_links_are_not_edits() {
+        let previous = first_iteration("https://example.com");
+        let embeds = json!([{ "type": "link", "title": "Example" }]);
+        let unfurled = [
+            json!({ "embeds": embeds }),
+            json!({ "content": "https://example.com", "embeds": embeds }),
+        ];
+        for fields in unfurled {
+            let update = test_util::update(1, fields.clone());
+            let iteration = ArchivedMessageIteration::from_gateway(
+                update,
+                Some(&previous),
+                Utc::now(),
+                &Session::new(None),
+            );
+            assert!(iteration.auto_embed, "update with {fields}");
+        }
+    }
+
+    #[test]
+    fn content_edits_are_not_unfurled_links() {
+        let previous = first_iteration("https://example.com");
+        let embeds = json!([{ "type": "link", "title": "Example" }]);
+        let edits = [
+            json!({ "content": "see https://example.com", "embeds": embeds }),
+            json!({
+                "content": "https://example.com",
+                "embeds": embeds,
+                "edited_timestamp": "2023-11-14T22:20:00.000000+00:00",
+            }),
+            json!({ "content": "https://example.com" }),
+        ];
+        for fields in edits {
+            let update = test_util::update(1, fields.clone());
+            let iteration = ArchivedMessageIteration::from_gateway(
+                update,
+                Some(&previous),
+                Utc::now(),
+                &Session::new(None),
+            );
+            assert!(!iteration.auto_embed, "update with {fields}");
+        }
+    }
+
+    #[test]
+    fn skewed_fallback_timestamps_are_clamped() {
+        let mut iterations = vec![first_iteration("hello")];
+        let edit = |timestamp| {
+            ArchivedMessageIteration::from_gateway(
+                test_util::update(1, json!({ "content": "edited" })),
+                None,
+                timestamp,
+                &Session::new(None),
+            )
+        };
+
+        // Our clock is a minute behind the one that stamped the message
+        push_iteration(
+            &mut iterations,
+            edit(sent_at() - chrono::Duration::minutes(1)),
+        );
+        assert_eq!(iterations[1].timestamp, sent_at());
+        assert!(iterations[1].clock_adjusted);
+
+        let later = sent_at() + chrono::Duration::minutes(1);
+        push_iteration(&mut iterations, edit(later));
+        assert_eq!(iterations[2].timestamp, later);
+        assert!(!iterations[2].clock_adjusted);
+    }
+
+    #[test]
+    fn links_to_guild_and_direct_messages() {
+        let session = Session::new(None);
+        let in_guild = test_util::message(1, json!({}));
+        let in_guild = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(in_guild, &session));
+        assert_eq!(
+            message_link(&in_guild),
+            "https://discord.com/channels/30/20/1"
+        );
+        assert_eq!(
+            message_link(&unknown_deleted(None)),
+            "https://discord.com/channels/@me/20/1"
+        );
+    }
+
+    #[test]
+    fn deferred_followups_without_author_or_timestamp_are_kept() {
+        // A message sent at `sent_at`, as its snowflake encodes it
+        let id = (1_700_000_000_000 - 1_420_070_400_000) << 22;
+        let update = test_util::update(
+            id,
+            json!({
+                "content": "Here are your results",
+                "flags": 64,
+                "edited_timestamp": "2023-11-14T22:20:00.000000+00:00",
+            }),
+        );
+        let message =
+            ArchivedMessageIncomplete::from_gateway(update, Utc::now(), &Session::new(None));
+        assert_eq!(message.author_id, None);
+        assert_eq!(message.timestamp, sent_at());
+        assert_eq!(message.iterations[0].content, "Here are your results");
+        assert!(message.marked_as_edited);
+    }
+
+    #[test]
+    fn updates_with_author_and_timestamp_use_them() {
+        let update = test_util::update(
+            1,
+            json!({
+                "author": { "id": "100", "username": "someone", "discriminator": "0", "avatar": null },
+                "timestamp": "2023-11-14T22:13:20.000000+00:00",
+            }),
+        );
+        let message =
+            ArchivedMessageIncomplete::from_gateway(update, Utc::now(), &Session::new(None));
+        assert_eq!(message.author_id, Some(UserId(100)));
+        assert_eq!(message.timestamp, sent_at());
+    }
+
+    #[test]
+    fn unknown_mapped_messages_keep_their_type_number() {
+        let session = Session::new(None);
+        // Serenity knows group icon updates, we don't
+        let message = test_util::message(1, json!({ "type": 5 }));
+        let message = ArchivedMessageFull::from_gateway(message, &session);
+        assert_eq!(message.kind, ArchivedMessageType::Unknown);
+        assert_eq!(message.raw_message_type, Some(5));
+
+        let mut update = test_util::update(2, json!({}));
+        update.kind = Some(MessageType::GroupIconUpdate);
+        let message = ArchivedMessageIncomplete::from_gateway(update, Utc::now(), &session);
+        assert_eq!(message.raw_message_type, Some(5));
+    }
+
+    #[test]
+    fn known_messages_keep_their_type_number_too() {
+        let message = test_util::message(1, json!({ "type": 19 }));
+        let message = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        assert_eq!(message.kind, ArchivedMessageType::InlineReply);
+        assert_eq!(message.raw_message_type, Some(19));
+    }
+
+    #[test]
+    fn session_label_is_stamped_on_iterations() {
+        let session = Session::new(Some("post-migration".to_string()));
+        let message = test_util::message(1, json!({ "content": "hello" }));
+        let mut message = ArchivedMessageFull::from_gateway(message, &session);
+        let update = test_util::update(1, json!({ "content": "hello!" }));
+        let iteration = ArchivedMessageIteration::from_gateway(
+            update,
+            message.iterations.last(),
+            Utc::now(),
+            &session,
+        );
+        push_iteration(&mut message.iterations, iteration);
+
+        assert_eq!(message.iterations.len(), 2);
+        for iteration in &message.iterations {
+            assert_eq!(iteration.session_id, session.session_id);
+            assert_eq!(iteration.session_label.as_deref(), Some("post-migration"));
+        }
+        let stored = to_stored_document(&session).unwrap();
+        assert_eq!(stored.get_str("label"), Ok("post-migration"));
+    }
+
+    #[test]
+    fn oversized_content_is_flagged_but_kept_whole() {
+        let session = Session::new(None);
+        // Multi-byte characters, the limit is in characters
+        let content = "é".repeat(MAX_CONTENT_CHARS + 1);
+        let message = test_util::message(1, json!({ "content": content }));
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        let iteration = message.latest_iteration().unwrap();
+        assert!(iteration.is_content_oversized());
+        assert_eq!(iteration.content, content);
+
+        let at_limit = "é".repeat(MAX_CONTENT_CHARS);
+        let message = test_util::message(2, json!({ "content": at_limit }));
+        let message = ArchivedMessageFull::from_gateway(message, &session);
+        assert!(!message.iterations[0].is_content_oversized());
+    }
+
+    /// An iteration `minutes` after the message was sent
+    fn iteration_after(minutes: i64, content: &str) -> ArchivedMessageIteration {
+        let mut iteration = first_iteration(content);
+        iteration.timestamp = sent_at() + chrono::Duration::minutes(minutes);
+        iteration
+    }
+
+    #[test]
+    fn single_iteration_messages_have_no_edits() {
+        let message = test_util::message(1, json!({ "content": "hello" }));
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            message,
+            &Session::new(None),
+        ));
+        assert_eq!(
+            message.edit_stats(),
+            EditStats {
+                edit_count: 0,
+                first_edit_at: None,
+                last_edit_at: None,
+                deleted: false,
+            }
+        );
+    }
+
+    #[test]
+    fn edit_stats_skip_unfurls_and_gaps() {
+        let message = test_util::message(1, json!({ "content": "hello" }));
+        let mut full = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        let mut unfurled = iteration_after(1, "hello");
+        unfurled.auto_embed = true;
+        let mut after_gap = iteration_after(5, "hello?");
+        after_gap.may_contain_gap = true;
+        full.iterations.extend([
+            iteration_after(2, "hello!"),
+            unfurled,
+            iteration_after(3, "hello!!"),
+            after_gap,
+        ]);
+
+        let stats = ArchivedMessage::Full(full.clone()).edit_stats();
+        assert_eq!(stats.edit_count, 2);
+        assert_eq!(
+            stats.first_edit_at,
+            Some(sent_at() + chrono::Duration::minutes(2))
+        );
+        assert_eq!(
+            stats.last_edit_at,
+            Some(sent_at() + chrono::Duration::minutes(3))
+        );
+        assert!(!stats.deleted);
+
+        let deleted = ArchivedMessage::FullDeleted(full.into_deleted(None)).edit_stats();
+        assert_eq!(deleted.edit_count, 2);
+        assert!(deleted.deleted);
+    }
+
+    #[test]
+    fn first_iteration_of_incomplete_messages_is_an_edit() {
+        let update = test_util::update(1, json!({ "content": "edited" }));
+        let mut incomplete =
+            ArchivedMessageIncomplete::from_gateway(update, Utc::now(), &Session::new(None));
+        incomplete.iterations[0].timestamp = sent_at() + chrono::Duration::minutes(1);
+        incomplete
+            .iterations
+            .push(iteration_after(4, "edited again"));
+
+        let stats = ArchivedMessage::Incomplete(incomplete.clone()).edit_stats();
+        assert_eq!(stats.edit_count, 2);
+        assert_eq!(
+            stats.first_edit_at,
+            Some(sent_at() + chrono::Duration::minutes(1))
+        );
+        assert_eq!(
+            stats.last_edit_at,
+            Some(sent_at() + chrono::Duration::minutes(4))
+        );
+        assert!(!stats.deleted);
+
+        let deleted = ArchivedMessage::IncompleteDeleted(incomplete.into_deleted(None));
+        assert!(deleted.edit_stats().deleted);
+        assert_eq!(deleted.edit_stats().edit_count, 2);
+    }
+
+    #[test]
+    fn unknown_deletions_have_no_edits() {
+        let stats = unknown_deleted(None).edit_stats();
+        assert_eq!(stats.edit_count, 0);
+        assert_eq!(stats.first_edit_at, None);
+        assert!(stats.deleted);
+    }
+
+    #[test]
+    fn edits_record_removed_and_added_attachments() {
+        let session = Session::new(None);
+        let message = test_util::message(
+            1,
+            json!({ "attachments": [test_util::attachment(5), test_util::attachment(6)] }),
+        );
+        let message = ArchivedMessageFull::from_gateway(message, &session);
+        assert!(message.iterations[0].attachment_changes.is_empty());
+
+        let update = test_util::update(
+            1,
+            json!({ "attachments": [test_util::attachment(6), test_util::attachment(7)] }),
+        );
+        let edited = ArchivedMessageIteration::from_gateway(
+            update,
+            message.iterations.last(),
+            Utc::now(),
+            &session,
+        );
+        assert_eq!(edited.attachment_changes.added, [AttachmentId(7)]);
+        assert_eq!(edited.attachment_changes.removed, [AttachmentId(5)]);
+
+        // Not resending the attachments doesn't remove them
+        let update = test_util::update(1, json!({ "content": "edited" }));
+        let carried =
+            ArchivedMessageIteration::from_gateway(update, Some(&edited), Utc::now(), &session);
+        assert!(carried.attachment_changes.is_empty());
+        assert_eq!(carried.attachments.len(), 2);
+    }
+
+    #[test]
+    fn normalized_content_sits_next_to_the_raw_content() {
+        let messy = "  hello  \n\n\n  world\t\n   \n";
+        let message = test_util::message(1, json!({ "content": messy }));
+        let mut message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            message,
+            &Session::new(None),
+        ));
+        message.normalize_content();
+        let iteration = message.latest_iteration().unwrap();
+        assert_eq!(iteration.content, messy);
+        assert_eq!(
+            iteration.normalized_content.as_deref(),
+            Some("hello\n  world")
+        );
+    }
+
+    #[test]
+    fn whitespace_changes_are_still_edits() {
+        let previous = first_iteration("hello\nworld");
+        let update = test_util::update(1, json!({ "content": "hello\n\nworld " }));
+        assert!(previous.is_content_changed_by(&update));
+    }
+
+    #[test]
+    fn indexes_the_embeds_of_link_only_messages() {
+        let link = "https://example.com/article";
+        let message = test_util::message(
+            1,
+            json!({
+                "content": link,
+                "embeds": [
+                    { "type": "link", "title": "An article", "description": "What it's about" },
+                    { "type": "link", "title": "", "description": "Another site" },
+                    { "type": "image" },
+                ],
+            }),
+        );
+        let session = Session::new(None);
+        let mut message =
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        message.index_embed_text();
+        let iteration = message.latest_iteration().unwrap();
+        assert_eq!(
+            iteration.effective_content.as_deref(),
+            Some("https://example.com/article\nAn article\nWhat it's about\nAnother site")
+        );
+        assert_eq!(iteration.content, link);
+    }
+}
@@ -0,0 +1,105 @@
+use regex::RegexSet;
+use serenity::{
+    http::Http,
+    model::{channel::Message, webhook::Webhook},
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Raises an alert for new messages whose content matches one of the
+/// configured patterns, archiving goes on as usual either way
+pub struct Alerts {
+    patterns: RegexSet,
+    webhook: Option<Webhook>,
+    raised: AtomicU64,
+}
+
+impl Alerts {
+    pub fn new(patterns: RegexSet, webhook: Option<Webhook>) -> Self {
+        Self {
+            patterns,
+            webhook,
+            raised: AtomicU64::new(0),
+        }
+    }
+
+    /// Log an alert if the message matches, posting it to the webhook in the
+    /// background so archiving isn't held up by it
+    pub fn check(&self, http: &Arc<Http>, message: &Message) {
+        let matched = self.matched(&message.content);
+        if matched.is_empty() {
+            return;
+        }
+        let raised = self.raised.fetch_add(1, Ordering::Relaxed) + 1;
+        let alert = format!(
+            "Message {} by {} matched {}: {}",
+            message.id,
+            message.author.id,
+            matched.join(", "),
+            message.link()
+        );
+        println!("Alert ({raised} so far): {alert}");
+        if let Some(webhook) = self.webhook.clone() {
+            let http = http.clone();
+            tokio::spawn(async move {
+                if let Err(err) = webhook
+                    .execute(&http, false, |w| {
+                        w.content(&alert).allowed_mentions(|m| m.empty_parse())
+                    })
+                    .await
+                {
+                    println!("Failed to post alert to the webhook: {err}");
+                }
+            });
+        }
+    }
+
+    /// The patterns `content` matches
+    fn matched(&self, content: &str) -> Vec<&str> {
+        self.patterns
+            .matches(content)
+            .into_iter()
+            .map(|index| self.patterns.patterns()[index].as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util;
+
+    fn alerts() -> Alerts {
+        let patterns = RegexSet::new([r"(?i)\bfree nitro\b", r"discord\.gift/\w+"]).unwrap();
+        Alerts::new(patterns, None)
+    }
+
+    #[test]
+    fn lists_the_patterns_content_matches() {
+        let alerts = alerts();
+        assert_eq!(
+            alerts.matched("FREE NITRO at discord.gift/abc"),
+            [r"(?i)\bfree nitro\b", r"discord\.gift/\w+"]
+        );
+        assert_eq!(alerts.matched("free nitro"), [r"(?i)\bfree nitro\b"]);
+        assert!(alerts.matched("nitro isn't free").is_empty());
+        assert!(alerts.matched("").is_empty());
+    }
+
+    #[test]
+    fn counts_only_matching_messages() {
+        let alerts = alerts();
+        let http = Arc::new(Http::new(""));
+        alerts.check(&http, &test_util::message(1, json!({ "content": "hello" })));
+        assert_eq!(alerts.raised.load(Ordering::Relaxed), 0);
+        alerts.check(
+            &http,
+            &test_util::message(2, json!({ "content": "get free nitro" })),
+        );
+        assert_eq!(alerts.raised.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,227 +1,2498 @@
 use async_trait::async_trait;
-use bson::doc;
+use bson::{doc, Document};
 use chrono::Utc;
-use mongodb::options::{InsertOneOptions, UpdateOptions};
+use futures::FutureExt;
+use mongodb::{
+    error::ErrorKind,
+    gridfs::GridFsBucket,
+    options::{InsertManyOptions, InsertOneOptions, UpdateOptions},
+};
 use serenity::{
     client::{Context, EventHandler},
+    http::Http,
     model::{
-        channel::Message,
-        event::MessageUpdateEvent,
-        id::{ChannelId, GuildId, MessageId},
+        channel::{Attachment, ChannelCategory, GuildChannel, Message, MessageType},
+        event::{MessageUpdateEvent, TypingStartEvent},
+        guild::{Guild, Role, UnavailableGuild},
+        id::{ChannelId, GuildId, MessageId, RoleId},
+    },
+};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
+    time::Duration,
 };
-use uuid::Uuid;
 
-use crate::archived_message::{
-    convert_ts, ArchivedMessage, ArchivedMessageFull, ArchivedMessageIncomplete,
-    ArchivedMessageIteration, ArchivedMessageUnknownDeleted,
+use crate::{
+    archived_message::{
+        convert_ts, push_iteration, ArchivedChannelType, ArchivedMessage, ArchivedMessageFull,
+        ArchivedMessageIncomplete, ArchivedMessageIteration, ArchivedMessageUnknownDeleted,
+        AttachmentChanges, Timestamp, MAX_CONTENT_CHARS,
+    },
+    archiver::{
+        alert::Alerts, channel_types::ChannelTypes, counters::EventCounters,
+        pending_deletions::PendingDeletions,
+    },
+    attachment_download::store_attachment,
+    config::{BotConfig, Config, EditAfterDeletePolicy, WriteStrategy},
+    hook::{run_hooks, ArchiveHook},
+    mong::{
+        self, attachments_bucket, messages_collection, permission_snapshots_collection,
+        roles_collection, system_events_collection, to_stored_bson, typing_events_collection,
+        upsert_cached, CollectionLocation, ATTACHMENTS, PERMISSION_SNAPSHOTS, ROLES, SYSTEM_EVENTS,
+        TYPING_EVENTS,
+    },
+    permission_snapshot::PermissionSnapshot,
+    publisher::{ArchiveNotice, ArchiveNoticeKind, EventPublisher},
+    reference::resolve_reference,
+    role::CachedRole,
+    session::Session,
+    system_event::{GuildLeft, SystemEvent, ThreadStarterDeleted},
+    typing_event::TypingEvent,
+    voice_message::{fetch_voice_attachments, looks_like_voice_message},
 };
 
+/// An event to archive, kept around while archiving is paused
+pub enum ArchiveEvent {
+    Message(Box<Message>),
+    Update(Box<MessageUpdateEvent>),
+    Delete {
+        channel_id: ChannelId,
+        id: MessageId,
+        guild_id: Option<GuildId>,
+        timestamp: Timestamp,
+    },
+    BulkDelete {
+        channel_id: ChannelId,
+        ids: Vec<MessageId>,
+        guild_id: Option<GuildId>,
+        timestamp: Timestamp,
+    },
+    ChannelDelete {
+        channel_id: ChannelId,
+        timestamp: Timestamp,
+    },
+    GuildLeave {
+        guild_id: GuildId,
+        timestamp: Timestamp,
+    },
+    /// Fetched when the message was received, so it's as of then even when
+    /// stored later
+    PermissionSnapshot(Box<PermissionSnapshot>),
+}
+
+impl ArchiveEvent {
+    /// What the event is about, for logs
+    fn describe(&self) -> String {
+        match self {
+            Self::Message(msg) => format!("message {}", msg.id),
+            Self::Update(update) => format!("update of message {}", update.id),
+            Self::Delete { id, .. } => format!("deletion of message {id}"),
+            Self::BulkDelete {
+                ids, channel_id, ..
+            } => {
+                format!(
+                    "bulk deletion of {} messages in channel {channel_id}",
+                    ids.len()
+                )
+            }
+            Self::ChannelDelete { channel_id, .. } => format!("deletion of channel {channel_id}"),
+            Self::GuildLeave { guild_id, .. } => format!("removal from guild {guild_id}"),
+            Self::PermissionSnapshot(snapshot) => {
+                format!("permission snapshot of channel {}", snapshot.channel_id)
+            }
+        }
+    }
+}
+
 pub struct Archiver {
     pub ignored_guilds: Vec<GuildId>,
     pub ignored_channels: Vec<ChannelId>,
+    /// Archive only these guilds, or every guild if empty
+    pub guild_whitelist: Vec<GuildId>,
     pub mong: mongodb::Client,
-    pub session_id: Uuid,
+    pub messages: CollectionLocation,
+    pub system_events: CollectionLocation,
+    pub typing_events: CollectionLocation,
+    pub permission_snapshots: CollectionLocation,
+    pub roles: CollectionLocation,
+    pub session: Session,
+    pub deletion_grace: Duration,
+    pub pending_deletions: PendingDeletions,
+    pub mark_messages_on_channel_delete: bool,
+    pub mark_messages_on_guild_leave: bool,
+    pub paused: AtomicBool,
+    /// Keep the events received while paused instead of dropping them
+    pub buffer_while_paused: bool,
+    pub paused_buffer: Mutex<Vec<ArchiveEvent>>,
+    pub edit_after_delete: EditAfterDeletePolicy,
+    pub archive_typing_events: bool,
+    pub iteration_on_noncontent_changes: bool,
+    pub record_system_events: bool,
+    pub normalize_content: bool,
+    pub index_embed_text: bool,
+    pub resolve_references: bool,
+    pub archive_roles: bool,
+    pub record_permission_snapshots: bool,
+    /// Channels whose permissions were already recorded this session
+    pub snapshotted_channels: Mutex<HashSet<ChannelId>>,
+    pub channel_types: ChannelTypes,
+    /// Where to download attachments to, if at all
+    pub attachments: Option<GridFsBucket>,
+    pub max_attachment_bytes: u64,
+    /// Content type prefixes to download, empty for all
+    pub download_content_types: Vec<String>,
+    pub http: Arc<Http>,
+    pub publisher: Option<Arc<dyn EventPublisher>>,
+    pub publish_failures: AtomicU64,
+    /// Zero treats every new message as live
+    pub max_live_message_age: Duration,
+    /// Zero disables the alarm
+    pub processing_latency_alarm: Duration,
+    pub latency_alarms: AtomicU64,
+    pub alerts: Option<Arc<Alerts>>,
+    pub hooks: Vec<Arc<dyn ArchiveHook>>,
+    pub write_strategy: WriteStrategy,
+    pub write_batch_size: usize,
+    /// New messages waiting to be inserted when batching
+    pub write_batch: tokio::sync::Mutex<Vec<(ArchivedMessage, Timestamp)>>,
+    pub slow_query_threshold: Duration,
+    pub counters: EventCounters,
 }
 
 impl Archiver {
+    /// An archiver for one of the configured bots, the rest is shared by all
+    /// of them
+    pub fn new(
+        config: &Config,
+        bot: BotConfig,
+        mong: mongodb::Client,
+        session: Session,
+        publisher: Option<Arc<dyn EventPublisher>>,
+        alerts: Option<Arc<Alerts>>,
+        hooks: Vec<Arc<dyn ArchiveHook>>,
+    ) -> Self {
+        Self {
+            mong: mong.clone(),
+            messages: config.collection_location(&bot.collection),
+            system_events: config.collection_location(SYSTEM_EVENTS),
+            typing_events: config.collection_location(TYPING_EVENTS),
+            permission_snapshots: config.collection_location(PERMISSION_SNAPSHOTS),
+            roles: config.collection_location(ROLES),
+            ignored_guilds: bot.ignored_guilds,
+            ignored_channels: bot.ignored_channels,
+            guild_whitelist: bot.guild_whitelist,
+            session,
+            deletion_grace: Duration::from_millis(config.deletion_grace_ms),
+            pending_deletions: PendingDeletions::default(),
+            mark_messages_on_channel_delete: config.mark_messages_on_channel_delete,
+            mark_messages_on_guild_leave: config.mark_messages_on_guild_leave,
+            paused: AtomicBool::new(false),
+            buffer_while_paused: config.buffer_while_paused,
+            edit_after_delete: config.edit_after_delete,
+            paused_buffer: Mutex::new(Vec::new()),
+            archive_typing_events: config.archive_typing_events,
+            iteration_on_noncontent_changes: config.iteration_on_noncontent_changes,
+            record_system_events: config.record_system_events,
+            normalize_content: config.normalize_content,
+            index_embed_text: config.index_embed_text,
+            resolve_references: config.resolve_references,
+            archive_roles: config.archive_roles,
+            record_permission_snapshots: config.record_permission_snapshots,
+            snapshotted_channels: Mutex::new(HashSet::new()),
+            channel_types: ChannelTypes::default(),
+            attachments: config
+                .download_attachments
+                .then(|| attachments_bucket(&mong, &config.collection_location(ATTACHMENTS))),
+            max_attachment_bytes: config.max_attachment_bytes,
+            download_content_types: config.download_content_types.clone(),
+            http: Arc::new(Http::new(&bot.discor_token)),
+            publisher,
+            publish_failures: AtomicU64::new(0),
+            max_live_message_age: Duration::from_secs(config.max_live_message_age_secs),
+            processing_latency_alarm: Duration::from_millis(config.processing_latency_alarm_ms),
+            latency_alarms: AtomicU64::new(0),
+            alerts,
+            hooks,
+            write_strategy: config.write_strategy,
+            write_batch_size: config.write_batch_size.max(1),
+            write_batch: tokio::sync::Mutex::new(Vec::new()),
+            slow_query_threshold: Duration::from_millis(config.slow_query_threshold_ms),
+            counters: EventCounters::default(),
+        }
+    }
+
     pub fn mong_messages(&self) -> mongodb::Collection<ArchivedMessage> {
-        self.mong.database("discor").collection("messages")
+        messages_collection(&self.mong, &self.messages)
     }
 }
 
 #[async_trait]
 impl EventHandler for Archiver {
-    async fn message(&self, _ctx: Context, msg: Message) {
+    async fn message(&self, ctx: Context, msg: Message) {
         if self.is_event_ignored(&msg.channel_id, &msg.guild_id) {
             return;
         }
-        let message_id = msg.id;
-        let archived = ArchivedMessageFull::from_gateway(msg, self.session_id);
-        if let Err(err) = self
-            .mong_messages()
-            .insert_one(
-                &ArchivedMessage::Full(archived),
-                InsertOneOptions::default(),
-            )
-            .await
-        {
-            println!("Failed to insert new message into mong: {err}");
-            return;
+        let channel_id = msg.channel_id;
+        let guild_id = msg.guild_id;
+        self.handle(ArchiveEvent::Message(Box::new(msg))).await;
+        if let Some(guild_id) = guild_id {
+            self.snapshot_permissions(&ctx, channel_id, guild_id).await;
         }
-        println!("Stored message {}", message_id);
     }
 
     async fn message_update(&self, _ctx: Context, update: MessageUpdateEvent) {
         if self.is_event_ignored(&update.channel_id, &update.guild_id) {
             return;
         }
-        let message_id = update.id;
-        let timestamp = update
-            .edited_timestamp
-            .and_then(|ts| Some(convert_ts(ts)))
-            .unwrap_or_else(Utc::now);
-        let marked_as_edited = update.edited_timestamp.is_some();
+        self.handle(ArchiveEvent::Update(Box::new(update))).await;
+    }
 
-        let filter = doc! {
-            "id": message_id.to_string(),
-        };
-        let db_message = match self.mong_messages().find_one(filter.clone(), None).await {
-            Ok(m) => m,
-            Err(err) => {
-                println!("Couldn't fetch message {message_id} from mong: {err}");
-                return;
-            }
-        };
+    async fn message_delete(
+        &self,
+        _: Context,
+        channel_id: ChannelId,
+        id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        if self.is_event_ignored(&channel_id, &guild_id) {
+            return;
+        }
+        self.handle(ArchiveEvent::Delete {
+            channel_id,
+            id,
+            guild_id,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-        let new_message = match db_message {
-            Some(db_message) => match db_message {
-                ArchivedMessage::Full(mut db_message) => {
-                    db_message
-                        .iterations
-                        .push(ArchivedMessageIteration::from_gateway(
-                            update,
-                            timestamp,
-                            self.session_id,
-                        ));
-                    db_message.marked_as_edited = marked_as_edited;
-                    ArchivedMessage::Full(db_message)
-                }
-                ArchivedMessage::Incomplete(mut db_message) => {
-                    db_message
-                        .iterations
-                        .push(ArchivedMessageIteration::from_gateway(
-                            update,
-                            timestamp,
-                            self.session_id,
-                        ));
-                    db_message.marked_as_edited = marked_as_edited;
-                    ArchivedMessage::Incomplete(db_message)
-                }
-                _ => {
-                    println!("Discor sent update for deleted message {message_id}??? wtf???");
-                    return;
-                }
-            },
-            None => ArchivedMessage::Incomplete(
-                match ArchivedMessageIncomplete::from_gateway(update, timestamp, self.session_id) {
-                    Ok(m) => m,
-                    Err(err) => {
-                        println!("Failed to create incomplete message from update event: {err}");
-                        return;
-                    }
-                },
-            ),
-        };
+    async fn guild_create(&self, _ctx: Context, guild: Guild) {
+        for (channel_id, channel) in &guild.channels {
+            self.channel_types
+                .learn(*channel_id, ArchivedChannelType::from(channel));
+        }
+        for thread in &guild.threads {
+            self.channel_types.learn(thread.id, thread.kind.into());
+        }
+    }
 
-        let encoded = match bson::to_bson(&new_message) {
-            Ok(e) => e,
-            Err(err) => {
-                println!("Failed to serialize database message: {err}");
-                return;
+    async fn channel_create(&self, _ctx: Context, channel: &GuildChannel) {
+        self.channel_types.learn(channel.id, channel.kind.into());
+    }
+
+    async fn category_create(&self, _ctx: Context, category: &ChannelCategory) {
+        self.channel_types.learn(category.id, category.kind.into());
+    }
+
+    async fn thread_create(&self, _ctx: Context, thread: GuildChannel) {
+        self.channel_types.learn(thread.id, thread.kind.into());
+        if let Some(parent_id) = thread.parent_id {
+            if !self.is_event_ignored(&parent_id, &Some(thread.guild_id)) {
+                self.mark_thread_started(thread.id, parent_id).await;
             }
+        }
+    }
+
+    async fn channel_delete(&self, _ctx: Context, channel: &GuildChannel) {
+        if self.is_event_ignored(&channel.id, &Some(channel.guild_id)) {
+            return;
+        }
+        self.handle(ArchiveEvent::ChannelDelete {
+            channel_id: channel.id,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
+
+    async fn guild_delete(&self, _ctx: Context, incomplete: UnavailableGuild) {
+        let Some(event) = guild_leave_event(&incomplete, Utc::now()) else {
+            println!("Guild {} became unavailable", incomplete.id);
+            return;
         };
-        let update = doc! {
-            "$set": encoded,
-        };
-        let options = UpdateOptions::builder().upsert(true).build();
-        match self
-            .mong_messages()
-            .update_one(filter, update, options)
+        if self.is_guild_ignored(&incomplete.id) {
+            return;
+        }
+        self.handle(event).await;
+    }
+
+    async fn typing_start(&self, _ctx: Context, event: TypingStartEvent) {
+        if !self.archive_typing_events
+            || self.paused.load(Ordering::SeqCst)
+            || self.is_event_ignored(&event.channel_id, &event.guild_id)
+        {
+            return;
+        }
+        let typing_event = TypingEvent::from_gateway(event);
+        if let Err(err) = typing_events_collection(&self.mong, &self.typing_events)
+            .insert_one(&typing_event, None)
             .await
         {
-            Ok(_) => println!("Stored update for message {message_id}"),
-            Err(err) => println!("Failed to store update for message {message_id}: {err}"),
+            println!("Failed to insert typing event into mong: {err}");
         }
     }
 
-    async fn message_delete(
+    async fn guild_role_create(&self, _ctx: Context, role: Role) {
+        self.archive_role(role).await;
+    }
+
+    async fn guild_role_update(&self, _ctx: Context, role: Role) {
+        self.archive_role(role).await;
+    }
+
+    async fn guild_role_delete(&self, _ctx: Context, guild_id: GuildId, role_id: RoleId) {
+        if !self.archive_roles || self.is_guild_ignored(&guild_id) {
+            return;
+        }
+        match roles_collection(&self.mong, &self.roles)
+            .update_one(
+                doc! { "id": role_id.to_string() },
+                doc! { "$set": { "deleted_at": bson::DateTime::now() } },
+                None,
+            )
+            .await
+        {
+            Ok(result) if result.matched_count == 0 => {
+                println!("Role {role_id} got deleted, but it isn't archived")
+            }
+            Ok(_) => println!("Marked role {role_id} as deleted"),
+            Err(err) => println!("Failed to mark role {role_id} as deleted: {err}"),
+        }
+    }
+
+    async fn message_delete_bulk(
         &self,
         _: Context,
         channel_id: ChannelId,
-        id: MessageId,
+        message_ids: Vec<MessageId>,
         guild_id: Option<GuildId>,
     ) {
         if self.is_event_ignored(&channel_id, &guild_id) {
             return;
         }
+        self.handle(ArchiveEvent::BulkDelete {
+            channel_id,
+            ids: message_ids,
+            guild_id,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
+}
 
-        println!("Message {id} deleted");
+impl Archiver {
+    fn is_event_ignored(&self, channel_id: &ChannelId, guild_id: &Option<GuildId>) -> bool {
+        self.ignored_channels.contains(channel_id)
+            || is_guild_ignored(
+                &self.ignored_guilds,
+                &self.guild_whitelist,
+                guild_id.as_ref(),
+            )
+    }
 
-        let timestamp = Utc::now();
-        let filter = doc! {
-            "id": id.to_string(),
-        };
-        let db_message = match self.mong_messages().find_one(filter.clone(), None).await {
-            Ok(m) => m,
-            Err(err) => {
-                println!("Couldn't fetch message {id} from mong: {err}");
-                return;
+    fn is_guild_ignored(&self, guild_id: &GuildId) -> bool {
+        is_guild_ignored(&self.ignored_guilds, &self.guild_whitelist, Some(guild_id))
+    }
+
+    fn cancel_pending_deletion(&self, id: MessageId) {
+        if self.pending_deletions.cancel(id) {
+            println!("Cancelling pending deletion of message {id}");
+        }
+    }
+
+    /// Archive an event, unless archiving is paused
+    async fn handle(&self, event: ArchiveEvent) {
+        if let Some(event) = self.hold_while_paused(event) {
+            self.archive(event).await;
+        }
+    }
+
+    /// Archive an event, containing any panic so one malformed event is
+    /// logged and counted instead of taking its handler down
+    ///
+    /// Debug builds still panic, so bugs don't go unnoticed while developing.
+    async fn archive(&self, event: ArchiveEvent) {
+        let description = event.describe();
+        contain_panic(
+            &description,
+            &self.counters.errored,
+            cfg!(debug_assertions),
+            self.dispatch(event),
+        )
+        .await;
+    }
+
+    async fn dispatch(&self, event: ArchiveEvent) {
+        // Everything but new messages and snapshots reads or updates stored
+        // messages
+        if !matches!(
+            event,
+            ArchiveEvent::Message(_) | ArchiveEvent::PermissionSnapshot(_)
+        ) {
+            self.flush_batch().await;
+        }
+        match event {
+            ArchiveEvent::Message(msg) => self.archive_message(*msg).await,
+            ArchiveEvent::Update(update) => self.archive_update(*update).await,
+            ArchiveEvent::Delete {
+                channel_id,
+                id,
+                guild_id,
+                timestamp,
+            } => {
+                self.archive_delete(channel_id, id, guild_id, timestamp)
+                    .await
             }
-        };
+            ArchiveEvent::BulkDelete {
+                channel_id,
+                ids,
+                guild_id,
+                timestamp,
+            } => {
+                self.archive_bulk_delete(channel_id, ids, guild_id, timestamp)
+                    .await
+            }
+            ArchiveEvent::ChannelDelete {
+                channel_id,
+                timestamp,
+            } => self.archive_channel_delete(channel_id, timestamp).await,
+            ArchiveEvent::GuildLeave {
+                guild_id,
+                timestamp,
+            } => self.archive_guild_leave(guild_id, timestamp).await,
+            ArchiveEvent::PermissionSnapshot(snapshot) => {
+                self.store_permission_snapshot(*snapshot).await
+            }
+        }
+    }
 
-        let new_message = match db_message {
-            Some(db_message) => match db_message {
-                ArchivedMessage::Full(db_message) => {
-                    ArchivedMessage::FullDeleted(db_message.into_deleted(Some(timestamp)))
+    /// Buffer or drop the event if archiving is paused, otherwise hand it
+    /// back to be archived right away
+    fn hold_while_paused(&self, event: ArchiveEvent) -> Option<ArchiveEvent> {
+        if !self.paused.load(Ordering::SeqCst) {
+            return Some(event);
+        }
+        // Resuming clears the flag while holding this lock, so check again
+        let mut buffer = self
+            .paused_buffer
+            .lock()
+            .expect("paused buffer lock poisoned");
+        if !self.paused.load(Ordering::SeqCst) {
+            return Some(event);
+        }
+        if self.buffer_while_paused {
+            println!("Archiving is paused, buffering event");
+            buffer.push(event);
+        } else {
+            println!("Archiving is paused, dropping event");
+            EventCounters::count(&self.counters.skipped);
+            // Take another snapshot with the channel's next message
+            if let ArchiveEvent::PermissionSnapshot(snapshot) = &event {
+                self.snapshotted_channels
+                    .lock()
+                    .expect("snapshotted channels lock poisoned")
+                    .remove(&snapshot.channel_id);
+            }
+        }
+        None
+    }
+
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            println!("Pausing archiving");
+        }
+    }
+
+    /// Resume archiving, first going through any events buffered in the
+    /// meantime
+    pub async fn resume(&self) {
+        if !self.paused.load(Ordering::SeqCst) {
+            return;
+        }
+        println!("Resuming archiving");
+        loop {
+            let events = {
+                let mut buffer = self
+                    .paused_buffer
+                    .lock()
+                    .expect("paused buffer lock poisoned");
+                if buffer.is_empty() {
+                    self.paused.store(false, Ordering::SeqCst);
+                    return;
                 }
-                ArchivedMessage::Incomplete(db_message) => {
-                    ArchivedMessage::IncompleteDeleted(db_message.into_deleted(Some(timestamp)))
+                std::mem::take(&mut *buffer)
+            };
+            println!("Archiving {} buffered events", events.len());
+            for event in events {
+                self.archive(event).await;
+            }
+        }
+    }
+
+    async fn archive_message(&self, msg: Message) {
+        let message_id = msg.id;
+        self.cancel_pending_deletion(message_id);
+        let replayed = is_replayed(
+            convert_ts(msg.timestamp),
+            Utc::now(),
+            self.max_live_message_age,
+        );
+        if replayed {
+            self.flush_batch().await;
+            let filter = doc! { "id": message_id.to_string() };
+            match self
+                .log_if_slow("find_one", filter, |filter| async move {
+                    self.mong_messages().find_one(filter, None).await
+                })
+                .await
+            {
+                Ok(Some(_)) => {
+                    println!("Skipping replayed message {message_id}, it's already archived");
+                    EventCounters::count(&self.counters.skipped);
+                    return;
                 }
-                _ => {
-                    println!("Discor sent delete event for deleted message {id}??? wtf???");
+                Ok(None) => println!("Archiving replayed message {message_id} we didn't have"),
+                Err(err) => {
+                    println!("Couldn't fetch replayed message {message_id} from mong: {err}");
+                    EventCounters::count(&self.counters.errored);
                     return;
                 }
-            },
-            None => ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
-                id,
-                channel_id,
-                guild_id,
-                deleted_timestamp: Some(timestamp),
-            }),
-        };
-
-        let encoded = match bson::to_bson(&new_message) {
-            Ok(e) => e,
-            Err(err) => {
-                println!("Failed to serialize database message: {err}");
-                return;
             }
+        }
+        let system_event = if self.record_system_events {
+            SystemEvent::from_message(&msg)
+        } else {
+            None
         };
-        let update = doc! {
-            "$set": encoded,
+        let pinned_message_id = pinned_message_id(&msg);
+        let referenced_message = if self.resolve_references {
+            resolve_reference(&self.http, &msg).await
+        } else {
+            None
         };
-        let options = UpdateOptions::builder().upsert(true).build();
-        match self
-            .mong_messages()
-            .update_one(filter, update, options)
+        if let Some(alerts) = &self.alerts {
+            alerts.check(&self.http, &msg);
+        }
+        let has_voice_message = msg.attachments.iter().any(looks_like_voice_message);
+        let channel_id = msg.channel_id;
+        let mut archived = ArchivedMessageFull::from_gateway(msg, &self.session);
+        archived.referenced_message = referenced_message;
+        archived.channel_type = self.channel_type(channel_id).await;
+        if replayed {
+            // We don't know what happened to it before it was replayed
+            for iteration in &mut archived.iterations {
+                iteration.may_contain_gap = true;
+            }
+        }
+        if has_voice_message {
+            match fetch_voice_attachments(&self.http, channel_id, message_id).await {
+                Ok(voice_attachments) => {
+                    archived.iterations[0].voice_attachments = voice_attachments
+                }
+                Err(err) => {
+                    println!("Failed to fetch the voice message of message {message_id}: {err}")
+                }
+            }
+        }
+        let timestamp = archived.timestamp;
+        let mut archived = ArchivedMessage::Full(archived);
+        if self.normalize_content {
+            archived.normalize_content();
+        }
+        if self.index_embed_text {
+            archived.index_embed_text();
+        }
+        warn_if_oversized(&archived);
+        if !self
+            .run_hooks(&mut archived, ArchiveNoticeKind::Created)
             .await
         {
-            Ok(_) => println!("Stored update for message {id}"),
-            Err(err) => println!("Failed to store update for message {id}: {err}"),
+            return;
+        }
+        match self.write_strategy {
+            WriteStrategy::Immediate => {
+                if let Err(err) = self
+                    .mong_messages()
+                    .insert_one(&archived, InsertOneOptions::default())
+                    .await
+                {
+                    println!("Failed to insert new message into mong: {err}");
+                    EventCounters::count(&self.counters.errored);
+                    return;
+                }
+                self.message_stored(&archived, timestamp).await;
+            }
+            WriteStrategy::Batched => {
+                let full = {
+                    let mut batch = self.write_batch.lock().await;
+                    batch.push((archived, timestamp));
+                    batch.len() >= self.write_batch_size
+                };
+                if full {
+                    self.flush_batch().await;
+                }
+            }
         }
 
-        println!("Stored deletion timestamp of message {}", id);
+        if let Some(system_event) = system_event {
+            if let Err(err) = system_events_collection(&self.mong, &self.system_events)
+                .insert_one(&system_event, None)
+                .await
+            {
+                println!("Failed to insert system event of message {message_id} into mong: {err}");
+            }
+        }
+
+        if let Some(pinned_message_id) = pinned_message_id {
+            self.mark_pinned(pinned_message_id).await;
+        }
     }
 
-    async fn message_delete_bulk(
-        &self,
-        _: Context,
-        _: ChannelId,
-        message_ids: Vec<MessageId>,
-        _: Option<GuildId>,
-    ) {
-        println!("bruh moment {message_ids:?}");
+    /// Log, count and announce a new message once it's in the database
+    async fn message_stored(&self, archived: &ArchivedMessage, timestamp: Timestamp) {
+        let message_id = archived.id();
+        println!("Stored message {message_id}");
+        EventCounters::count(&self.counters.created);
+        self.check_processing_latency(message_id, timestamp);
+        self.download_attachments(archived);
+        self.publish(ArchiveNotice::for_message(
+            ArchiveNoticeKind::Created,
+            archived,
+            timestamp,
+        ))
+        .await;
     }
-}
 
-impl Archiver {
-    fn is_event_ignored(&self, channel_id: &ChannelId, guild_id: &Option<GuildId>) -> bool {
-        match guild_id.as_ref() {
-            Some(guild_id) => {
-                self.ignored_channels.contains(channel_id) || self.ignored_guilds.contains(guild_id)
+    /// Insert the new messages collected so far
+    ///
+    /// The batch stays locked until they're written, so events handled after
+    /// a flush see the messages in the database. Messages the server rejects
+    /// don't hold back the rest, and when it can't be reached the batch is
+    /// kept for the next flush.
+    pub async fn flush_batch(&self) {
+        let mut batch = self.write_batch.lock().await;
+        if batch.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut *batch);
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let inserted = self
+            .mong_messages()
+            .insert_many(pending.iter().map(|(archived, _)| archived), options)
+            .await;
+        let rejected = match inserted {
+            Ok(_) => HashSet::new(),
+            Err(err) => match rejected_inserts(&err) {
+                Some(rejected) => {
+                    println!(
+                        "Failed to insert {} of {} new messages into mong: {err}",
+                        rejected.len(),
+                        pending.len()
+                    );
+                    rejected
+                }
+                None if is_unreachable(&err) => {
+                    println!(
+                        "Failed to insert {} new messages into mong, keeping them for the next flush: {err}",
+                        pending.len()
+                    );
+                    batch.splice(0..0, pending);
+                    return;
+                }
+                None => {
+                    println!(
+                        "Failed to insert {} new messages into mong: {err}",
+                        pending.len()
+                    );
+                    (0..pending.len()).collect()
+                }
+            },
+        };
+        for (index, (archived, timestamp)) in pending.iter().enumerate() {
+            if rejected.contains(&index) {
+                EventCounters::count(&self.counters.errored);
+            } else {
+                self.message_stored(archived, *timestamp).await;
             }
-            None => self.ignored_channels.contains(channel_id),
         }
     }
+
+    /// Download the attachments of the latest iteration that aren't stored
+    /// yet, once the message is in the database
+    ///
+    /// Runs in the background so archiving doesn't wait for the CDN, and
+    /// adds each stored attachment to the iterations that have it.
+    fn download_attachments(&self, message: &ArchivedMessage) {
+        let Some(bucket) = self.attachments.clone() else {
+            return;
+        };
+        let Some(latest) = message.latest_iteration() else {
+            return;
+        };
+        let pending = attachments_to_download(latest);
+        if pending.is_empty() {
+            return;
+        }
+        let messages = self.mong_messages();
+        let message_id = message.id();
+        let mut downloads = Vec::with_capacity(pending.len());
+        for (index, attachment) in pending {
+            match download_skip_reason(
+                &attachment,
+                self.max_attachment_bytes,
+                &self.download_content_types,
+            ) {
+                Some(reason) => println!(
+                    "Not downloading attachment {} of message {message_id}, {reason}",
+                    attachment.id
+                ),
+                None => downloads.push((index, attachment)),
+            }
+        }
+        if downloads.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            for (index, attachment) in downloads {
+                let stored = match store_attachment(&bucket, message_id, index, &attachment).await {
+                    Ok(stored) => stored,
+                    Err(err) => {
+                        println!("Attachment {} of message {message_id} {err}", attachment.id);
+                        continue;
+                    }
+                };
+                let encoded = match to_stored_bson(&stored) {
+                    Ok(encoded) => encoded,
+                    Err(err) => {
+                        println!("Failed to serialize stored attachment: {err}");
+                        continue;
+                    }
+                };
+                let update = doc! {
+                    "$addToSet": { "iterations.$[it].stored_attachments": encoded },
+                };
+                let options = UpdateOptions::builder()
+                    .array_filters(vec![
+                        doc! { "it.attachments.id": attachment.id.to_string() },
+                    ])
+                    .build();
+                if let Err(err) = messages
+                    .update_one(doc! { "id": message_id.to_string() }, update, options)
+                    .await
+                {
+                    println!(
+                        "Stored attachment {} of message {message_id} but couldn't link it: {err}",
+                        attachment.id
+                    );
+                }
+            }
+        });
+    }
+
+    /// The type of a channel, from the gateway or fetched the first time it's
+    /// needed this session
+    async fn channel_type(&self, channel_id: ChannelId) -> ArchivedChannelType {
+        if let Some(channel_type) = self.channel_types.get(channel_id) {
+            return channel_type;
+        }
+        match self.http.get_channel(channel_id.0).await {
+            Ok(channel) => {
+                let channel_type = ArchivedChannelType::from(&channel);
+                self.channel_types.learn(channel_id, channel_type);
+                channel_type
+            }
+            Err(err) => {
+                println!("Failed to fetch the type of channel {channel_id}: {err}");
+                self.channel_types.fetch_failed(channel_id);
+                ArchivedChannelType::Unknown
+            }
+        }
+    }
+
+    /// Record what the bot may do in a channel, once per channel and session
+    ///
+    /// Stored like any other event, so not while archiving is paused.
+    async fn snapshot_permissions(&self, ctx: &Context, channel_id: ChannelId, guild_id: GuildId) {
+        if !self.record_permission_snapshots {
+            return;
+        }
+        let first = self
+            .snapshotted_channels
+            .lock()
+            .expect("snapshotted channels lock poisoned")
+            .insert(channel_id);
+        if !first {
+            return;
+        }
+        let snapshot = match PermissionSnapshot::fetch(&ctx.http, channel_id, guild_id).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                println!("Failed to work out permissions in channel {channel_id}: {err}");
+                return;
+            }
+        };
+        self.handle(ArchiveEvent::PermissionSnapshot(Box::new(snapshot)))
+            .await;
+    }
+
+    async fn store_permission_snapshot(&self, snapshot: PermissionSnapshot) {
+        if let Err(err) = permission_snapshots_collection(&self.mong, &self.permission_snapshots)
+            .insert_one(&snapshot, None)
+            .await
+        {
+            println!(
+                "Failed to insert permission snapshot of channel {} into mong: {err}",
+                snapshot.channel_id
+            );
+        }
+    }
+
+    /// Store the latest state of a role, roles aren't affected by pausing
+    async fn archive_role(&self, role: Role) {
+        if !self.archive_roles || self.is_guild_ignored(&role.guild_id) {
+            return;
+        }
+        let role_id = role.id;
+        if let Err(err) = upsert_cached(
+            &roles_collection(&self.mong, &self.roles),
+            role_id,
+            &CachedRole::from(role),
+        )
+        .await
+        {
+            println!("Failed to store role {role_id} in mong: {err}");
+        }
+    }
+
+    /// Link a thread to the message it was started from
+    ///
+    /// Only new messages say which thread they started, existing ones get no
+    /// update when a thread is started from them. Such a thread shares the
+    /// message's id, threads started without a message match nothing.
+    async fn mark_thread_started(&self, thread_id: ChannelId, parent_id: ChannelId) {
+        self.flush_batch().await;
+        let filter = doc! {
+            "id": thread_id.to_string(),
+            "channel_id": parent_id.to_string(),
+            "archive_type": { "$in": ["Full", "FullDeleted"] },
+        };
+        let update = doc! { "$set": { "started_thread_id": thread_id.to_string() } };
+        match self
+            .log_if_slow("update_one", filter, |filter| async move {
+                self.mong_messages().update_one(filter, update, None).await
+            })
+            .await
+        {
+            Ok(result) if result.modified_count > 0 => {
+                println!("Linked thread {thread_id} to the message it was started from")
+            }
+            Ok(_) => {}
+            Err(err) => println!("Failed to link thread {thread_id} to its message: {err}"),
+        }
+    }
+
+    /// A `PinsAdd` system message points at the message that got pinned, but
+    /// the pinned message itself may not get an update of its own
+    async fn mark_pinned(&self, id: MessageId) {
+        self.flush_batch().await;
+        let filter =
+            doc! { "id": id.to_string(), "archive_type": { "$in": ["Full", "FullDeleted"] } };
+        let update = doc! { "$set": { "pinned": true } };
+        match self
+            .log_if_slow("update_one", filter, |filter| async move {
+                self.mong_messages().update_one(filter, update, None).await
+            })
+            .await
+        {
+            Ok(result) if result.matched_count == 0 => {
+                println!("Message {id} got pinned, but it isn't archived")
+            }
+            Ok(_) => println!("Marked message {id} as pinned"),
+            Err(err) => println!("Failed to mark message {id} as pinned: {err}"),
+        }
+    }
+
+    async fn archive_update(&self, update: MessageUpdateEvent) {
+        let message_id = update.id;
+        self.cancel_pending_deletion(message_id);
+        let timestamp = update
+            .edited_timestamp
+            .and_then(|ts| Some(convert_ts(ts)))
+            .unwrap_or_else(Utc::now);
+        let marked_as_edited = update.edited_timestamp.is_some();
+
+        let filter = doc! {
+            "id": message_id.to_string(),
+        };
+        let db_message = match self
+            .log_if_slow("find_one", filter.clone(), |filter| async move {
+                self.mong_messages().find_one(filter, None).await
+            })
+            .await
+        {
+            Ok(m) => m,
+            Err(err) => {
+                println!("Couldn't fetch message {message_id} from mong: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        };
+
+        let mut new_message = match db_message {
+            Some(db_message) => match db_message {
+                ArchivedMessage::Full(mut db_message) => {
+                    // Pinning and unpinning come in as updates carrying the
+                    // new state
+                    if let Some(pinned) = update.pinned {
+                        db_message.pinned = pinned;
+                    }
+                    apply_update(
+                        &mut db_message.iterations,
+                        update,
+                        timestamp,
+                        &self.session,
+                        self.iteration_on_noncontent_changes,
+                    );
+                    db_message.marked_as_edited = marked_as_edited;
+                    ArchivedMessage::Full(db_message)
+                }
+                ArchivedMessage::Incomplete(mut db_message) => {
+                    apply_update(
+                        &mut db_message.iterations,
+                        update,
+                        timestamp,
+                        &self.session,
+                        self.iteration_on_noncontent_changes,
+                    );
+                    db_message.marked_as_edited = marked_as_edited;
+                    ArchivedMessage::Incomplete(db_message)
+                }
+                deleted @ (ArchivedMessage::FullDeleted(_)
+                | ArchivedMessage::IncompleteDeleted(_)) => {
+                    let Some(edited) = edit_deleted(
+                        deleted,
+                        self.edit_after_delete,
+                        update,
+                        timestamp,
+                        &self.session,
+                        self.iteration_on_noncontent_changes,
+                    ) else {
+                        println!("Ignoring update for deleted message {message_id}");
+                        EventCounters::count(&self.counters.skipped);
+                        return;
+                    };
+                    if self.edit_after_delete == EditAfterDeletePolicy::AppendWithGap {
+                        EventCounters::count(&self.counters.gap_flagged);
+                    }
+                    edited
+                }
+                ArchivedMessage::UnknownDeleted(_) => {
+                    // Nothing to attach the update to
+                    println!("Ignoring update for deleted message {message_id} we know nothing of");
+                    EventCounters::count(&self.counters.skipped);
+                    return;
+                }
+            },
+            None => {
+                let mut db_message =
+                    ArchivedMessageIncomplete::from_gateway(update, timestamp, &self.session);
+                db_message.channel_type = self.channel_type(db_message.channel_id).await;
+                ArchivedMessage::Incomplete(db_message)
+            }
+        };
+
+        if self.normalize_content {
+            new_message.normalize_content();
+        }
+        if self.index_embed_text {
+            new_message.index_embed_text();
+        }
+        warn_if_oversized(&new_message);
+        if !self
+            .run_hooks(&mut new_message, ArchiveNoticeKind::Updated)
+            .await
+        {
+            return;
+        }
+        let encoded = match to_stored_bson(&new_message) {
+            Ok(e) => e,
+            Err(err) => {
+                println!("Failed to serialize database message: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        };
+        let update = doc! {
+            "$set": encoded,
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        match self
+            .log_if_slow("update_one", filter, |filter| async move {
+                self.mong_messages()
+                    .update_one(filter, update, options)
+                    .await
+            })
+            .await
+        {
+            Ok(_) => println!("Stored update for message {message_id}"),
+            Err(err) => {
+                println!("Failed to store update for message {message_id}: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        }
+        EventCounters::count(&self.counters.updated);
+        self.check_processing_latency(message_id, timestamp);
+        self.download_attachments(&new_message);
+        self.publish(ArchiveNotice::for_message(
+            ArchiveNoticeKind::Updated,
+            &new_message,
+            timestamp,
+        ))
+        .await;
+    }
+
+    /// Pass a document through the registered hooks, whether it should be
+    /// written
+    async fn run_hooks(&self, message: &mut ArchivedMessage, kind: ArchiveNoticeKind) -> bool {
+        match run_hooks(&self.hooks, message, kind).await {
+            Ok(()) => true,
+            Err(rejection) => {
+                println!("{rejection}");
+                EventCounters::count(&self.counters.skipped);
+                false
+            }
+        }
+    }
+
+    /// Warn when we're falling behind, measured from when Discord says the
+    /// event happened to when we're done writing it
+    fn check_processing_latency(&self, id: MessageId, event_timestamp: Timestamp) {
+        if let Some(latency) =
+            slow_processing(event_timestamp, Utc::now(), self.processing_latency_alarm)
+        {
+            let alarms = self.latency_alarms.fetch_add(1, Ordering::Relaxed) + 1;
+            println!(
+                "Message {id} took {}ms to archive ({alarms} slow archivals so far)",
+                latency.as_millis()
+            );
+        }
+    }
+
+    /// Tell the broker about a successful write, if there is one
+    async fn publish(&self, notice: ArchiveNotice) {
+        let Some(publisher) = &self.publisher else {
+            return;
+        };
+        if let Err(err) = publisher.publish(&notice).await {
+            let failures = self.publish_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            println!("Failed to publish archive notice ({failures} failures so far): {err}");
+        }
+    }
+
+    /// [`mong::log_if_slow`] with the configured threshold
+    async fn log_if_slow<T, F>(
+        &self,
+        operation: &str,
+        filter: Document,
+        query: impl FnOnce(Document) -> F,
+    ) -> T
+    where
+        F: Future<Output = T>,
+    {
+        mong::log_if_slow(self.slow_query_threshold, operation, filter, query).await
+    }
+
+    async fn archive_delete(
+        &self,
+        channel_id: ChannelId,
+        id: MessageId,
+        guild_id: Option<GuildId>,
+        timestamp: Timestamp,
+    ) {
+        println!("Message {id} deleted");
+
+        if self.wait_out_deletion_grace(vec![id]).await.is_empty() {
+            return;
+        }
+
+        let filter = doc! {
+            "id": id.to_string(),
+        };
+        let db_message = match self
+            .log_if_slow("find_one", filter.clone(), |filter| async move {
+                self.mong_messages().find_one(filter, None).await
+            })
+            .await
+        {
+            Ok(m) => m,
+            Err(err) => {
+                println!("Couldn't fetch message {id} from mong: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        };
+
+        let Some(mut new_message) = self
+            .deleted_version(db_message, channel_id, id, guild_id, timestamp)
+            .await
+        else {
+            return;
+        };
+
+        if !self
+            .run_hooks(&mut new_message, ArchiveNoticeKind::Deleted)
+            .await
+        {
+            return;
+        }
+        let encoded = match to_stored_bson(&new_message) {
+            Ok(e) => e,
+            Err(err) => {
+                println!("Failed to serialize database message: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        };
+        let update = doc! {
+            "$set": encoded,
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        match self
+            .log_if_slow("update_one", filter, |filter| async move {
+                self.mong_messages()
+                    .update_one(filter, update, options)
+                    .await
+            })
+            .await
+        {
+            Ok(_) => println!("Stored update for message {id}"),
+            Err(err) => {
+                println!("Failed to store update for message {id}: {err}");
+                EventCounters::count(&self.counters.errored);
+                return;
+            }
+        }
+
+        println!("Stored deletion timestamp of message {}", id);
+
+        EventCounters::count(&self.counters.deleted);
+        self.publish(ArchiveNotice::for_message(
+            ArchiveNoticeKind::Deleted,
+            &new_message,
+            timestamp,
+        ))
+        .await;
+    }
+
+    /// Archive the deletion of many messages of a channel at once, as
+    /// moderators purging a channel do
+    ///
+    /// They share one deletion timestamp and are read with one round trip.
+    /// Messages already archived are written concurrently, the rest with one
+    /// insert.
+    async fn archive_bulk_delete(
+        &self,
+        channel_id: ChannelId,
+        ids: Vec<MessageId>,
+        guild_id: Option<GuildId>,
+        timestamp: Timestamp,
+    ) {
+        println!(
+            "{} messages deleted at once in channel {channel_id}",
+            ids.len()
+        );
+
+        let ids = self.wait_out_deletion_grace(ids).await;
+        if ids.is_empty() {
+            return;
+        }
+
+        let filter = doc! {
+            "id": { "$in": ids.iter().map(ToString::to_string).collect::<Vec<_>>() },
+        };
+        let found = self
+            .log_if_slow("find", filter, |filter| async move {
+                let mut cursor = self.mong_messages().find(filter, None).await?;
+                let mut found = HashMap::new();
+                while cursor.advance().await? {
+                    let message = cursor.deserialize_current()?;
+                    found.insert(message.id(), message);
+                }
+                Ok::<_, mongodb::error::Error>(found)
+            })
+            .await;
+        let mut db_messages = match found {
+            Ok(found) => found,
+            Err(err) => {
+                println!("Couldn't fetch bulk deleted messages from mong: {err}");
+                self.counters
+                    .errored
+                    .fetch_add(ids.len() as u64, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        // Messages already archived are replaced only if they weren't edited or
+        // deleted since they were read, the others are inserted
+        let mut marked = Vec::with_capacity(ids.len());
+        let mut inserted = Vec::new();
+        for id in ids {
+            let db_message = db_messages.remove(&id);
+            let Some(mut new_message) = self
+                .deleted_version(db_message, channel_id, id, guild_id, timestamp)
+                .await
+            else {
+                continue;
+            };
+            let read_as = read_state(&new_message);
+            if !self
+                .run_hooks(&mut new_message, ArchiveNoticeKind::Deleted)
+                .await
+            {
+                continue;
+            }
+            let Some(read_as) = read_as else {
+                inserted.push(new_message);
+                continue;
+            };
+            match deletion_update(read_as, &new_message) {
+                Ok((filter, update)) => marked.push((new_message, filter, update)),
+                Err(err) => {
+                    println!("Failed to serialize database message: {err}");
+                    EventCounters::count(&self.counters.errored);
+                }
+            }
+        }
+
+        let mut stored = Vec::with_capacity(marked.len() + inserted.len());
+        let writes = marked
+            .into_iter()
+            .map(|(new_message, filter, update)| async move {
+                let result = self
+                    .log_if_slow("update_one", filter, |filter| async move {
+                        self.mong_messages().update_one(filter, update, None).await
+                    })
+                    .await;
+                (new_message, result)
+            });
+        for (new_message, result) in futures::future::join_all(writes).await {
+            match result {
+                Ok(result) if result.modified_count > 0 => stored.push(new_message),
+                Ok(_) => {
+                    println!(
+                        "Message {} changed since it was read, not storing its bulk deletion",
+                        new_message.id()
+                    );
+                    EventCounters::count(&self.counters.skipped);
+                }
+                Err(err) => {
+                    println!(
+                        "Failed to store deletion of message {}: {err}",
+                        new_message.id()
+                    );
+                    EventCounters::count(&self.counters.errored);
+                }
+            }
+        }
+
+        if !inserted.is_empty() {
+            let options = InsertManyOptions::builder().ordered(false).build();
+            let rejected = match self.mong_messages().insert_many(&inserted, options).await {
+                Ok(_) => HashSet::new(),
+                Err(err) => {
+                    println!("Failed to store bulk deletion in channel {channel_id}: {err}");
+                    rejected_inserts(&err).unwrap_or_else(|| (0..inserted.len()).collect())
+                }
+            };
+            for (index, new_message) in inserted.into_iter().enumerate() {
+                if rejected.contains(&index) {
+                    EventCounters::count(&self.counters.errored);
+                } else {
+                    stored.push(new_message);
+                }
+            }
+        }
+
+        println!("Stored deletion timestamp of {} messages", stored.len());
+        for new_message in stored {
+            EventCounters::count(&self.counters.deleted);
+            self.publish(ArchiveNotice::for_message(
+                ArchiveNoticeKind::Deleted,
+                &new_message,
+                timestamp,
+            ))
+            .await;
+        }
+    }
+
+    /// Hold deletions for the grace period, returning the ones that weren't
+    /// cancelled by a newer event meanwhile
+    async fn wait_out_deletion_grace(&self, ids: Vec<MessageId>) -> Vec<MessageId> {
+        let kept = self
+            .pending_deletions
+            .wait_out(ids.clone(), self.deletion_grace)
+            .await;
+        for id in ids.iter().filter(|id| !kept.contains(id)) {
+            println!("Deletion of message {id} was cancelled by a newer event");
+            EventCounters::count(&self.counters.skipped);
+        }
+        kept
+    }
+
+    /// What an archived message becomes once deleted, `None` if it already
+    /// was
+    async fn deleted_version(
+        &self,
+        db_message: Option<ArchivedMessage>,
+        channel_id: ChannelId,
+        id: MessageId,
+        guild_id: Option<GuildId>,
+        timestamp: Timestamp,
+    ) -> Option<ArchivedMessage> {
+        let new_message = match db_message {
+            Some(db_message) => match db_message {
+                ArchivedMessage::Full(db_message) => {
+                    if let Some(event) = ThreadStarterDeleted::from_deleted(&db_message, timestamp)
+                    {
+                        self.record_thread_starter_deletion(event).await;
+                    }
+                    ArchivedMessage::FullDeleted(db_message.into_deleted(Some(timestamp)))
+                }
+                ArchivedMessage::Incomplete(db_message) => {
+                    ArchivedMessage::IncompleteDeleted(db_message.into_deleted(Some(timestamp)))
+                }
+                _ => {
+                    println!("Discor sent delete event for deleted message {id}??? wtf???");
+                    return None;
+                }
+            },
+            None => ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
+                id,
+                channel_id,
+                guild_id,
+                deleted_timestamp: Some(timestamp),
+                channel_deleted_at: None,
+                archive_stopped_at: None,
+            }),
+        };
+        Some(new_message)
+    }
+
+    /// There's no threads cache to mark the thread in, so the deletion is
+    /// only recorded as a system event
+    async fn record_thread_starter_deletion(&self, event: ThreadStarterDeleted) {
+        println!(
+            "Starter message {} of thread {} deleted, the thread may be gone too",
+            event.message_id, event.thread_id
+        );
+        if !self.record_system_events {
+            return;
+        }
+        let message_id = event.message_id;
+        let event = SystemEvent::ThreadStarterDeleted(event);
+        if let Err(err) = system_events_collection(&self.mong, &self.system_events)
+            .insert_one(&event, None)
+            .await
+        {
+            println!(
+                "Failed to insert thread starter deletion of message {message_id} into mong: {err}"
+            );
+        }
+    }
+
+    async fn archive_channel_delete(&self, channel_id: ChannelId, timestamp: Timestamp) {
+        println!("Channel {channel_id} deleted");
+
+        if !self.mark_messages_on_channel_delete {
+            return;
+        }
+
+        let (filter, update) = channel_delete_update(channel_id, timestamp);
+        match self
+            .log_if_slow("update_many", filter, |filter| async move {
+                self.mong_messages().update_many(filter, update, None).await
+            })
+            .await
+        {
+            Ok(result) => {
+                println!(
+                    "Marked {} messages of deleted channel {channel_id}",
+                    result.modified_count
+                );
+                self.publish(ArchiveNotice {
+                    kind: ArchiveNoticeKind::ChannelDeleted,
+                    message_id: None,
+                    channel_id,
+                    guild_id: None,
+                    timestamp,
+                })
+                .await;
+            }
+            Err(err) => println!("Failed to mark messages of deleted channel {channel_id}: {err}"),
+        }
+    }
+
+    async fn archive_guild_leave(&self, guild_id: GuildId, timestamp: Timestamp) {
+        println!("Removed from guild {guild_id}");
+
+        if self.record_system_events {
+            let event = SystemEvent::GuildLeft(GuildLeft {
+                guild_id,
+                left_at: timestamp,
+            });
+            if let Err(err) = system_events_collection(&self.mong, &self.system_events)
+                .insert_one(&event, None)
+                .await
+            {
+                println!("Failed to insert removal from guild {guild_id} into mong: {err}");
+            }
+        }
+
+        if !self.mark_messages_on_guild_leave {
+            return;
+        }
+
+        let (filter, update) = guild_leave_update(guild_id, timestamp);
+        match self
+            .log_if_slow("update_many", filter, |filter| async move {
+                self.mong_messages().update_many(filter, update, None).await
+            })
+            .await
+        {
+            Ok(result) => println!(
+                "Marked {} messages of left guild {guild_id}",
+                result.modified_count
+            ),
+            Err(err) => println!("Failed to mark messages of left guild {guild_id}: {err}"),
+        }
+    }
+}
+
+/// How long after the event it was stored, `None` if our clock is behind
+/// Discord's
+fn processing_latency(event_timestamp: Timestamp, stored_at: Timestamp) -> Option<Duration> {
+    (stored_at - event_timestamp).to_std().ok()
+}
+
+/// Record an update in the iterations of an archived message
+///
+/// Updates that leave the content as is only get their own iteration if
+/// configured to, otherwise they're folded into the latest one.
+fn apply_update(
+    iterations: &mut Vec<ArchivedMessageIteration>,
+    update: MessageUpdateEvent,
+    timestamp: Timestamp,
+    session: &Session,
+    iteration_on_noncontent_changes: bool,
+) {
+    if !iteration_on_noncontent_changes {
+        if let Some(latest) = iterations.last_mut() {
+            if !latest.is_content_changed_by(&update) {
+                latest.apply_noncontent_update(update);
+                if let [.., previous, latest] = iterations.as_mut_slice() {
+                    latest.attachment_changes =
+                        AttachmentChanges::between(&previous.attachments, &latest.attachments);
+                }
+                return;
+            }
+        }
+    }
+    let iteration =
+        ArchivedMessageIteration::from_gateway(update, iterations.last(), timestamp, session);
+    push_iteration(iterations, iteration);
+}
+
+/// Always add a separate iteration flagged as possibly missing history, for
+/// updates of messages we thought were deleted
+fn apply_update_with_gap(
+    iterations: &mut Vec<ArchivedMessageIteration>,
+    update: MessageUpdateEvent,
+    timestamp: Timestamp,
+    session: &Session,
+) {
+    let mut iteration =
+        ArchivedMessageIteration::from_gateway(update, iterations.last(), timestamp, session);
+    iteration.may_contain_gap = true;
+    push_iteration(iterations, iteration);
+}
+
+/// Apply an update of a message we have as deleted the way `policy` says,
+/// `None` if the update should be dropped
+fn edit_deleted(
+    message: ArchivedMessage,
+    policy: EditAfterDeletePolicy,
+    update: MessageUpdateEvent,
+    timestamp: Timestamp,
+    session: &Session,
+    iteration_on_noncontent_changes: bool,
+) -> Option<ArchivedMessage> {
+    let marked_as_edited = update.edited_timestamp.is_some();
+    let message = match (message, policy) {
+        (_, EditAfterDeletePolicy::Ignore) => return None,
+        (ArchivedMessage::FullDeleted(mut m), EditAfterDeletePolicy::AppendWithGap) => {
+            apply_update_with_gap(&mut m.iterations, update, timestamp, session);
+            m.marked_as_edited = marked_as_edited;
+            ArchivedMessage::FullDeleted(m)
+        }
+        (ArchivedMessage::IncompleteDeleted(mut m), EditAfterDeletePolicy::AppendWithGap) => {
+            apply_update_with_gap(&mut m.iterations, update, timestamp, session);
+            m.marked_as_edited = marked_as_edited;
+            ArchivedMessage::IncompleteDeleted(m)
+        }
+        (ArchivedMessage::FullDeleted(m), EditAfterDeletePolicy::Resurrect) => {
+            println!("Resurrecting deleted message {}", m.id);
+            let mut m = m.into_undeleted(timestamp);
+            apply_update(
+                &mut m.iterations,
+                update,
+                timestamp,
+                session,
+                iteration_on_noncontent_changes,
+            );
+            m.marked_as_edited = marked_as_edited;
+            ArchivedMessage::Full(m)
+        }
+        (ArchivedMessage::IncompleteDeleted(m), EditAfterDeletePolicy::Resurrect) => {
+            println!("Resurrecting deleted message {}", m.id);
+            let mut m = m.into_undeleted(timestamp);
+            apply_update(
+                &mut m.iterations,
+                update,
+                timestamp,
+                session,
+                iteration_on_noncontent_changes,
+            );
+            m.marked_as_edited = marked_as_edited;
+            ArchivedMessage::Incomplete(m)
+        }
+        // Messages that aren't deleted, or that we know nothing of
+        _ => return None,
+    };
+    Some(message)
+}
+
+/// How long an event took to archive, if that's past `alarm`, which is off
+/// when zero
+fn slow_processing(
+    event_timestamp: Timestamp,
+    stored_at: Timestamp,
+    alarm: Duration,
+) -> Option<Duration> {
+    if alarm.is_zero() {
+        return None;
+    }
+    processing_latency(event_timestamp, stored_at).filter(|latency| *latency > alarm)
+}
+
+/// Whether a new message was sent too long ago to be live, Discord replays
+/// old events after some reconnects
+///
+/// Messages a little late because of a slow gateway or clock skew stay
+/// under any sensible threshold.
+fn is_replayed(sent: Timestamp, now: Timestamp, max_age: Duration) -> bool {
+    !max_age.is_zero() && processing_latency(sent, now).is_some_and(|age| age > max_age)
+}
+
+/// The message a `PinsAdd` system message says got pinned
+fn pinned_message_id(msg: &Message) -> Option<MessageId> {
+    match msg.kind {
+        MessageType::PinsAdd => msg
+            .message_reference
+            .as_ref()
+            .and_then(|reference| reference.message_id),
+        _ => None,
+    }
+}
+
+/// The attachments of an iteration that aren't stored yet, with their
+/// positions
+fn attachments_to_download(iteration: &ArchivedMessageIteration) -> Vec<(usize, Attachment)> {
+    iteration
+        .attachments
+        .iter()
+        .enumerate()
+        .filter(|(_, attachment)| {
+            !iteration
+                .stored_attachments
+                .iter()
+                .any(|stored| stored.attachment_id == attachment.id)
+        })
+        .map(|(index, attachment)| (index, attachment.clone()))
+        .collect()
+}
+
+/// Why an attachment isn't downloaded, `None` if it should be
+///
+/// With `content_types` set, attachments Discord gave no type for aren't
+/// downloaded either.
+fn download_skip_reason(
+    attachment: &Attachment,
+    max_bytes: u64,
+    content_types: &[String],
+) -> Option<String> {
+    if attachment.size > max_bytes {
+        return Some(format!("it's {} bytes", attachment.size));
+    }
+    if content_types.is_empty() {
+        return None;
+    }
+    let content_type = attachment.content_type.as_deref().unwrap_or_default();
+    let allowed = content_types.iter().any(|prefix| {
+        content_type
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    });
+    if allowed {
+        None
+    } else if content_type.is_empty() {
+        Some("its content type is unknown".to_string())
+    } else {
+        Some(format!("it's {content_type}"))
+    }
+}
+
+/// Whether events of a guild are left out, `None` for direct messages
+///
+/// Direct messages are left out when there's a whitelist, since they
+/// aren't in any of its guilds.
+fn is_guild_ignored(
+    ignored: &[GuildId],
+    whitelist: &[GuildId],
+    guild_id: Option<&GuildId>,
+) -> bool {
+    match guild_id {
+        Some(guild_id) => {
+            ignored.contains(guild_id) || !(whitelist.is_empty() || whitelist.contains(guild_id))
+        }
+        None => !whitelist.is_empty(),
+    }
+}
+
+/// The positions of the documents the server rejected in an unordered
+/// `insert_many`, `None` if the error isn't about single documents
+fn rejected_inserts(err: &mongodb::error::Error) -> Option<HashSet<usize>> {
+    match err.kind.as_ref() {
+        ErrorKind::BulkWrite(failure) => Some(
+            failure
+                .write_errors
+                .iter()
+                .flatten()
+                .map(|error| error.index)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Whether the server couldn't be reached, so the write can be tried again
+fn is_unreachable(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+    )
+}
+
+/// The type a deleted message was read as and how many iterations it had,
+/// `None` if it wasn't archived
+fn read_state(deleted: &ArchivedMessage) -> Option<(&'static str, usize)> {
+    match deleted {
+        ArchivedMessage::FullDeleted(m) => Some(("Full", m.iterations.len())),
+        ArchivedMessage::IncompleteDeleted(m) => Some(("Incomplete", m.iterations.len())),
+        _ => None,
+    }
+}
+
+/// The filter and update storing the deleted version of a message, matching
+/// only while it's still as it was read so newer edits and deletions aren't
+/// overwritten
+fn deletion_update(
+    (archive_type, iteration_count): (&str, usize),
+    deleted: &ArchivedMessage,
+) -> bson::ser::Result<(Document, Document)> {
+    let filter = doc! {
+        "id": deleted.id().to_string(),
+        "archive_type": archive_type,
+        "iterations": { "$size": iteration_count as i64 },
+    };
+    let update = doc! { "$set": to_stored_bson(deleted)? };
+    Ok((filter, update))
+}
+
+/// Log content longer than Discord allows, so it can be looked into later
+fn warn_if_oversized(message: &ArchivedMessage) {
+    if let Some(iteration) = message.latest_iteration() {
+        if iteration.is_content_oversized() {
+            println!(
+                "Message {} has {} characters of content, more than the expected {MAX_CONTENT_CHARS}",
+                message.id(),
+                iteration.content.chars().count()
+            );
+        }
+    }
+}
+
+/// Mark every archived message of a deleted channel
+fn channel_delete_update(channel_id: ChannelId, timestamp: Timestamp) -> (Document, Document) {
+    let filter = doc! {
+        "channel_id": channel_id.to_string(),
+    };
+    let update = doc! {
+        "$set": {
+            "channel_deleted_at": bson::DateTime::from_chrono(timestamp),
+        },
+    };
+    (filter, update)
+}
+
+/// Run `work`, logging a panic in it and counting it in `errored`, then
+/// carrying on unless `resume` is set
+async fn contain_panic(
+    description: &str,
+    errored: &AtomicU64,
+    resume: bool,
+    work: impl Future<Output = ()>,
+) {
+    let Err(panic) = AssertUnwindSafe(work).catch_unwind().await else {
+        return;
+    };
+    println!(
+        "Archiving {description} panicked: {}",
+        panic_reason(panic.as_ref())
+    );
+    EventCounters::count(errored);
+    if resume {
+        panic::resume_unwind(panic);
+    }
+}
+
+/// The message a panic was raised with, if it had one
+fn panic_reason(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+/// Outages also remove the guild for a while, it comes back by itself, so
+/// only an actual removal of the bot is a `GuildLeave`
+fn guild_leave_event(guild: &UnavailableGuild, timestamp: Timestamp) -> Option<ArchiveEvent> {
+    if guild.unavailable {
+        return None;
+    }
+    Some(ArchiveEvent::GuildLeave {
+        guild_id: guild.id,
+        timestamp,
+    })
+}
+
+/// Mark every archived message of a guild the bot was removed from
+fn guild_leave_update(guild_id: GuildId, timestamp: Timestamp) -> (Document, Document) {
+    let filter = doc! {
+        "guild_id": guild_id.to_string(),
+    };
+    let update = doc! {
+        "$set": {
+            "archive_stopped_at": bson::DateTime::from_chrono(timestamp),
+        },
+    };
+    (filter, update)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use serde_json::json;
+    use serenity::model::id::AttachmentId;
+
+    use super::*;
+    use crate::{
+        attachment_download::StoredAttachment, hook::HookOutcome, mong::to_stored_document,
+        test_util,
+    };
+
+    /// Apply a `$set` update to a stored message, if the filter matches its
+    /// fields
+    fn apply_set(
+        message: &ArchivedMessage,
+        filter: &Document,
+        update: &Document,
+    ) -> ArchivedMessage {
+        let mut stored = to_stored_document(message).unwrap();
+        if filter
+            .iter()
+            .all(|(key, value)| stored.get(key) == Some(value))
+        {
+            stored.extend(update.get_document("$set").unwrap().clone());
+        }
+        bson::from_document(stored).unwrap()
+    }
+
+    fn deleted_full(session: &Session) -> ArchivedMessage {
+        let message = test_util::message(1, json!({ "content": "original" }));
+        let deleted = ArchivedMessageFull::from_gateway(message, session).into_deleted(None);
+        ArchivedMessage::FullDeleted(deleted)
+    }
+
+    fn deleted_incomplete(session: &Session) -> ArchivedMessage {
+        let update = test_util::update(1, json!({ "content": "seen in an edit" }));
+        let deleted =
+            ArchivedMessageIncomplete::from_gateway(update, Utc::now(), session).into_deleted(None);
+        ArchivedMessage::IncompleteDeleted(deleted)
+    }
+
+    fn edit() -> MessageUpdateEvent {
+        test_util::update(
+            1,
+            json!({
+                "content": "edited",
+                "edited_timestamp": "2023-11-14T22:20:00.000000+00:00",
+            }),
+        )
+    }
+
+    fn contents(message: &ArchivedMessage) -> Vec<&str> {
+        let iterations = match message {
+            ArchivedMessage::Full(m) => &m.iterations,
+            ArchivedMessage::FullDeleted(m) => &m.iterations,
+            ArchivedMessage::Incomplete(m) => &m.iterations,
+            ArchivedMessage::IncompleteDeleted(m) => &m.iterations,
+            ArchivedMessage::UnknownDeleted(_) => return vec![],
+        };
+        iterations.iter().map(|i| i.content.as_str()).collect()
+    }
+
+    #[test]
+    fn ignore_drops_edits_of_deleted_messages() {
+        let session = Session::new(None);
+        for deleted in [deleted_full(&session), deleted_incomplete(&session)] {
+            let edited = edit_deleted(
+                deleted,
+                EditAfterDeletePolicy::Ignore,
+                edit(),
+                Utc::now(),
+                &session,
+                false,
+            );
+            assert!(edited.is_none());
+        }
+    }
+
+    #[test]
+    fn append_with_gap_keeps_message_deleted() {
+        let session = Session::new(None);
+        let edited = edit_deleted(
+            deleted_full(&session),
+            EditAfterDeletePolicy::AppendWithGap,
+            edit(),
+            Utc::now(),
+            &session,
+            false,
+        )
+        .unwrap();
+        let ArchivedMessage::FullDeleted(edited) = edited else {
+            panic!("expected the message to stay deleted, got {edited:?}");
+        };
+        assert!(edited.marked_as_edited);
+        let [original, appended] = edited.iterations.as_slice() else {
+            panic!("expected two iterations, got {:?}", edited.iterations);
+        };
+        assert_eq!(original.content, "original");
+        assert!(!original.may_contain_gap);
+        assert_eq!(appended.content, "edited");
+        assert!(appended.may_contain_gap);
+
+        let edited = edit_deleted(
+            deleted_incomplete(&session),
+            EditAfterDeletePolicy::AppendWithGap,
+            edit(),
+            Utc::now(),
+            &session,
+            false,
+        )
+        .unwrap();
+        assert!(matches!(edited, ArchivedMessage::IncompleteDeleted(_)));
+        assert_eq!(contents(&edited), ["seen in an edit", "edited"]);
+    }
+
+    #[test]
+    fn resurrect_undeletes_message() {
+        let session = Session::new(None);
+        let edited = edit_deleted(
+            deleted_full(&session),
+            EditAfterDeletePolicy::Resurrect,
+            edit(),
+            Utc::now(),
+            &session,
+            false,
+        )
+        .unwrap();
+        let ArchivedMessage::Full(edited) = &edited else {
+            panic!("expected the message to be undeleted, got {edited:?}");
+        };
+        assert_eq!(edited.earlier_deletions.len(), 1);
+        assert!(edited.marked_as_edited);
+        assert!(edited.iterations.iter().all(|i| !i.may_contain_gap));
+
+        let edited = edit_deleted(
+            deleted_incomplete(&session),
+            EditAfterDeletePolicy::Resurrect,
+            edit(),
+            Utc::now(),
+            &session,
+            false,
+        )
+        .unwrap();
+        assert!(matches!(edited, ArchivedMessage::Incomplete(_)));
+        assert_eq!(contents(&edited), ["seen in an edit", "edited"]);
+    }
+
+    #[test]
+    fn policies_leave_live_messages_alone() {
+        let session = Session::new(None);
+        let message = test_util::message(1, json!({}));
+        let live = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+        let edited = edit_deleted(
+            live,
+            EditAfterDeletePolicy::Resurrect,
+            edit(),
+            Utc::now(),
+            &session,
+            false,
+        );
+        assert!(edited.is_none());
+    }
+
+    #[test]
+    fn pins_add_links_to_pinned_message() {
+        let pins_add = test_util::message(
+            2,
+            json!({
+                "type": 6,
+                "message_reference": { "channel_id": "20", "message_id": "1" },
+            }),
+        );
+        assert_eq!(pinned_message_id(&pins_add), Some(MessageId(1)));
+    }
+
+    #[test]
+    fn other_messages_pin_nothing() {
+        let reply = test_util::message(
+            2,
+            json!({
+                "type": 19,
+                "message_reference": { "channel_id": "20", "message_id": "1" },
+            }),
+        );
+        assert_eq!(pinned_message_id(&reply), None);
+        assert_eq!(pinned_message_id(&test_util::message(3, json!({}))), None);
+    }
+
+    /// Appends a tag to the latest content
+    struct Tag;
+
+    #[async_trait]
+    impl ArchiveHook for Tag {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        async fn before_write(
+            &self,
+            message: &mut ArchivedMessage,
+            _kind: ArchiveNoticeKind,
+        ) -> HookOutcome {
+            if let ArchivedMessage::FullDeleted(message) = message {
+                if let Some(iteration) = message.iterations.last_mut() {
+                    iteration.content.push_str(" #tagged");
+                }
+            }
+            HookOutcome::Write
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_deletion_stores_what_hooks_tagged() {
+        let session = Session::new(None);
+        let mut deleted = deleted_full(&session);
+        let read_as = read_state(&deleted);
+        assert_eq!(read_as, Some(("Full", 1)));
+
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(Tag)];
+        run_hooks(&hooks, &mut deleted, ArchiveNoticeKind::Deleted)
+            .await
+            .unwrap();
+        let (filter, update) = deletion_update(read_as.unwrap(), &deleted).unwrap();
+        assert_eq!(
+            filter,
+            doc! { "id": "1", "archive_type": "Full", "iterations": { "$size": 1_i64 } }
+        );
+        let set = update.get_document("$set").unwrap();
+        assert_eq!(set.get_str("archive_type"), Ok("FullDeleted"));
+        let iterations = set.get_array("iterations").unwrap();
+        let latest = iterations[0].as_document().unwrap();
+        assert_eq!(latest.get_str("content"), Ok("original #tagged"));
+    }
+
+    #[test]
+    fn unarchived_messages_are_inserted() {
+        let deleted = ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
+            id: MessageId(1),
+            channel_id: ChannelId(20),
+            guild_id: None,
+            deleted_timestamp: None,
+            channel_deleted_at: None,
+            archive_stopped_at: None,
+        });
+        assert_eq!(read_state(&deleted), None);
+    }
+
+    const LISTED: GuildId = GuildId(30);
+    const OTHER: GuildId = GuildId(31);
+
+    #[test]
+    fn empty_whitelist_archives_every_guild() {
+        assert!(!is_guild_ignored(&[], &[], Some(&LISTED)));
+        assert!(!is_guild_ignored(&[], &[], Some(&OTHER)));
+        assert!(!is_guild_ignored(&[], &[], None));
+    }
+
+    #[test]
+    fn whitelist_archives_only_listed_guilds() {
+        let whitelist = [LISTED];
+        assert!(!is_guild_ignored(&[], &whitelist, Some(&LISTED)));
+        assert!(is_guild_ignored(&[], &whitelist, Some(&OTHER)));
+        assert!(is_guild_ignored(&[], &whitelist, None));
+    }
+
+    #[test]
+    fn ignored_guilds_win_over_the_whitelist() {
+        assert!(is_guild_ignored(&[LISTED], &[LISTED], Some(&LISTED)));
+        assert!(is_guild_ignored(&[LISTED], &[], Some(&LISTED)));
+    }
+
+    #[test]
+    fn only_attachments_not_stored_yet_are_downloaded() {
+        let message = test_util::message(
+            1,
+            json!({ "attachments": [test_util::attachment(5), test_util::attachment(6)] }),
+        );
+        let mut archived = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        let iteration = &mut archived.iterations[0];
+        let pending: Vec<_> = attachments_to_download(iteration)
+            .into_iter()
+            .map(|(index, attachment)| (index, attachment.id))
+            .collect();
+        assert_eq!(pending, [(0, AttachmentId(5)), (1, AttachmentId(6))]);
+
+        iteration.stored_attachments.push(StoredAttachment {
+            attachment_id: AttachmentId(5),
+            file_id: bson::oid::ObjectId::new(),
+        });
+        let pending: Vec<_> = attachments_to_download(iteration)
+            .into_iter()
+            .map(|(index, attachment)| (index, attachment.id))
+            .collect();
+        assert_eq!(pending, [(1, AttachmentId(6))]);
+    }
+
+    #[test]
+    fn edits_only_download_the_attachments_they_add() {
+        let session = Session::new(None);
+        let message = test_util::message(1, json!({ "attachments": [test_util::attachment(5)] }));
+        let mut archived = ArchivedMessageFull::from_gateway(message, &session);
+        let previous = &mut archived.iterations[0];
+        previous.stored_attachments.push(StoredAttachment {
+            attachment_id: AttachmentId(5),
+            file_id: bson::oid::ObjectId::new(),
+        });
+
+        let edit = test_util::update(
+            1,
+            json!({ "attachments": [test_util::attachment(5), test_util::attachment(6)] }),
+        );
+        let edited =
+            ArchivedMessageIteration::from_gateway(edit, Some(previous), Utc::now(), &session);
+        let pending: Vec<_> = attachments_to_download(&edited)
+            .into_iter()
+            .map(|(index, attachment)| (index, attachment.id))
+            .collect();
+        assert_eq!(pending, [(1, AttachmentId(6))]);
+    }
+
+    fn attachment(size: u64, content_type: &str) -> Attachment {
+        let mut attachment = test_util::attachment(5);
+        attachment["size"] = json!(size);
+        attachment["content_type"] = json!(content_type);
+        serde_json::from_value(attachment).unwrap()
+    }
+
+    #[test]
+    fn only_allowed_content_types_are_downloaded() {
+        let images = ["image/".to_string()];
+        let image = attachment(10, "image/png");
+        let video = attachment(10, "video/mp4");
+        assert_eq!(download_skip_reason(&image, 100, &images), None);
+        assert_eq!(
+            download_skip_reason(&video, 100, &images).as_deref(),
+            Some("it's video/mp4")
+        );
+        // Everything is downloaded without an allow-list
+        assert_eq!(download_skip_reason(&video, 100, &[]), None);
+    }
+
+    #[test]
+    fn the_size_limit_applies_to_allowed_types() {
+        let images = ["image/".to_string()];
+        assert_eq!(
+            download_skip_reason(&attachment(1000, "image/png"), 100, &images).as_deref(),
+            Some("it's 1000 bytes")
+        );
+    }
+
+    /// Archive the same events with `write_strategy` into a fresh collection,
+    /// returning each message's id, archive type and iteration contents
+    async fn archive_with(
+        mong: &mongodb::Client,
+        write_strategy: WriteStrategy,
+    ) -> Vec<(String, String, Vec<String>)> {
+        let location = CollectionLocation {
+            database: "iswyd_test".to_string(),
+            collection: format!("messages_{}", uuid::Uuid::new_v4()),
+        };
+        let config = Config {
+            write_strategy,
+            write_batch_size: 10,
+            collections: HashMap::from([(mong::MESSAGES.to_string(), location.clone())]),
+            ..Config::default()
+        };
+        let bot = config.all_bots().remove(0);
+        let archiver = Archiver::new(
+            &config,
+            bot,
+            mong.clone(),
+            Session::new(None),
+            None,
+            None,
+            vec![],
+        );
+        // Known up front, so nothing is asked of Discord
+        archiver
+            .channel_types
+            .learn(ChannelId(20), ArchivedChannelType::Text);
+
+        let message = |id, content| {
+            ArchiveEvent::Message(Box::new(test_util::message(
+                id,
+                json!({ "content": content }),
+            )))
+        };
+        let edit = test_util::update(
+            1,
+            json!({
+                "content": "hello!",
+                "edited_timestamp": "2023-11-14T22:20:00.000000+00:00",
+            }),
+        );
+        let events = [
+            message(1, "hello"),
+            message(2, "world"),
+            ArchiveEvent::Update(Box::new(edit)),
+            message(3, "again"),
+            ArchiveEvent::Delete {
+                channel_id: ChannelId(20),
+                id: MessageId(2),
+                guild_id: Some(GuildId(30)),
+                timestamp: Utc::now(),
+            },
+            message(4, "last"),
+        ];
+        for event in events {
+            archiver.handle(event).await;
+        }
+        archiver.flush_batch().await;
+
+        let messages = archiver.mong_messages();
+        let stored: Vec<ArchivedMessage> = messages
+            .find(None, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        messages.drop(None).await.unwrap();
+        let mut stored: Vec<_> = stored
+            .into_iter()
+            .map(|message| {
+                let archive_type = to_stored_document(&message)
+                    .unwrap()
+                    .get_str("archive_type")
+                    .unwrap()
+                    .to_string();
+                let contents = match &message {
+                    ArchivedMessage::Full(m) => &m.iterations,
+                    ArchivedMessage::FullDeleted(m) => &m.iterations,
+                    _ => unreachable!(),
+                }
+                .iter()
+                .map(|iteration| iteration.content.clone())
+                .collect();
+                (message.id().to_string(), archive_type, contents)
+            })
+            .collect();
+        stored.sort();
+        stored
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn both_write_strategies_store_the_same() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = mong::get_mong(&connstring).await.unwrap();
+        let immediate = archive_with(&mong, WriteStrategy::Immediate).await;
+        let batched = archive_with(&mong, WriteStrategy::Batched).await;
+
+        let contents = |contents: &[&str]| contents.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            immediate,
+            [
+                (
+                    "1".to_string(),
+                    "Full".to_string(),
+                    contents(&["hello", "hello!"])
+                ),
+                (
+                    "2".to_string(),
+                    "FullDeleted".to_string(),
+                    contents(&["world"])
+                ),
+                ("3".to_string(), "Full".to_string(), contents(&["again"])),
+                ("4".to_string(), "Full".to_string(), contents(&["last"])),
+            ]
+        );
+        assert_eq!(batched, immediate);
+    }
+
+    #[tokio::test]
+    async fn panics_while_archiving_are_contained() {
+        let errored = AtomicU64::new(0);
+        contain_panic("message 1", &errored, false, async {
+            panic!("malformed event");
+        })
+        .await;
+        contain_panic("message 2", &errored, false, async {}).await;
+        assert_eq!(errored.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "malformed event")]
+    async fn panics_are_resumed_when_asked_to() {
+        let errored = AtomicU64::new(0);
+        contain_panic("message 1", &errored, true, async {
+            panic!("malformed event");
+        })
+        .await;
+    }
+
+    #[test]
+    fn panic_reasons_are_read_from_either_payload() {
+        let reason = |payload: Box<dyn Any + Send>| panic_reason(payload.as_ref()).to_string();
+        assert_eq!(reason(Box::new("static")), "static");
+        assert_eq!(reason(Box::new(String::from("formatted"))), "formatted");
+        assert_eq!(reason(Box::new(42)), "unknown cause");
+    }
+
+    #[test]
+    fn guild_outages_are_not_removals() {
+        let left_at = Utc::now();
+        let outage: UnavailableGuild =
+            serde_json::from_value(json!({ "id": "30", "unavailable": true })).unwrap();
+        assert!(guild_leave_event(&outage, left_at).is_none());
+
+        let removal: UnavailableGuild =
+            serde_json::from_value(json!({ "id": "30", "unavailable": false })).unwrap();
+        let Some(ArchiveEvent::GuildLeave {
+            guild_id,
+            timestamp,
+        }) = guild_leave_event(&removal, left_at)
+        else {
+            panic!("expected the removal to be a guild leave");
+        };
+        assert_eq!(guild_id, GuildId(30));
+        assert_eq!(timestamp, left_at);
+    }
+
+    #[test]
+    fn guild_removal_marks_the_messages_in_it() {
+        let session = Session::new(None);
+        let left_at = Utc::now();
+        let (filter, update) = guild_leave_update(GuildId(30), left_at);
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            test_util::message(1, json!({})),
+            &session,
+        ));
+        let ArchivedMessage::Full(marked) = apply_set(&message, &filter, &update) else {
+            unreachable!();
+        };
+        assert_eq!(
+            marked.archive_stopped_at.map(|at| at.timestamp_millis()),
+            Some(left_at.timestamp_millis())
+        );
+
+        let elsewhere = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            test_util::message(2, json!({ "guild_id": "31" })),
+            &session,
+        ));
+        let ArchivedMessage::Full(elsewhere) = apply_set(&elsewhere, &filter, &update) else {
+            unreachable!();
+        };
+        assert_eq!(elsewhere.archive_stopped_at, None);
+    }
+
+    #[test]
+    fn channel_deletion_marks_the_messages_in_it() {
+        let session = Session::new(None);
+        let deleted_at = Utc::now();
+        let (filter, update) = channel_delete_update(ChannelId(20), deleted_at);
+        let in_channel = [
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+                test_util::message(1, json!({})),
+                &session,
+            )),
+            deleted_full(&session),
+            deleted_incomplete(&session),
+        ];
+        for message in &in_channel {
+            let marked = apply_set(message, &filter, &update);
+            let channel_deleted_at = match marked {
+                ArchivedMessage::Full(m) => m.channel_deleted_at,
+                ArchivedMessage::FullDeleted(m) => m.channel_deleted_at,
+                ArchivedMessage::IncompleteDeleted(m) => m.channel_deleted_at,
+                _ => unreachable!(),
+            };
+            assert_eq!(
+                channel_deleted_at.map(|at| at.timestamp_millis()),
+                Some(deleted_at.timestamp_millis())
+            );
+        }
+
+        let elsewhere = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            test_util::message(2, json!({ "channel_id": "21" })),
+            &session,
+        ));
+        let ArchivedMessage::Full(elsewhere) = apply_set(&elsewhere, &filter, &update) else {
+            unreachable!()
+        };
+        assert_eq!(elsewhere.channel_deleted_at, None);
+    }
+
+    fn sent(content: &str) -> Vec<ArchivedMessageIteration> {
+        let message = test_util::message(1, json!({ "content": content }));
+        ArchivedMessageFull::from_gateway(message, &Session::new(None)).iterations
+    }
+
+    #[test]
+    fn content_updates_get_their_own_iteration() {
+        let session = Session::new(None);
+        for iteration_on_noncontent_changes in [false, true] {
+            let mut iterations = sent("original");
+            apply_update(
+                &mut iterations,
+                edit(),
+                Utc::now(),
+                &session,
+                iteration_on_noncontent_changes,
+            );
+            let contents: Vec<_> = iterations.iter().map(|i| i.content.as_str()).collect();
+            assert_eq!(contents, ["original", "edited"]);
+        }
+    }
+
+    #[test]
+    fn noncontent_updates_are_folded_into_the_latest_iteration() {
+        let session = Session::new(None);
+        let mut iterations = sent("https://example.com");
+        let unfurl = test_util::update(
+            1,
+            json!({ "embeds": [{ "type": "link", "title": "Example" }] }),
+        );
+        apply_update(&mut iterations, unfurl, Utc::now(), &session, false);
+        assert_eq!(iterations.len(), 1);
+        assert_eq!(iterations[0].content, "https://example.com");
+        assert_eq!(iterations[0].embeds.len(), 1);
+    }
+
+    #[test]
+    fn noncontent_updates_can_get_their_own_iteration() {
+        let session = Session::new(None);
+        let mut iterations = sent("https://example.com");
+        let unfurl = test_util::update(
+            1,
+            json!({ "embeds": [{ "type": "link", "title": "Example" }] }),
+        );
+        apply_update(&mut iterations, unfurl, Utc::now(), &session, true);
+        assert_eq!(iterations.len(), 2);
+        assert!(iterations[0].embeds.is_empty());
+        assert_eq!(iterations[1].embeds.len(), 1);
+    }
+
+    #[test]
+    fn latency_is_measured_from_the_event() {
+        let sent = Utc::now();
+        let stored = sent + chrono::Duration::milliseconds(1500);
+        assert_eq!(
+            processing_latency(sent, stored),
+            Some(Duration::from_millis(1500))
+        );
+        // Our clock is behind Discord's
+        assert_eq!(processing_latency(stored, sent), None);
+    }
+
+    #[test]
+    fn only_latencies_past_the_alarm_are_slow() {
+        let sent = Utc::now();
+        let stored = sent + chrono::Duration::seconds(5);
+        assert_eq!(
+            slow_processing(sent, stored, Duration::from_secs(2)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(slow_processing(sent, stored, Duration::from_secs(5)), None);
+        assert_eq!(slow_processing(sent, stored, Duration::from_secs(10)), None);
+        assert_eq!(slow_processing(stored, sent, Duration::from_secs(2)), None);
+        // Zero turns the alarm off
+        assert_eq!(slow_processing(sent, stored, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn only_messages_older_than_the_max_age_are_replayed() {
+        let max_age = Duration::from_secs(60);
+        let now = Utc::now();
+        let sent = |seconds| now - chrono::Duration::seconds(seconds);
+        assert!(is_replayed(sent(3600), now, max_age));
+        assert!(is_replayed(sent(61), now, max_age));
+        // Right at the limit is still live
+        assert!(!is_replayed(sent(60), now, max_age));
+        // Slightly delayed
+        assert!(!is_replayed(sent(5), now, max_age));
+        // Our clock is behind Discord's
+        assert!(!is_replayed(
+            now + chrono::Duration::seconds(5),
+            now,
+            max_age
+        ));
+        // Zero turns the check off
+        assert!(!is_replayed(sent(3600), now, Duration::ZERO));
+    }
 }
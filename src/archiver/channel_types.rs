@@ -0,0 +1,101 @@
+use serenity::model::id::ChannelId;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::archived_message::ArchivedChannelType;
+
+/// How long a channel whose type couldn't be fetched is treated as unknown
+/// before it's fetched again
+pub const FAILED_FETCH_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+enum Entry {
+    Known(ArchivedChannelType),
+    FailedAt(Instant),
+}
+
+/// Channel types seen on the gateway or fetched this session, so channels
+/// are only fetched when the gateway didn't describe them
+#[derive(Debug, Default)]
+pub struct ChannelTypes(Mutex<HashMap<ChannelId, Entry>>);
+
+impl ChannelTypes {
+    pub fn learn(&self, channel_id: ChannelId, channel_type: ArchivedChannelType) {
+        self.0
+            .lock()
+            .expect("channel types lock poisoned")
+            .insert(channel_id, Entry::Known(channel_type));
+    }
+
+    /// Remember that fetching a channel failed, so it isn't fetched for
+    /// every message until [`FAILED_FETCH_TTL`] passes
+    pub fn fetch_failed(&self, channel_id: ChannelId) {
+        self.0
+            .lock()
+            .expect("channel types lock poisoned")
+            .insert(channel_id, Entry::FailedAt(Instant::now()));
+    }
+
+    /// The type of a channel if it's known or failed to fetch recently,
+    /// `None` if it has to be fetched
+    pub fn get(&self, channel_id: ChannelId) -> Option<ArchivedChannelType> {
+        self.get_at(channel_id, Instant::now())
+    }
+
+    fn get_at(&self, channel_id: ChannelId, now: Instant) -> Option<ArchivedChannelType> {
+        match self
+            .0
+            .lock()
+            .expect("channel types lock poisoned")
+            .get(&channel_id)?
+        {
+            Entry::Known(channel_type) => Some(*channel_type),
+            Entry::FailedAt(at) if now.duration_since(*at) < FAILED_FETCH_TTL => {
+                Some(ArchivedChannelType::Unknown)
+            }
+            Entry::FailedAt(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId(20);
+
+    #[test]
+    fn unseen_channels_have_to_be_fetched() {
+        assert_eq!(ChannelTypes::default().get(CHANNEL), None);
+    }
+
+    #[test]
+    fn learned_types_are_kept() {
+        let types = ChannelTypes::default();
+        types.learn(CHANNEL, ArchivedChannelType::PublicThread);
+        assert_eq!(types.get(CHANNEL), Some(ArchivedChannelType::PublicThread));
+    }
+
+    #[test]
+    fn failed_fetches_are_retried_after_the_ttl() {
+        let types = ChannelTypes::default();
+        types.fetch_failed(CHANNEL);
+        let now = Instant::now();
+        assert_eq!(
+            types.get_at(CHANNEL, now),
+            Some(ArchivedChannelType::Unknown)
+        );
+        assert_eq!(types.get_at(CHANNEL, now + FAILED_FETCH_TTL), None);
+    }
+
+    #[test]
+    fn learning_a_type_replaces_a_failure() {
+        let types = ChannelTypes::default();
+        types.fetch_failed(CHANNEL);
+        types.learn(CHANNEL, ArchivedChannelType::Text);
+        assert_eq!(types.get(CHANNEL), Some(ArchivedChannelType::Text));
+    }
+}
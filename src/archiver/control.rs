@@ -0,0 +1,21 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::archiver::archiver::Archiver;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pause archiving while the control file exists and resume once it's gone
+pub async fn watch_control_file(path: PathBuf, archivers: Vec<Arc<Archiver>>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let paused = tokio::fs::metadata(&path).await.is_ok();
+        for archiver in &archivers {
+            if paused {
+                archiver.pause();
+            } else {
+                archiver.resume().await;
+            }
+        }
+    }
+}
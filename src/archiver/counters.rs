@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::session::SessionSummary;
+
+/// How many events an archiver handled, and how
+#[derive(Debug, Default)]
+pub struct EventCounters {
+    pub created: AtomicU64,
+    pub updated: AtomicU64,
+    pub deleted: AtomicU64,
+    /// Dropped on purpose, like while paused or because the deletion was
+    /// cancelled
+    pub skipped: AtomicU64,
+    pub errored: AtomicU64,
+    /// Iterations stored with `may_contain_gap` set
+    pub gap_flagged: AtomicU64,
+}
+
+impl EventCounters {
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add these counts to a summary covering several archivers
+    pub fn add_to(&self, summary: &mut SessionSummary) {
+        summary.created += self.created.load(Ordering::Relaxed);
+        summary.updated += self.updated.load(Ordering::Relaxed);
+        summary.deleted += self.deleted.load(Ordering::Relaxed);
+        summary.skipped += self.skipped.load(Ordering::Relaxed);
+        summary.errored += self.errored.load(Ordering::Relaxed);
+        summary.gap_flagged += self.gap_flagged.load(Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_adds_up_every_archiver() {
+        let first = EventCounters::default();
+        let second = EventCounters::default();
+        // A new message, its edit after a gap and a cancelled deletion
+        EventCounters::count(&first.created);
+        EventCounters::count(&first.updated);
+        EventCounters::count(&first.gap_flagged);
+        EventCounters::count(&first.skipped);
+        // Another bot sees a message deleted and fails to store a second one
+        EventCounters::count(&second.created);
+        EventCounters::count(&second.deleted);
+        EventCounters::count(&second.errored);
+
+        let mut summary = SessionSummary {
+            duration_secs: 90,
+            ..Default::default()
+        };
+        first.add_to(&mut summary);
+        second.add_to(&mut summary);
+
+        assert_eq!(
+            summary,
+            SessionSummary {
+                created: 2,
+                updated: 1,
+                deleted: 1,
+                skipped: 1,
+                errored: 1,
+                gap_flagged: 1,
+                duration_secs: 90,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "Session lasted 90s: 2 created, 1 updated, 1 deleted, 1 skipped, 1 errored, 1 gap-flagged iterations"
+        );
+    }
+}
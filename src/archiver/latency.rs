@@ -0,0 +1,85 @@
+use serenity::{client::bridge::gateway::ShardManager, prelude::Mutex};
+use std::{sync::Arc, time::Duration};
+
+/// Periodically log the gateway latency of every shard of a client
+pub async fn log_latencies(bot: usize, shard_manager: Arc<Mutex<ShardManager>>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let latencies: Vec<_> = {
+            let manager = shard_manager.lock().await;
+            let runners = manager.runners.lock().await;
+            runners
+                .iter()
+                .map(|(id, runner)| (id.0, runner.latency))
+                .collect()
+        };
+        println!("{}", format_latencies(bot, latencies));
+    }
+}
+
+/// Render shard latencies as a single log line, shard numbers are only shown
+/// when there is more than one shard
+pub fn format_latencies(bot: usize, mut latencies: Vec<(u64, Option<Duration>)>) -> String {
+    fn format_latency(latency: Option<Duration>) -> String {
+        match latency {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "unknown".to_string(),
+        }
+    }
+
+    latencies.sort_by_key(|(shard, _)| *shard);
+    let formatted = match latencies.as_slice() {
+        [] => "no shards running".to_string(),
+        [(_, latency)] => format_latency(*latency),
+        _ => latencies
+            .iter()
+            .map(|(shard, latency)| format!("shard {shard} {}", format_latency(*latency)))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    format!("Gateway latency of bot {bot}: {formatted}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_shard_leaves_out_the_number() {
+        let latencies = vec![(0, Some(Duration::from_millis(42)))];
+        assert_eq!(
+            format_latencies(0, latencies),
+            "Gateway latency of bot 0: 42ms"
+        );
+    }
+
+    #[test]
+    fn single_shard_without_a_heartbeat_is_unknown() {
+        assert_eq!(
+            format_latencies(1, vec![(0, None)]),
+            "Gateway latency of bot 1: unknown"
+        );
+    }
+
+    #[test]
+    fn multiple_shards_are_listed_in_order() {
+        let latencies = vec![
+            (2, None),
+            (0, Some(Duration::from_millis(42))),
+            (1, Some(Duration::from_millis(120))),
+        ];
+        assert_eq!(
+            format_latencies(0, latencies),
+            "Gateway latency of bot 0: shard 0 42ms, shard 1 120ms, shard 2 unknown"
+        );
+    }
+
+    #[test]
+    fn no_shards() {
+        assert_eq!(
+            format_latencies(0, vec![]),
+            "Gateway latency of bot 0: no shards running"
+        );
+    }
+}
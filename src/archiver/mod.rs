@@ -1,28 +1,309 @@
-use uuid::Uuid;
+use bson::doc;
+use chrono::Utc;
+use regex::RegexSet;
+use serenity::{client::ClientBuilder, http::Http};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinSet;
 
-use crate::{archiver::archiver::Archiver, config::Config, mong::get_mong, MainError};
+use crate::{
+    archiver::{alert::Alerts, archiver::Archiver},
+    backfill::{self, BackfillSettings},
+    circuit_breaker::CircuitBreaker,
+    compact,
+    config::{BotConfig, Config, ConfigLoadSaveError, WriteStrategy},
+    hook::ArchiveHook,
+    mong::{
+        create_cache_index, create_message_indexes, create_typing_events_ttl_index, get_mong,
+        messages_collection, roles_collection, sessions_collection, to_stored_bson,
+        CollectionLocation, ROLES, SESSIONS, TYPING_EVENTS,
+    },
+    publisher::{self, EventPublisher},
+    reconcile,
+    request_pacer::RequestPacer,
+    session::{Session, SessionSummary},
+    MainError,
+};
 
+mod alert;
 mod archiver;
+mod channel_types;
+mod control;
+mod counters;
+mod latency;
+mod pending_deletions;
 
 pub async fn run(config: Config) -> Result<(), MainError> {
+    run_with_hooks(config, vec![]).await
+}
+
+/// Archive like [`run`], passing every message document through `hooks`
+/// before it's written, see [`ArchiveHook`] for how they're run
+pub async fn run_with_hooks(
+    config: Config,
+    hooks: Vec<Arc<dyn ArchiveHook>>,
+) -> Result<(), MainError> {
     let mong = get_mong(&config.mong_connstring).await?;
+    let session = Session::new(config.session_label.clone());
+    let sessions = config.collection_location(SESSIONS);
+    if let Err(err) = sessions_collection(&mong, &sessions)
+        .insert_one(&session, None)
+        .await
+    {
+        println!(
+            "Failed to record session {} in mong: {err}",
+            session.session_id
+        );
+    }
+
+    if config.archive_typing_events {
+        let ttl = Duration::from_secs(config.typing_events_ttl_secs);
+        create_typing_events_ttl_index(&mong, &config.collection_location(TYPING_EVENTS), ttl)
+            .await?;
+    }
+
+    for bot in config.all_bots() {
+        let location = config.collection_location(&bot.collection);
+        create_message_indexes(&messages_collection(&mong, &location)).await?;
+    }
+
+    if config.archive_roles {
+        create_cache_index(&roles_collection(&mong, &config.collection_location(ROLES))).await?;
+    }
 
-    let handler = Archiver {
-        mong,
-        ignored_guilds: config.ignored_guilds,
-        ignored_channels: config.ignored_channels,
-        session_id: Uuid::new_v4(),
+    let alerts = if config.alert_patterns.is_empty() {
+        None
+    } else {
+        let patterns = RegexSet::new(&config.alert_patterns).map_err(ConfigLoadSaveError::from)?;
+        let webhook = match &config.alert_webhook_url {
+            Some(url) => Some(
+                Http::new(&config.discor_token)
+                    .get_webhook_from_url(url)
+                    .await?,
+            ),
+            None => None,
+        };
+        Some(Arc::new(Alerts::new(patterns, webhook)))
     };
 
-    let mut client = serenity::Client::builder(&config.discor_token)
-        .event_handler(handler)
-        .await?;
+    let publisher: Option<Arc<dyn EventPublisher>> = match &config.broker {
+        Some(broker) => Some(publisher::connect(broker).await?.into()),
+        None => None,
+    };
+
+    let mut archivers = Vec::new();
+    let mut clients = Vec::new();
+    for bot in config.all_bots() {
+        let builder = client_builder(&bot);
+        let handler = Arc::new(Archiver::new(
+            &config,
+            bot,
+            mong.clone(),
+            session.clone(),
+            publisher.clone(),
+            alerts.clone(),
+            hooks.clone(),
+        ));
+
+        let client = builder.event_handler_arc(handler.clone()).await?;
+        archivers.push(handler);
+        clients.push(client);
+    }
+
+    let shard_managers: Vec<_> = clients
+        .iter()
+        .map(|client| client.shard_manager.clone())
+        .collect();
+
+    // Dropped, and thereby stopped, together with the clients
+    let mut monitors = JoinSet::new();
+    if config.latency_log_interval_secs > 0 {
+        let every = Duration::from_secs(config.latency_log_interval_secs);
+        for (bot, shard_manager) in shard_managers.iter().enumerate() {
+            monitors.spawn(latency::log_latencies(bot, shard_manager.clone(), every));
+        }
+    }
+    if config.write_strategy == WriteStrategy::Batched {
+        let every = Duration::from_millis(config.write_batch_interval_ms);
+        monitors.spawn(flush_batches(archivers.clone(), every));
+    }
+    if config.infer_deletions_on_startup {
+        let window = Duration::from_secs(config.startup_deletion_window_secs);
+        for archiver in &archivers {
+            let archiver = archiver.clone();
+            let breaker = CircuitBreaker::new(
+                config.rest_breaker_threshold,
+                Duration::from_secs(config.rest_breaker_cooldown_secs),
+            );
+            let sample_size = config.reconcile_sample_size;
+            monitors.spawn(async move {
+                reconcile::infer_deletions_on_startup(
+                    &archiver.http,
+                    &archiver.mong_messages(),
+                    &breaker,
+                    window,
+                    sample_size,
+                )
+                .await
+            });
+        }
+    }
+    if config.online_compaction_interval_secs > 0 {
+        let window = Duration::from_secs(config.online_compaction_window_secs);
+        let every = Duration::from_secs(config.online_compaction_interval_secs);
+        for archiver in &archivers {
+            monitors.spawn(compact::compact_recent_iterations(
+                archiver.mong_messages(),
+                window,
+                every,
+            ));
+        }
+    }
+    if !config.backfill_channels.is_empty() {
+        // Into the main bot's collection, like backfill-channel mode
+        let archiver = archivers[0].clone();
+        let channels = config.backfill_channels.clone();
+        let settings = BackfillSettings::from(&config);
+        let pacer = RequestPacer::new(config.backfill_max_requests_per_sec);
+        monitors.spawn(async move {
+            backfill::backfill_channels(
+                &archiver.http,
+                &archiver.mong_messages(),
+                &channels,
+                settings,
+                &archiver.session,
+                &pacer,
+            )
+            .await
+        });
+    }
+    if let Some(control_file) = config.control_file {
+        monitors.spawn(control::watch_control_file(control_file, archivers.clone()));
+    }
+
+    println!("Starting {} client(s)", clients.len());
+
+    let mut tasks = JoinSet::new();
+    for mut client in clients {
+        tasks.spawn(async move { client.start().await });
+    }
 
-    println!("Starting client");
+    // Once any of the clients stops, take the rest down with it
+    tokio::select! {
+        result = tasks.join_next() => match result {
+            Some(Ok(Err(why))) => eprintln!("Client error: {why:?}"),
+            Some(Err(why)) => eprintln!("Client task failed: {why}"),
+            _ => {}
+        },
+        _ = tokio::signal::ctrl_c() => println!("Shutting down"),
+    }
+    for shard_manager in shard_managers {
+        shard_manager.lock().await.shutdown_all().await;
+    }
+    while tasks.join_next().await.is_some() {}
+    for archiver in &archivers {
+        archiver.flush_batch().await;
+    }
 
-    if let Err(why) = client.start().await {
-        eprintln!("Client error: {why:?}");
+    let mut summary = SessionSummary {
+        duration_secs: (Utc::now() - session.started_at).num_seconds().max(0) as u64,
+        ..Default::default()
+    };
+    for archiver in &archivers {
+        archiver.counters.add_to(&mut summary);
     }
+    println!("{summary}");
+    record_session_end(&mong, &sessions, &session, &summary).await;
 
     Ok(())
 }
+
+/// Periodically write the batches of new messages, so a quiet channel
+/// doesn't keep them waiting
+async fn flush_batches(archivers: Vec<Arc<Archiver>>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        for archiver in &archivers {
+            archiver.flush_batch().await;
+        }
+    }
+}
+
+/// Store the summary with the session, failing to is only logged since we're
+/// shutting down anyway
+async fn record_session_end(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+    session: &Session,
+    summary: &SessionSummary,
+) {
+    let (session_id, summary) = match (to_stored_bson(&session.session_id), to_stored_bson(summary))
+    {
+        (Ok(session_id), Ok(summary)) => (session_id, summary),
+        (Err(err), _) | (_, Err(err)) => {
+            println!("Failed to serialize the session summary: {err}");
+            return;
+        }
+    };
+    let update = doc! {
+        "$set": {
+            "ended_at": bson::DateTime::now(),
+            "summary": summary,
+        },
+    };
+    if let Err(err) = sessions_collection(mong, location)
+        .update_one(doc! { "session_id": session_id }, update, None)
+        .await
+    {
+        println!(
+            "Failed to record the end of session {}: {err}",
+            session.session_id
+        );
+    }
+}
+
+/// A client for one bot, connecting with its intents
+fn client_builder(bot: &BotConfig) -> ClientBuilder {
+    serenity::Client::builder(&bot.discor_token).intents(bot.gateway_intents())
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::gateway::GatewayIntents;
+
+    use super::*;
+
+    #[test]
+    fn builds_a_client_per_bot_with_its_intents() {
+        let config: Config = toml::from_str(
+            r#"
+            discor_token = "MTA.main.token"
+            mong_connstring = "mongodb://localhost:27017"
+            ignored_guilds = []
+            ignored_channels = []
+
+            [[bots]]
+            discor_token = "MTA.second.token"
+            collection = "second_messages"
+            intents = 37376
+
+            [[bots]]
+            discor_token = "MTA.third.token"
+            guild_whitelist = ["1000000000000000000"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let builders: Vec<_> = config.all_bots().iter().map(client_builder).collect();
+        assert_eq!(builders.len(), 3);
+        assert_eq!(builders[0].get_intents(), GatewayIntents::all());
+        assert_eq!(
+            builders[1].get_intents(),
+            GatewayIntents::GUILD_MESSAGES
+                | GatewayIntents::DIRECT_MESSAGES
+                | GatewayIntents::MESSAGE_CONTENT
+        );
+        assert_eq!(builders[2].get_intents(), GatewayIntents::all());
+    }
+}
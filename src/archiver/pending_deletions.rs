@@ -0,0 +1,74 @@
+use serenity::model::id::MessageId;
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+/// Deletions waiting out the grace period, a newer event about a message
+/// cancels its deletion
+#[derive(Debug, Default)]
+pub struct PendingDeletions(Mutex<HashSet<MessageId>>);
+
+impl PendingDeletions {
+    /// Cancel the pending deletion of a message, returning whether there was
+    /// one
+    pub fn cancel(&self, id: MessageId) -> bool {
+        self.0
+            .lock()
+            .expect("pending deletions lock poisoned")
+            .remove(&id)
+    }
+
+    /// Hold deletions for `grace`, returning the ones that weren't cancelled
+    /// meanwhile
+    pub async fn wait_out(&self, ids: Vec<MessageId>, grace: Duration) -> Vec<MessageId> {
+        if grace.is_zero() {
+            return ids;
+        }
+        self.0
+            .lock()
+            .expect("pending deletions lock poisoned")
+            .extend(ids.iter().copied());
+        tokio::time::sleep(grace).await;
+        let mut pending = self.0.lock().expect("pending deletions lock poisoned");
+        ids.into_iter().filter(|id| pending.remove(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const GRACE: Duration = Duration::from_millis(200);
+
+    #[tokio::test]
+    async fn deletion_goes_through_after_grace() {
+        let pending = PendingDeletions::default();
+        let kept = pending.wait_out(vec![MessageId(1)], GRACE).await;
+        assert_eq!(kept, vec![MessageId(1)]);
+        // Nothing is left behind to cancel
+        assert!(!pending.cancel(MessageId(1)));
+    }
+
+    #[tokio::test]
+    async fn deletion_cancelled_within_grace() {
+        let pending = Arc::new(PendingDeletions::default());
+        let waiting = tokio::spawn({
+            let pending = pending.clone();
+            async move {
+                pending
+                    .wait_out(vec![MessageId(1), MessageId(2)], GRACE)
+                    .await
+            }
+        });
+        tokio::time::sleep(GRACE / 4).await;
+        assert!(pending.cancel(MessageId(1)));
+        assert_eq!(waiting.await.unwrap(), vec![MessageId(2)]);
+    }
+
+    #[tokio::test]
+    async fn zero_grace_keeps_everything() {
+        let pending = PendingDeletions::default();
+        let kept = pending.wait_out(vec![MessageId(1)], Duration::ZERO).await;
+        assert_eq!(kept, vec![MessageId(1)]);
+        assert!(!pending.cancel(MessageId(1)));
+    }
+}
@@ -0,0 +1,60 @@
+use bson::{doc, oid::ObjectId};
+use mongodb::{gridfs::GridFsBucket, options::GridFsUploadOptions};
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    channel::Attachment,
+    id::{AttachmentId, MessageId},
+};
+use thiserror::Error;
+
+use crate::util::safe_attachment_file_name;
+
+/// Where the bytes of an attachment were stored
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoredAttachment {
+    pub attachment_id: AttachmentId,
+    /// The GridFS file holding the content
+    pub file_id: ObjectId,
+}
+
+#[derive(Debug, Error)]
+pub enum AttachmentDownloadError {
+    #[error("failed to download: {0}")]
+    Discord(#[from] serenity::Error),
+
+    #[error("failed to store in GridFS: {0}")]
+    Mong(#[from] mongodb::error::Error),
+}
+
+/// Download an attachment from Discord's CDN into GridFS, named after the
+/// message and its position in it
+///
+/// The file's metadata links it back to the message and keeps the name it
+/// was uploaded with, since the stored name is sanitized.
+pub async fn store_attachment(
+    bucket: &GridFsBucket,
+    message_id: MessageId,
+    index: usize,
+    attachment: &Attachment,
+) -> Result<StoredAttachment, AttachmentDownloadError> {
+    let content = attachment.download().await?;
+    let options = GridFsUploadOptions::builder()
+        .metadata(doc! {
+            "message_id": message_id.to_string(),
+            "attachment_id": attachment.id.to_string(),
+            "filename": &attachment.filename,
+            "content_type": attachment.content_type.as_deref(),
+        })
+        .build();
+    let file_id = bucket
+        .upload_from_futures_0_3_reader(
+            safe_attachment_file_name(message_id, index, &attachment.filename),
+            futures::io::Cursor::new(content),
+            options,
+        )
+        .await?;
+    Ok(StoredAttachment {
+        attachment_id: attachment.id,
+        file_id,
+    })
+}
@@ -0,0 +1,546 @@
+use bson::doc;
+use chrono::{DateTime, Utc};
+use futures::{Future, StreamExt};
+use mongodb::{
+    error::ErrorKind,
+    options::{FindOptions, InsertManyOptions},
+};
+use serde::Deserialize;
+use serenity::{
+    http::{routing::Route, Http},
+    model::{
+        channel::{Channel, Message},
+        id::{ChannelId, GuildId, MessageId},
+    },
+};
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    archived_message::{convert_ts, ArchivedChannelType, ArchivedMessage, ArchivedMessageFull},
+    config::Config,
+    mong::{get_mong, messages_collection, MESSAGES},
+    request_pacer::RequestPacer,
+    session::Session,
+    MainError,
+};
+
+/// Discord doesn't return more messages per request
+const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize)]
+struct ArchivedId {
+    id: MessageId,
+}
+
+/// How backfilling is configured
+#[derive(Clone, Copy, Debug)]
+pub struct BackfillSettings {
+    pub page_size: u64,
+    /// How many channels are backfilled at once
+    pub concurrency: usize,
+    /// See [`Config::backfill_slowdown_below_percent`]
+    pub slowdown_below_percent: u64,
+}
+
+impl From<&Config> for BackfillSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            page_size: config.backfill_page_size.clamp(1, MAX_PAGE_SIZE),
+            concurrency: config.backfill_channel_concurrency,
+            slowdown_below_percent: config.backfill_slowdown_below_percent,
+        }
+    }
+}
+
+/// The rate limit of a route as of its last response, from the
+/// `X-RateLimit` headers serenity keeps track of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitState {
+    pub limit: i64,
+    pub remaining: i64,
+    pub resets_in: Duration,
+}
+
+/// What the archive holds of a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub struct ArchivedSpan {
+    pub count: i64,
+    pub oldest: bson::DateTime,
+    pub newest: bson::DateTime,
+}
+
+/// How much a backfill of a channel is expected to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackfillEstimate {
+    /// Messages that aren't archived yet
+    pub missing: u64,
+    pub requests: u64,
+}
+
+/// Archive the history of a channel the archiver missed, from before we
+/// started listening or while we weren't running, see [`backfill_channel`]
+///
+/// A dry run only estimates how much that is, without fetching any messages.
+pub async fn run(config: Config, channel_id: ChannelId, dry_run: bool) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let messages = messages_collection(&mong, &config.collection_location(MESSAGES));
+    let http = Http::new(&config.discor_token);
+    let session = Session::new(config.session_label.clone());
+    let settings = BackfillSettings::from(&config);
+    let page_size = settings.page_size;
+
+    if dry_run {
+        let latest = match http.get_channel(channel_id.0).await? {
+            Channel::Guild(channel) => channel.last_message_id,
+            Channel::Private(channel) => channel.last_message_id,
+            _ => None,
+        };
+        let span = archived_span(&messages, channel_id).await?;
+        let estimate = estimate_backfill(
+            span,
+            convert_ts(channel_id.created_at()),
+            latest.map(|id| convert_ts(id.created_at())),
+            page_size,
+        );
+        match estimate {
+            Some(BackfillEstimate { missing, requests }) => println!(
+                "Backfilling channel {channel_id} would archive about {missing} messages in \
+                 about {requests} requests of {page_size} messages"
+            ),
+            None => println!(
+                "Too little of channel {channel_id} is archived to estimate its backfill, it \
+                 takes a request per {page_size} messages in the channel"
+            ),
+        }
+        return Ok(());
+    }
+
+    let pacer = RequestPacer::new(config.backfill_max_requests_per_sec);
+    let count = backfill_channel(&http, &messages, channel_id, settings, &session, &pacer).await?;
+    println!("Backfilled {count} messages of channel {channel_id}");
+    Ok(())
+}
+
+/// Backfill `channels`, several at a time, logging how each went
+///
+/// Every channel has its own rate limit bucket, which serenity waits on and
+/// [`backfill_channel`] slows down for, while `pacer` caps the requests of
+/// all of them together. Each page is
+/// written with a single unordered insert, so the channels don't hold each
+/// other up in Mongo.
+pub async fn backfill_channels(
+    http: &Http,
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channels: &[ChannelId],
+    settings: BackfillSettings,
+    session: &Session,
+    pacer: &RequestPacer,
+) {
+    for_each_bounded(
+        channels.iter().copied(),
+        settings.concurrency,
+        |channel_id| async move {
+            match backfill_channel(http, messages, channel_id, settings, session, pacer).await {
+                Ok(count) => println!("Backfilled {count} messages of channel {channel_id}"),
+                Err(err) => println!("Failed to backfill channel {channel_id}: {err}"),
+            }
+        },
+    )
+    .await;
+}
+
+/// Run `f` on every item, at most `limit` at a time and at least one
+async fn for_each_bounded<T, F, Fut>(items: impl IntoIterator<Item = T>, limit: usize, f: F)
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    futures::stream::iter(items)
+        .for_each_concurrent(limit.max(1), f)
+        .await;
+}
+
+/// Walk back from the latest message of a channel to its beginning,
+/// inserting the messages that aren't archived yet, how many were inserted
+///
+/// Once the channel's rate limit runs low the pages are spread out
+/// until it resets, see [`slowdown`].
+pub async fn backfill_channel(
+    http: &Http,
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channel_id: ChannelId,
+    settings: BackfillSettings,
+    session: &Session,
+    pacer: &RequestPacer,
+) -> Result<u64, MainError> {
+    let page_size = settings.page_size;
+    pacer.wait().await;
+    let channel = http.get_channel(channel_id.0).await?;
+    let channel_type = ArchivedChannelType::from(&channel);
+    // Messages fetched over REST don't say which guild they're from
+    let guild_id = channel.guild().map(|channel| channel.guild_id);
+
+    let mut before: Option<MessageId> = None;
+    let mut count = 0u64;
+    loop {
+        pacer.wait().await;
+        let page = channel_id
+            .messages(http, |retriever| match before {
+                Some(before) => retriever.before(before).limit(page_size),
+                None => retriever.limit(page_size),
+            })
+            .await?;
+        // Newest first
+        let Some(oldest) = page.last() else {
+            break;
+        };
+        before = Some(oldest.id);
+        let reached_end = (page.len() as u64) < page_size;
+        if let Some(state) = rate_limit_state(http, channel_id).await {
+            let wait = slowdown(state, settings.slowdown_below_percent);
+            if !reached_end && !wait.is_zero() {
+                println!(
+                    "{} of {} requests left for channel {channel_id} for {}ms, waiting {}ms",
+                    state.remaining,
+                    state.limit,
+                    state.resets_in.as_millis(),
+                    wait.as_millis()
+                );
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let filter = doc! {
+            "id": { "$in": page.iter().map(|m| m.id.to_string()).collect::<Vec<_>>() },
+        };
+        let options = FindOptions::builder().projection(doc! { "id": 1 }).build();
+        let mut cursor = messages
+            .find(filter, options)
+            .await?
+            .with_type::<ArchivedId>();
+        let mut archived = HashSet::new();
+        while cursor.advance().await? {
+            archived.insert(cursor.deserialize_current()?.id);
+        }
+
+        let new = unarchived(page, &archived, channel_type, guild_id, session);
+        if !new.is_empty() {
+            let fetched = new.len() as u64;
+            // The archiver may insert some of them meanwhile
+            let options = InsertManyOptions::builder().ordered(false).build();
+            count += match messages.insert_many(new, options).await {
+                Ok(result) => result.inserted_ids.len() as u64,
+                Err(err) => match err.kind.as_ref() {
+                    ErrorKind::BulkWrite(failure) => {
+                        let rejected = failure.write_errors.as_ref().map_or(0, Vec::len) as u64;
+                        println!("{rejected} messages were archived meanwhile");
+                        fetched.saturating_sub(rejected)
+                    }
+                    _ => return Err(err.into()),
+                },
+            };
+            println!("Backfilled {count} messages of channel {channel_id} so far");
+        }
+        if reached_end {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+/// The messages of a page that aren't in `archived`, ready to be inserted
+///
+/// Since their edits weren't seen, they're flagged as possibly missing
+/// history.
+fn unarchived(
+    page: Vec<Message>,
+    archived: &HashSet<MessageId>,
+    channel_type: ArchivedChannelType,
+    guild_id: Option<GuildId>,
+    session: &Session,
+) -> Vec<ArchivedMessage> {
+    page.into_iter()
+        .filter(|message| !archived.contains(&message.id))
+        .map(|message| {
+            let mut archived = ArchivedMessageFull::from_gateway(message, session);
+            archived.channel_type = channel_type;
+            archived.guild_id = guild_id;
+            // We don't know what happened to it before we fetched it
+            for iteration in &mut archived.iterations {
+                iteration.may_contain_gap = true;
+            }
+            ArchivedMessage::Full(archived)
+        })
+        .collect()
+}
+
+/// Where the rate limit of fetching a channel's messages stands, `None`
+/// before the first response
+async fn rate_limit_state(http: &Http, channel_id: ChannelId) -> Option<RateLimitState> {
+    let routes = http.ratelimiter.routes();
+    let routes = routes.read().await;
+    let bucket = routes.get(&Route::ChannelsIdMessages(channel_id.0))?;
+    let bucket = bucket.lock().await;
+    Some(RateLimitState {
+        limit: bucket.limit(),
+        remaining: bucket.remaining(),
+        resets_in: bucket
+            .reset()
+            .and_then(|reset| reset.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default(),
+    })
+}
+
+/// How long to wait before the next request to keep from running out of
+/// requests, nothing while at least `below_percent` of the limit is left
+///
+/// Below that the remaining requests are spread evenly until the limit
+/// resets, so a long backfill slows down gradually instead of stopping
+/// once the limit is used up.
+pub fn slowdown(state: RateLimitState, below_percent: u64) -> Duration {
+    let RateLimitState {
+        limit,
+        remaining,
+        resets_in,
+    } = state;
+    // Serenity starts out with an unlimited bucket until a response says
+    if below_percent == 0 || limit <= 0 || limit == i64::MAX {
+        return Duration::ZERO;
+    }
+    if remaining as i128 * 100 >= limit as i128 * below_percent as i128 {
+        return Duration::ZERO;
+    }
+    match u32::try_from(remaining) {
+        Ok(remaining) if remaining > 0 => resets_in / remaining,
+        _ => resets_in,
+    }
+}
+
+/// How many messages of a channel are archived and when they were sent
+async fn archived_span(
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channel_id: ChannelId,
+) -> Result<Option<ArchivedSpan>, mongodb::error::Error> {
+    let pipeline = [
+        doc! { "$match": {
+            "channel_id": channel_id.to_string(),
+            "timestamp": { "$exists": true },
+        } },
+        // Sent times may still be milliseconds until migrate-timestamps ran
+        doc! { "$group": {
+            "_id": null,
+            "count": { "$sum": 1_i64 },
+            "oldest": { "$min": { "$toDate": "$timestamp" } },
+            "newest": { "$max": { "$toDate": "$timestamp" } },
+        } },
+    ];
+    let mut cursor = messages.aggregate(pipeline, None).await?;
+    if !cursor.advance().await? {
+        return Ok(None);
+    }
+    Ok(Some(bson::from_slice(cursor.current().as_bytes())?))
+}
+
+/// Guess how many messages backfilling a channel would add and how many
+/// requests it takes, from what's archived of it
+///
+/// Assumes the channel was as busy before and after the archived messages as
+/// in between them. Backfilling pages through the whole channel, so the
+/// archived messages count toward the requests too. `None` if there aren't
+/// two archived messages sent at different times to tell how busy it is.
+pub fn estimate_backfill(
+    span: Option<ArchivedSpan>,
+    created: DateTime<Utc>,
+    latest: Option<DateTime<Utc>>,
+    page_size: u64,
+) -> Option<BackfillEstimate> {
+    // An empty channel takes one request to find out
+    let Some(latest) = latest else {
+        return Some(BackfillEstimate {
+            missing: 0,
+            requests: 1,
+        });
+    };
+    let span = span?;
+    let (oldest, newest) = (span.oldest.to_chrono(), span.newest.to_chrono());
+    let archived_ms = (newest - oldest).num_milliseconds();
+    if span.count < 2 || archived_ms <= 0 {
+        return None;
+    }
+    let unarchived_ms =
+        (oldest - created).num_milliseconds().max(0) + (latest - newest).num_milliseconds().max(0);
+    let missing = (span.count as f64 * unarchived_ms as f64 / archived_ms as f64).round() as u64;
+    Some(BackfillEstimate {
+        missing,
+        // The last page is the one that isn't full
+        requests: (span.count as u64 + missing) / page_size + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_util;
+
+    fn state(remaining: i64, resets_in_secs: u64) -> RateLimitState {
+        RateLimitState {
+            limit: 10,
+            remaining,
+            resets_in: Duration::from_secs(resets_in_secs),
+        }
+    }
+
+    #[test]
+    fn no_slowdown_while_enough_requests_are_left() {
+        assert_eq!(slowdown(state(5, 4), 50), Duration::ZERO);
+        assert_eq!(slowdown(state(10, 4), 50), Duration::ZERO);
+    }
+
+    #[test]
+    fn spreads_the_remaining_requests_until_the_reset() {
+        assert_eq!(slowdown(state(4, 4), 50), Duration::from_secs(1));
+        assert_eq!(slowdown(state(1, 4), 50), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn waits_for_the_reset_once_used_up() {
+        assert_eq!(slowdown(state(0, 3), 50), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn slowing_down_can_be_turned_off() {
+        assert_eq!(slowdown(state(0, 3), 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn buckets_without_a_response_yet_dont_slow_down() {
+        let unknown = RateLimitState {
+            limit: i64::MAX,
+            remaining: i64::MAX,
+            resets_in: Duration::ZERO,
+        };
+        assert_eq!(slowdown(unknown, 100), Duration::ZERO);
+    }
+
+    #[test]
+    fn only_unarchived_messages_are_inserted_with_a_gap() {
+        let page: Vec<_> = [3, 2, 1]
+            .into_iter()
+            .map(|id| test_util::message(id, json!({ "guild_id": null })))
+            .collect();
+        let archived = HashSet::from([MessageId(2)]);
+        let new = unarchived(
+            page,
+            &archived,
+            ArchivedChannelType::Text,
+            Some(GuildId(30)),
+            &Session::new(None),
+        );
+
+        assert_eq!(
+            new.iter().map(ArchivedMessage::id).collect::<Vec<_>>(),
+            [MessageId(3), MessageId(1)]
+        );
+        for message in new {
+            let ArchivedMessage::Full(message) = message else {
+                panic!("expected a full message");
+            };
+            assert_eq!(message.channel_type, ArchivedChannelType::Text);
+            assert_eq!(message.guild_id, Some(GuildId(30)));
+            assert!(message.iterations.iter().all(|i| i.may_contain_gap));
+        }
+    }
+
+    /// The most items `for_each_bounded` worked on at once
+    async fn peak_concurrency(items: usize, limit: usize) -> usize {
+        let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        for_each_bounded(0..items, limit, |_| async {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            // Give the others a chance to start meanwhile
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+        .await;
+        peak.into_inner()
+    }
+
+    #[tokio::test]
+    async fn channels_are_backfilled_up_to_the_limit_at_once() {
+        assert_eq!(peak_concurrency(6, 2).await, 2);
+        assert_eq!(peak_concurrency(6, 4).await, 4);
+        assert_eq!(peak_concurrency(2, 4).await, 2);
+    }
+
+    #[tokio::test]
+    async fn a_zero_limit_backfills_one_at_a_time() {
+        assert_eq!(peak_concurrency(3, 0).await, 1);
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 11, 14, hour, 0, 0).unwrap()
+    }
+
+    fn span(count: i64, oldest: u32, newest: u32) -> Option<ArchivedSpan> {
+        Some(ArchivedSpan {
+            count,
+            oldest: bson::DateTime::from_chrono(at(oldest)),
+            newest: bson::DateTime::from_chrono(at(newest)),
+        })
+    }
+
+    #[test]
+    fn estimates_the_unarchived_hours_at_the_archived_rate() {
+        // 100 messages an hour archived from 10 to 12, the channel was
+        // created at 8 and last written to at 13
+        let estimate = estimate_backfill(span(200, 10, 12), at(8), Some(at(13)), 100);
+        assert_eq!(
+            estimate,
+            Some(BackfillEstimate {
+                missing: 300,
+                requests: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn a_fully_archived_channel_only_pages_through_it() {
+        let estimate = estimate_backfill(span(250, 8, 13), at(8), Some(at(13)), 100);
+        assert_eq!(
+            estimate,
+            Some(BackfillEstimate {
+                missing: 0,
+                requests: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn an_empty_channel_takes_one_request() {
+        assert_eq!(
+            estimate_backfill(None, at(8), None, 100),
+            Some(BackfillEstimate {
+                missing: 0,
+                requests: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn cant_estimate_without_an_archived_rate() {
+        assert_eq!(estimate_backfill(None, at(8), Some(at(13)), 100), None);
+        assert_eq!(
+            estimate_backfill(span(1, 10, 10), at(8), Some(at(13)), 100),
+            None
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use mongodb::Collection;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    archived_message::ArchivedMessage,
+    config::Config,
+    mong::{get_mong, messages_collection, MESSAGES},
+    MainError,
+};
+
+/// How many messages are inserted at once when restoring
+const RESTORE_BATCH_SIZE: usize = 1000;
+
+/// Stream the whole messages collection into a gzipped NDJSON file
+pub async fn backup(config: Config, path: &Path) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let messages = messages_collection(&mong, &config.collection_location(MESSAGES));
+    let count = backup_collection(&messages, path).await?;
+    println!("Backed up {count} messages to {}", path.display());
+    Ok(())
+}
+
+/// Load a backup made by [`backup`] into the messages collection, which is
+/// expected to be empty
+pub async fn restore(config: Config, path: &Path) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let messages = messages_collection(&mong, &config.collection_location(MESSAGES));
+    let count = restore_collection(&messages, path).await?;
+    println!("Restored {count} messages from {}", path.display());
+    Ok(())
+}
+
+/// Write every message of `messages` to `path`, returning how many
+async fn backup_collection(
+    messages: &Collection<ArchivedMessage>,
+    path: &Path,
+) -> Result<u64, MainError> {
+    let mut cursor = messages.find(None, None).await?;
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    let mut count = 0u64;
+    while cursor.advance().await? {
+        let message = cursor.deserialize_current()?;
+        serde_json::to_writer(&mut writer, &message)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.finish()?.flush()?;
+    Ok(count)
+}
+
+/// Insert every message in the backup at `path` into `messages`, returning
+/// how many
+async fn restore_collection(
+    messages: &Collection<ArchivedMessage>,
+    path: &Path,
+) -> Result<u64, MainError> {
+    let reader = BufReader::new(GzDecoder::new(BufReader::new(File::open(path)?)));
+    let mut batch = Vec::with_capacity(RESTORE_BATCH_SIZE);
+    let mut count = 0u64;
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        batch.push(serde_json::from_str::<ArchivedMessage>(&line)?);
+        if batch.len() == RESTORE_BATCH_SIZE {
+            count += batch.len() as u64;
+            messages.insert_many(batch.drain(..), None).await?;
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len() as u64;
+        messages.insert_many(batch, None).await?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use futures::TryStreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn restores_what_was_backed_up() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let database = mong.database("iswyd_test");
+        let seeded =
+            database.collection::<ArchivedMessage>(&format!("backup_{}", uuid::Uuid::new_v4()));
+        let restored =
+            database.collection::<ArchivedMessage>(&format!("restore_{}", uuid::Uuid::new_v4()));
+        let path = std::env::temp_dir().join(format!("iswyd-{}.ndjson.gz", uuid::Uuid::new_v4()));
+
+        let session = Session::new(None);
+        let full = |id, overrides| {
+            ArchivedMessageFull::from_gateway(test_util::message(id, overrides), &session)
+        };
+        let messages = vec![
+            ArchivedMessage::Full(full(1, json!({ "content": "hello" }))),
+            ArchivedMessage::Full(full(
+                2,
+                json!({ "attachments": [test_util::attachment(5)] }),
+            )),
+            ArchivedMessage::FullDeleted(full(3, json!({})).into_deleted(Some(Utc::now()))),
+        ];
+        seeded.insert_many(&messages, None).await.unwrap();
+
+        let backed_up = backup_collection(&seeded, &path).await;
+        let restored_count = restore_collection(&restored, &path).await;
+        let mut documents: Vec<_> = restored
+            .find(None, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        seeded.drop(None).await.unwrap();
+        restored.drop(None).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(backed_up.unwrap(), 3);
+        assert_eq!(restored_count.unwrap(), 3);
+        documents.sort_by_key(ArchivedMessage::id);
+        let as_json = |messages: &[ArchivedMessage]| serde_json::to_value(messages).unwrap();
+        assert_eq!(as_json(&documents), as_json(&messages));
+    }
+}
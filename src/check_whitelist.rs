@@ -0,0 +1,121 @@
+use serenity::{
+    http::{GuildPagination, Http},
+    model::id::GuildId,
+};
+
+use crate::{config::Config, MainError};
+
+/// Discord doesn't list more guilds per request
+const GUILDS_PER_PAGE: u64 = 200;
+
+/// How the guilds an account is in line up with its whitelist
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WhitelistCheck {
+    /// Whitelisted guilds the account is in
+    pub present: Vec<GuildId>,
+    /// Whitelisted guilds the account can't see, nothing is archived from
+    /// them
+    pub absent: Vec<GuildId>,
+    /// Guilds the account is in that aren't whitelisted, so they're ignored
+    pub not_whitelisted: Vec<GuildId>,
+}
+
+impl WhitelistCheck {
+    pub fn new(whitelist: &[GuildId], member_of: &[GuildId]) -> Self {
+        let mut check = Self::default();
+        for &guild_id in whitelist {
+            if member_of.contains(&guild_id) {
+                check.present.push(guild_id);
+            } else {
+                check.absent.push(guild_id);
+            }
+        }
+        check.not_whitelisted = member_of
+            .iter()
+            .filter(|guild_id| !whitelist.contains(guild_id))
+            .copied()
+            .collect();
+        check
+    }
+}
+
+/// Print which whitelisted guilds every bot is and isn't in, and which of
+/// its guilds it ignores because they aren't whitelisted
+///
+/// Bots without a whitelist archive every guild and are skipped. Fails if
+/// any whitelisted guild is absent, so it can gate a long run.
+pub async fn run(config: Config) -> Result<(), MainError> {
+    let mut absent = 0;
+    for (bot, bot_config) in config.all_bots().into_iter().enumerate() {
+        if bot_config.guild_whitelist.is_empty() {
+            println!("Bot {bot} has no guild whitelist, it archives every guild");
+            continue;
+        }
+        let http = Http::new(&bot_config.discor_token);
+        let member_of = fetch_guilds(&http).await?;
+        let check = WhitelistCheck::new(&bot_config.guild_whitelist, &member_of);
+        for guild_id in &check.present {
+            println!("Bot {bot} is in whitelisted guild {guild_id}");
+        }
+        for guild_id in &check.absent {
+            println!("Bot {bot} is not in whitelisted guild {guild_id}");
+        }
+        for guild_id in &check.not_whitelisted {
+            println!("Bot {bot} is in guild {guild_id}, which isn't whitelisted");
+        }
+        absent += check.absent.len();
+    }
+    if absent > 0 {
+        return Err(MainError::AbsentGuilds(absent));
+    }
+    Ok(())
+}
+
+/// Every guild the account is in
+async fn fetch_guilds(http: &Http) -> Result<Vec<GuildId>, MainError> {
+    let mut guilds = Vec::new();
+    loop {
+        let after = guilds.last().copied().map(GuildPagination::After);
+        let page = http
+            .get_guilds(after.as_ref(), Some(GUILDS_PER_PAGE))
+            .await?;
+        let full = page.len() as u64 == GUILDS_PER_PAGE;
+        guilds.extend(page.into_iter().map(|guild| guild.id));
+        if !full {
+            return Ok(guilds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_guilds_into_present_absent_and_not_whitelisted() {
+        let whitelist = [GuildId(1), GuildId(2), GuildId(3)];
+        let member_of = [GuildId(3), GuildId(4), GuildId(1)];
+        assert_eq!(
+            WhitelistCheck::new(&whitelist, &member_of),
+            WhitelistCheck {
+                present: vec![GuildId(1), GuildId(3)],
+                absent: vec![GuildId(2)],
+                not_whitelisted: vec![GuildId(4)],
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_is_absent_when_the_account_is_in_every_guild() {
+        let whitelist = [GuildId(1)];
+        let check = WhitelistCheck::new(&whitelist, &whitelist);
+        assert!(check.absent.is_empty());
+        assert!(check.not_whitelisted.is_empty());
+    }
+
+    #[test]
+    fn an_account_in_no_guilds_misses_the_whole_whitelist() {
+        let whitelist = [GuildId(1), GuildId(2)];
+        assert_eq!(WhitelistCheck::new(&whitelist, &[]).absent, whitelist);
+    }
+}
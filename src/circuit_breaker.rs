@@ -0,0 +1,154 @@
+use serenity::http::HttpError;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BreakerState {
+    /// Requests go through, counting consecutive failures
+    Closed { failures: u32 },
+    /// Requests are held back until the cooldown is over
+    Open { until: Instant },
+    /// The cooldown is over, the next request decides whether to close again
+    HalfOpen,
+}
+
+/// Stops REST work after too many failures in a row, so we don't keep
+/// hammering Discord while it's down or our token is revoked
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request may be made now
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        match *state {
+            BreakerState::Closed { .. } | BreakerState::HalfOpen => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                println!("REST cooldown over, trying again");
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+
+    /// Feed the outcome of a request back, only failures that point at an
+    /// outage count against the threshold
+    pub fn record<T>(&self, result: &serenity::Result<T>) {
+        self.record_outage(result.as_ref().err().is_some_and(is_outage));
+    }
+
+    fn record_outage(&self, failed: bool) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        *state = match (*state, failed) {
+            (_, false) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, true) if failures + 1 < self.threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (BreakerState::Open { until }, true) => BreakerState::Open { until },
+            (_, true) => {
+                println!(
+                    "Too many failed REST requests, pausing them for {}s",
+                    self.cooldown.as_secs()
+                );
+                BreakerState::Open {
+                    until: Instant::now() + self.cooldown,
+                }
+            }
+        };
+    }
+}
+
+/// Server errors, a bad token and requests that never got a response, as
+/// opposed to problems with a single request like a missing message
+fn is_outage(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => match err.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.is_server_error() || response.status_code.as_u16() == 401
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(breaker: &CircuitBreaker) -> BreakerState {
+        *breaker.state.lock().unwrap()
+    }
+
+    #[test]
+    fn opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_outage(true);
+        breaker.record_outage(true);
+        assert_eq!(state(&breaker), BreakerState::Closed { failures: 2 });
+        assert!(breaker.allow());
+        breaker.record_outage(true);
+        assert!(matches!(state(&breaker), BreakerState::Open { .. }));
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_outage(true);
+        breaker.record_outage(true);
+        breaker.record_outage(false);
+        assert_eq!(state(&breaker), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_outage(true);
+        assert!(matches!(state(&breaker), BreakerState::Open { .. }));
+        assert!(breaker.allow());
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn half_open_closes_on_success() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_outage(true);
+        breaker.allow();
+        breaker.record_outage(false);
+        assert_eq!(state(&breaker), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn half_open_reopens_on_failure() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        *breaker.state.lock().unwrap() = BreakerState::HalfOpen;
+        breaker.record_outage(true);
+        assert!(matches!(state(&breaker), BreakerState::Open { .. }));
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn failures_of_single_requests_dont_count() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.record::<()>(&Err(serenity::Error::Other("missing message")));
+        assert_eq!(state(&breaker), BreakerState::Closed { failures: 0 });
+    }
+}
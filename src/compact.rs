@@ -0,0 +1,277 @@
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serenity::model::id::MessageId;
+use std::time::Duration;
+
+use crate::{
+    archived_message::{ArchivedMessage, ArchivedMessageIteration},
+    config::Config,
+    mong::{get_mong, to_stored_bson, MESSAGES},
+    MainError,
+};
+
+/// The parts of `collStats` that compacting changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Bytes allocated on disk for the documents
+    pub storage_size: i64,
+    pub total_index_size: i64,
+}
+
+impl StorageStats {
+    fn from_coll_stats(stats: &Document) -> Self {
+        Self {
+            storage_size: number(stats, "storageSize"),
+            total_index_size: number(stats, "totalIndexSize"),
+        }
+    }
+
+    /// Bytes freed going from `self` to `after`, negative if it grew
+    pub fn reclaimed_by(&self, after: &StorageStats) -> i64 {
+        (self.storage_size + self.total_index_size) - (after.storage_size + after.total_index_size)
+    }
+}
+
+/// Mongo picks the smallest type that fits, so sizes come back as any number
+fn number(document: &Document, key: &str) -> i64 {
+    match document.get(key) {
+        Some(bson::Bson::Int32(n)) => *n as i64,
+        Some(bson::Bson::Int64(n)) => *n,
+        Some(bson::Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+/// Run `compact` on the messages collection so Mongo gives the space of
+/// removed documents back to the OS
+///
+/// Compacting blocks some operations on the collection while it runs, so it
+/// only happens when `confirmed`.
+pub async fn run(config: Config, confirmed: bool) -> Result<(), MainError> {
+    let location = config.collection_location(MESSAGES);
+    if !confirmed {
+        println!(
+            "Compacting {}.{} can block writes to it until it's done, pass --yes to go ahead",
+            location.database, location.collection
+        );
+        return Ok(());
+    }
+
+    let mong = get_mong(&config.mong_connstring).await?;
+    let database = mong.database(&location.database);
+    let coll_stats = doc! { "collStats": &location.collection };
+
+    let before =
+        StorageStats::from_coll_stats(&database.run_command(coll_stats.clone(), None).await?);
+    println!("Compacting {}.{}", location.database, location.collection);
+    database
+        .run_command(doc! { "compact": &location.collection }, None)
+        .await?;
+    let after = StorageStats::from_coll_stats(&database.run_command(coll_stats, None).await?);
+
+    println!(
+        "Storage went from {} to {} bytes, indexes from {} to {} bytes, reclaimed {} bytes",
+        before.storage_size,
+        after.storage_size,
+        before.total_index_size,
+        after.total_index_size,
+        before.reclaimed_by(&after)
+    );
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ArchivedIterations {
+    id: MessageId,
+    iterations: Vec<ArchivedMessageIteration>,
+}
+
+/// Every `every`, drop the iterations that repeat the one before them from
+/// messages with an iteration newer than `window`, while archiving goes on
+///
+/// A message that got another iteration while it was looked at is left for
+/// the next round.
+pub async fn compact_recent_iterations(
+    messages: mongodb::Collection<ArchivedMessage>,
+    window: Duration,
+    every: Duration,
+) {
+    let messages = messages.clone_with_type::<ArchivedIterations>();
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let since = Utc::now() - chrono::Duration::seconds(window.as_secs() as i64);
+        match compact_since(&messages, since.timestamp_millis()).await {
+            Ok(0) => {}
+            Ok(dropped) => println!("Dropped {dropped} repeated iterations"),
+            Err(err) => println!("Failed to compact recent iterations: {err}"),
+        }
+    }
+}
+
+async fn compact_since(
+    messages: &mongodb::Collection<ArchivedIterations>,
+    since_millis: i64,
+) -> Result<u64, mongodb::error::Error> {
+    let filter = doc! {
+        "iterations.1": { "$exists": true },
+        "iterations.timestamp": { "$gte": since_millis },
+    };
+    let options = FindOptions::builder()
+        .projection(doc! { "id": 1, "iterations": 1 })
+        .build();
+    let mut cursor = messages.find(filter, options).await?;
+    let mut dropped = 0;
+    while cursor.advance().await? {
+        let ArchivedIterations { id, mut iterations } = cursor.deserialize_current()?;
+        let before = iterations.len();
+        if drop_repeated_iterations(&mut iterations) == 0 {
+            continue;
+        }
+        let encoded = to_stored_bson(&iterations)?;
+        let result = messages
+            .update_one(
+                doc! { "id": id.to_string(), "iterations": { "$size": before as i64 } },
+                doc! { "$set": { "iterations": encoded } },
+                None,
+            )
+            .await?;
+        if result.modified_count > 0 {
+            dropped += (before - iterations.len()) as u64;
+        }
+    }
+    Ok(dropped)
+}
+
+/// Remove the iterations that look exactly like the one before them, how
+/// many were removed
+///
+/// Iterations flagged as possibly following a gap are kept even if they
+/// look the same, they record that we weren't watching.
+pub fn drop_repeated_iterations(iterations: &mut Vec<ArchivedMessageIteration>) -> usize {
+    let before = iterations.len();
+    let mut kept: Vec<ArchivedMessageIteration> = Vec::with_capacity(before);
+    for iteration in iterations.drain(..) {
+        match kept.last() {
+            Some(previous) if !iteration.may_contain_gap && same_state(previous, &iteration) => {}
+            _ => kept.push(iteration),
+        }
+    }
+    *iterations = kept;
+    before - iterations.len()
+}
+
+/// Whether two iterations show the message the same way, serenity's types
+/// can't be compared so they're compared as BSON
+fn same_state(a: &ArchivedMessageIteration, b: &ArchivedMessageIteration) -> bool {
+    fn shown(iteration: &ArchivedMessageIteration) -> Option<bson::Bson> {
+        bson::to_bson(&(
+            &iteration.content,
+            &iteration.attachments,
+            &iteration.embeds,
+            &iteration.components,
+            &iteration.sticker_items,
+            &iteration.voice_attachments,
+        ))
+        .ok()
+    }
+    a.content == b.content && shown(a).is_some_and(|a| Some(a) == shown(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    fn iteration(overrides: serde_json::Value) -> ArchivedMessageIteration {
+        let message = test_util::message(1, overrides);
+        ArchivedMessageFull::from_gateway(message, &Session::new(None))
+            .iterations
+            .remove(0)
+    }
+
+    #[test]
+    fn reclaimed_space_is_read_from_coll_stats() {
+        // collStats output trimmed to what's read, sizes of either width
+        let before = StorageStats::from_coll_stats(&doc! {
+            "ns": "discor.messages",
+            "storageSize": 5_000_000_000_i64,
+            "totalIndexSize": 40_000_i32,
+        });
+        let after = StorageStats::from_coll_stats(&doc! {
+            "ns": "discor.messages",
+            "storageSize": 3_000_000_000_i64,
+            "totalIndexSize": 30_000.0,
+        });
+        assert_eq!(
+            before,
+            StorageStats {
+                storage_size: 5_000_000_000,
+                total_index_size: 40_000,
+            }
+        );
+        assert_eq!(before.reclaimed_by(&after), 2_000_010_000);
+        assert_eq!(after.reclaimed_by(&before), -2_000_010_000);
+    }
+
+    #[test]
+    fn missing_sizes_count_as_zero() {
+        let stats = StorageStats::from_coll_stats(&doc! { "ok": 1.0 });
+        assert_eq!(
+            stats,
+            StorageStats {
+                storage_size: 0,
+                total_index_size: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn repeated_iterations_are_dropped() {
+        let first = iteration(json!({ "content": "hello" }));
+        let edited = iteration(json!({ "content": "hello!" }));
+        let mut iterations = vec![first.clone(), first, edited.clone(), edited];
+        assert_eq!(drop_repeated_iterations(&mut iterations), 2);
+        let contents: Vec<_> = iterations.iter().map(|i| i.content.as_str()).collect();
+        assert_eq!(contents, ["hello", "hello!"]);
+    }
+
+    #[test]
+    fn iterations_after_a_gap_are_kept() {
+        let first = iteration(json!({ "content": "hello" }));
+        let mut after_gap = first.clone();
+        after_gap.may_contain_gap = true;
+        let mut iterations = vec![first, after_gap];
+        assert_eq!(drop_repeated_iterations(&mut iterations), 0);
+        assert_eq!(iterations.len(), 2);
+    }
+
+    #[test]
+    fn auto_embeds_are_kept() {
+        let first = iteration(json!({ "content": "https://example.com" }));
+        let mut embedded = iteration(json!({
+            "content": "https://example.com",
+            "embeds": [{ "type": "link", "url": "https://example.com" }],
+        }));
+        embedded.auto_embed = true;
+        let mut iterations = vec![first, embedded];
+        assert_eq!(drop_repeated_iterations(&mut iterations), 0);
+        assert!(iterations[1].auto_embed);
+    }
+
+    #[test]
+    fn attachment_changes_are_kept() {
+        let first = iteration(json!({ "content": "look" }));
+        let attached = iteration(json!({
+            "content": "look",
+            "attachments": [test_util::attachment(5)],
+        }));
+        let mut iterations = vec![first, attached];
+        assert_eq!(drop_repeated_iterations(&mut iterations), 0);
+        assert_eq!(iterations[1].attachments.len(), 1);
+    }
+}
@@ -0,0 +1,243 @@
+use bson::doc;
+use mongodb::{options::AggregateOptions, Cursor};
+use serde::Deserialize;
+use std::cmp::Ordering;
+
+use crate::{
+    config::Config,
+    mong::{get_mong, messages_collection, MESSAGES},
+    MainError,
+};
+
+/// The parts of a stored message that are compared
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+struct ComparedMessage {
+    /// Kept as the stored string so both sides sort the same way
+    id: String,
+    archive_type: String,
+    iteration_count: i32,
+    latest_content: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counts {
+    same: u64,
+    only_here: u64,
+    only_there: u64,
+    different: u64,
+}
+
+/// Compare the messages collection with the one behind `other_connstring`,
+/// printing every message that's missing on either side or differs
+pub async fn run(config: Config, other_connstring: &str) -> Result<(), MainError> {
+    let location = config.collection_location(MESSAGES);
+    let here = get_mong(&config.mong_connstring).await?;
+    let there = get_mong(other_connstring).await?;
+    let counts = compare_collections(
+        &messages_collection(&here, &location),
+        &messages_collection(&there, &location),
+    )
+    .await?;
+
+    println!(
+        "{} identical, {} different, {} only in this archive, {} only in the other",
+        counts.same, counts.different, counts.only_here, counts.only_there
+    );
+    Ok(())
+}
+
+/// Both sides are streamed in id order, so only one message per side is held
+/// in memory
+async fn compare_collections<T>(
+    here: &mongodb::Collection<T>,
+    there: &mongodb::Collection<T>,
+) -> Result<Counts, mongodb::error::Error> {
+    let mut here = compared_messages(here).await?;
+    let mut there = compared_messages(there).await?;
+
+    let mut counts = Counts::default();
+    let mut left = next(&mut here).await?;
+    let mut right = next(&mut there).await?;
+    loop {
+        let order = match (&left, &right) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(r)) => l.id.cmp(&r.id),
+        };
+        match order {
+            Ordering::Less => {
+                let l = left.take().expect("compared above");
+                println!("Message {} is missing from the other archive", l.id);
+                counts.only_here += 1;
+                left = next(&mut here).await?;
+            }
+            Ordering::Greater => {
+                let r = right.take().expect("compared above");
+                println!("Message {} is missing from this archive", r.id);
+                counts.only_there += 1;
+                right = next(&mut there).await?;
+            }
+            Ordering::Equal => {
+                let (l, r) = (
+                    left.take().expect("compared above"),
+                    right.take().expect("compared above"),
+                );
+                match differences(&l, &r) {
+                    differences if differences.is_empty() => counts.same += 1,
+                    differences => {
+                        println!("Message {} differs: {}", l.id, differences.join(", "));
+                        counts.different += 1;
+                    }
+                }
+                left = next(&mut here).await?;
+                right = next(&mut there).await?;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+async fn compared_messages<T>(
+    messages: &mongodb::Collection<T>,
+) -> Result<Cursor<ComparedMessage>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$sort": { "id": 1 } },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "id": 1,
+                "archive_type": 1,
+                "iteration_count": { "$size": { "$ifNull": ["$iterations", []] } },
+                "latest_content": {
+                    "$ifNull": [{ "$arrayElemAt": ["$iterations.content", -1] }, null]
+                },
+            }
+        },
+    ];
+    // Sorting a whole collection can go over the in-memory limit
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    Ok(messages
+        .aggregate(pipeline, options)
+        .await?
+        .with_type::<ComparedMessage>())
+}
+
+async fn next(
+    cursor: &mut Cursor<ComparedMessage>,
+) -> Result<Option<ComparedMessage>, mongodb::error::Error> {
+    Ok(if cursor.advance().await? {
+        Some(cursor.deserialize_current()?)
+    } else {
+        None
+    })
+}
+
+/// What differs between two versions of the same message
+fn differences(here: &ComparedMessage, there: &ComparedMessage) -> Vec<String> {
+    let mut differences = Vec::new();
+    if here.archive_type != there.archive_type {
+        differences.push(format!(
+            "archive type {} vs {}",
+            here.archive_type, there.archive_type
+        ));
+    }
+    if here.iteration_count != there.iteration_count {
+        differences.push(format!(
+            "{} vs {} iterations",
+            here.iteration_count, there.iteration_count
+        ));
+    }
+    if here.latest_content != there.latest_content {
+        differences.push("latest content".to_string());
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+
+    use super::*;
+
+    fn compared(archive_type: &str, iteration_count: i32, content: &str) -> ComparedMessage {
+        ComparedMessage {
+            id: "1".to_string(),
+            archive_type: archive_type.to_string(),
+            iteration_count,
+            latest_content: Some(content.to_string()),
+        }
+    }
+
+    #[test]
+    fn lists_every_difference() {
+        let here = compared("Full", 1, "hello");
+        assert!(differences(&here, &here.clone()).is_empty());
+        assert_eq!(
+            differences(&here, &compared("FullDeleted", 2, "hello!")),
+            [
+                "archive type Full vs FullDeleted",
+                "1 vs 2 iterations",
+                "latest content"
+            ]
+        );
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn diffs_two_seeded_collections() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let database = mong.database("iswyd_test");
+        let here = database.collection::<Document>(&format!("here_{}", uuid::Uuid::new_v4()));
+        let there = database.collection::<Document>(&format!("there_{}", uuid::Uuid::new_v4()));
+
+        let message = |id: &str, archive_type: &str, contents: &[&str]| {
+            let iterations: Vec<_> = contents.iter().map(|c| doc! { "content": c }).collect();
+            doc! { "id": id, "archive_type": archive_type, "iterations": iterations }
+        };
+        let unknown = |id: &str| doc! { "id": id, "archive_type": "UnknownDeleted" };
+        here.insert_many(
+            [
+                message("1", "Full", &["hello"]),
+                message("2", "Full", &["a"]),
+                message("3", "Full", &["b"]),
+                message("4", "Full", &["only here"]),
+                unknown("6"),
+            ],
+            None,
+        )
+        .await
+        .unwrap();
+        there
+            .insert_many(
+                [
+                    message("1", "Full", &["hello"]),
+                    message("2", "FullDeleted", &["a"]),
+                    message("3", "Full", &["b", "b!"]),
+                    message("5", "Full", &["only there"]),
+                    unknown("6"),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let counts = compare_collections(&here, &there).await;
+        here.drop(None).await.unwrap();
+        there.drop(None).await.unwrap();
+
+        assert_eq!(
+            counts.unwrap(),
+            Counts {
+                same: 2,
+                only_here: 1,
+                only_there: 1,
+                different: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,97 @@
+/// A single line of a line-based diff
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Added(&'a str),
+    Removed(&'a str),
+}
+
+/// Diff two texts line by line using their longest common subsequence
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // lcs[i][j] is the length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    diff
+}
+
+/// Render a diff in the usual `+`/`-` format, optionally colored with ANSI
+/// escapes
+pub fn render_diff(diff: &[DiffLine], color: bool) -> String {
+    let mut rendered = String::new();
+    for line in diff {
+        let (prefix, text, escape) = match line {
+            DiffLine::Same(text) => (' ', text, None),
+            DiffLine::Added(text) => ('+', text, Some("\x1b[32m")),
+            DiffLine::Removed(text) => ('-', text, Some("\x1b[31m")),
+        };
+        match escape {
+            Some(escape) if color => {
+                rendered.push_str(&format!("{escape}{prefix} {text}\x1b[0m\n"))
+            }
+            _ => rendered.push_str(&format!("{prefix} {text}\n")),
+        }
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_lines_are_removed_and_added() {
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nB\nc\nd"),
+            [
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Added("B"),
+                DiffLine::Same("c"),
+                DiffLine::Added("d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_plain_and_colored() {
+        let diff = [
+            DiffLine::Same("a"),
+            DiffLine::Removed("b"),
+            DiffLine::Added("c"),
+        ];
+        assert_eq!(render_diff(&diff, false), "  a\n- b\n+ c\n");
+        assert_eq!(
+            render_diff(&diff, true),
+            "  a\n\x1b[31m- b\x1b[0m\n\x1b[32m+ c\x1b[0m\n"
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use bson::{doc, Document};
+use serenity::model::id::{ChannelId, GuildId};
+use std::{
+    io::{self, BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    archived_message::ArchivedMessage,
+    config::Config,
+    export_dce::dce_export,
+    export_schema::{MessageV1, SchemaVersion},
+    mong::{get_mong, messages_collection},
+    reply_tree::reply_order,
+    MainError,
+};
+
+/// Progress is logged after this many messages or this long, whichever
+/// comes first
+const PROGRESS_EVERY: u64 = 10_000;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What export mode writes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ExportFormat {
+    /// One message per line, see [`SchemaVersion`]
+    #[default]
+    Ndjson,
+    /// One channel as DiscordChatExporter's JSON, see [`crate::export_dce`]
+    DceJson,
+}
+
+/// Write archived messages to stdout as compact NDJSON, one message per
+/// line, optionally only those of a guild or channel
+///
+/// The collections of all bots are exported one after the other, a message
+/// archived by several bots is written once per bot.
+///
+/// Meant to be piped into `jq` and the like, so stdout gets nothing but the
+/// messages and progress and the summary go to stderr. Messages are written
+/// as stored unless a schema version is asked for, see [`SchemaVersion`].
+///
+/// In the DiscordChatExporter format a channel is exported as one JSON
+/// document instead.
+///
+/// With `nest_replies` replies come right after what they reply to, see
+/// [`reply_order`]. That needs every message in memory, and a message
+/// archived by several bots is then written once.
+pub async fn run(
+    config: Config,
+    guild_id: Option<GuildId>,
+    channel_id: Option<ChannelId>,
+    format: ExportFormat,
+    schema: Option<SchemaVersion>,
+    nest_replies: bool,
+) -> Result<(), MainError> {
+    if format == ExportFormat::DceJson {
+        if schema.is_some() {
+            return Err(MainError::InvalidArg {
+                arg: "--schema-version",
+                problem: "only applies to the ndjson format",
+            });
+        }
+        let channel_id = channel_id.ok_or(MainError::MissingArg("--channel-id"))?;
+        return run_dce(config, guild_id, channel_id, nest_replies).await;
+    }
+    let schema = schema.unwrap_or_default();
+    if nest_replies {
+        return run_nested(config, guild_id, channel_id, schema).await;
+    }
+
+    let mong = get_mong(&config.mong_connstring).await?;
+    let filter = export_filter(guild_id, channel_id);
+    let collections: Vec<_> = config
+        .message_locations()
+        .iter()
+        .map(|location| messages_collection(&mong, location))
+        .collect();
+
+    // Only for showing a percentage, so exporting goes on without it
+    let mut total = Some(0);
+    for messages in &collections {
+        match messages.count_documents(filter.clone(), None).await {
+            Ok(count) => total = total.map(|total| total + count),
+            Err(err) => {
+                eprintln!("Couldn't count the messages to export: {err}");
+                total = None;
+                break;
+            }
+        }
+    }
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut progress = Progress::new(total, Instant::now());
+    for messages in &collections {
+        let mut cursor = messages.find(filter.clone(), None).await?;
+        while cursor.advance().await? {
+            write_line(&mut stdout, &cursor.deserialize_current()?, schema, None)?;
+            if let Some(line) = progress.advance(Instant::now()) {
+                eprintln!("{line}");
+            }
+        }
+    }
+    stdout.flush()?;
+
+    eprintln!("{}", progress.describe(Instant::now()));
+    Ok(())
+}
+
+/// Write a channel to stdout as DiscordChatExporter's JSON
+///
+/// The document is built in memory, so this is meant for single channels
+/// rather than whole archives. A message archived by several bots is
+/// written once, as the first bot archived it.
+async fn run_dce(
+    config: Config,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    nest_replies: bool,
+) -> Result<(), MainError> {
+    let mut messages = fetch_in_sent_order(&config, guild_id, Some(channel_id)).await?;
+    if nest_replies {
+        messages = nested(messages)
+            .into_iter()
+            .map(|(message, _)| message)
+            .collect();
+    }
+
+    let export = dce_export(channel_id, &messages, chrono::Utc::now());
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    serde_json::to_writer_pretty(&mut stdout, &export)?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()?;
+
+    eprintln!(
+        "Exported {} messages of channel {channel_id}",
+        export.message_count
+    );
+    Ok(())
+}
+
+/// Write messages as NDJSON with replies nested under what they reply to
+async fn run_nested(
+    config: Config,
+    guild_id: Option<GuildId>,
+    channel_id: Option<ChannelId>,
+    schema: SchemaVersion,
+) -> Result<(), MainError> {
+    let messages = fetch_in_sent_order(&config, guild_id, channel_id).await?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut progress = Progress::new(Some(messages.len() as u64), Instant::now());
+    for (message, depth) in nested(messages) {
+        write_line(&mut stdout, &message, schema, Some(depth))?;
+        if let Some(line) = progress.advance(Instant::now()) {
+            eprintln!("{line}");
+        }
+    }
+    stdout.flush()?;
+
+    eprintln!("{}", progress.describe(Instant::now()));
+    Ok(())
+}
+
+/// Every message matching the filters across all bots, see [`in_sent_order`]
+async fn fetch_in_sent_order(
+    config: &Config,
+    guild_id: Option<GuildId>,
+    channel_id: Option<ChannelId>,
+) -> Result<Vec<ArchivedMessage>, MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let filter = export_filter(guild_id, channel_id);
+    let mut messages = Vec::new();
+    for location in config.message_locations() {
+        let mut cursor = messages_collection(&mong, &location)
+            .find(filter.clone(), None)
+            .await?;
+        while cursor.advance().await? {
+            messages.push(cursor.deserialize_current()?);
+        }
+    }
+    Ok(in_sent_order(messages))
+}
+
+/// Messages in sent order reordered into reply trees, each with how many
+/// replies deep it is
+fn nested(messages: Vec<ArchivedMessage>) -> Vec<(ArchivedMessage, usize)> {
+    let replies: Vec<_> = messages
+        .iter()
+        .map(|message| (message.id(), message.replied_to()))
+        .collect();
+    let mut messages: Vec<_> = messages.into_iter().map(Some).collect();
+    reply_order(&replies)
+        .into_iter()
+        .filter_map(|nested| Some((messages[nested.index].take()?, nested.depth)))
+        .collect()
+}
+
+/// Sort messages by id, which is the order they were sent in, keeping the
+/// first of duplicates
+fn in_sent_order(mut messages: Vec<ArchivedMessage>) -> Vec<ArchivedMessage> {
+    // Stable, so the first bot's copy stays first
+    messages.sort_by_key(ArchivedMessage::id);
+    messages.dedup_by_key(|message| message.id());
+    messages
+}
+
+/// How far an export got
+struct Progress {
+    /// How many messages there are to export, if they could be counted
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    reported: Instant,
+    reported_done: u64,
+}
+
+impl Progress {
+    fn new(total: Option<u64>, now: Instant) -> Self {
+        Self {
+            total,
+            done: 0,
+            started: now,
+            reported: now,
+            reported_done: 0,
+        }
+    }
+
+    /// Count an exported message, returning a line to log if it's time for
+    /// one
+    fn advance(&mut self, now: Instant) -> Option<String> {
+        self.done += 1;
+        if self.done - self.reported_done < PROGRESS_EVERY
+            && now.duration_since(self.reported) < PROGRESS_INTERVAL
+        {
+            return None;
+        }
+        self.reported = now;
+        self.reported_done = self.done;
+        Some(self.describe(now))
+    }
+
+    /// How many messages were exported, of how many, and how fast
+    fn describe(&self, now: Instant) -> String {
+        let secs = now.duration_since(self.started).as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.done as f64 / secs
+        } else {
+            0.0
+        };
+        let done = match self.total {
+            // Messages archived since counting can take it past 100%
+            Some(total) if total > 0 => format!(
+                "{} of {total} messages ({:.1}%)",
+                self.done,
+                self.done as f64 * 100.0 / total as f64
+            ),
+            Some(total) => format!("{} of {total} messages", self.done),
+            None => format!("{} messages", self.done),
+        };
+        format!("Exported {done} in {secs:.1}s, {rate:.0} messages/s")
+    }
+}
+
+/// Write a message as one line of compact JSON
+///
+/// Only the versioned schemas have room for the reply depth, stored
+/// documents are written as they are.
+fn write_line(
+    out: &mut impl Write,
+    message: &ArchivedMessage,
+    schema: SchemaVersion,
+    reply_depth: Option<usize>,
+) -> io::Result<()> {
+    match schema {
+        SchemaVersion::Stored => serde_json::to_writer(&mut *out, message)?,
+        SchemaVersion::V1 => {
+            let exported = MessageV1 {
+                reply_depth,
+                ..MessageV1::from(message)
+            };
+            serde_json::to_writer(&mut *out, &exported)?
+        }
+    }
+    out.write_all(b"\n")
+}
+
+fn export_filter(guild_id: Option<GuildId>, channel_id: Option<ChannelId>) -> Document {
+    let mut filter = doc! {};
+    if let Some(guild_id) = guild_id {
+        filter.insert("guild_id", guild_id.to_string());
+    }
+    if let Some(channel_id) = channel_id {
+        filter.insert("channel_id", channel_id.to_string());
+    }
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let session = Session::new(None);
+        let mut out = Vec::new();
+        for (id, content) in [
+            (1, "plain"),
+            (2, "two\nlines\r\n"),
+            (3, "emoji 💀 \"quoted\""),
+        ] {
+            let message = test_util::message(id, json!({ "content": content }));
+            let message =
+                ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session));
+            write_line(&mut out, &message, SchemaVersion::Stored, None).unwrap();
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with('\n'));
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, id) in lines.iter().zip(["1", "2", "3"]) {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["id"], id);
+            assert_eq!(value["archive_type"], "Full");
+        }
+    }
+
+    #[test]
+    fn writes_the_schema_asked_for() {
+        let message = test_util::message(1, json!({}));
+        let message = ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            message,
+            &Session::new(None),
+        ));
+        let mut out = Vec::new();
+        write_line(&mut out, &message, SchemaVersion::V1, None).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["schema_version"], 1);
+        assert_eq!(value["completeness"], "full");
+        assert_eq!(value.get("archive_type"), None);
+        assert_eq!(value.get("reply_depth"), None);
+    }
+
+    #[test]
+    fn nested_replies_carry_their_depth() {
+        let session = Session::new(None);
+        let messages = [
+            (1, json!({})),
+            (2, json!({})),
+            (
+                3,
+                json!({ "type": 19, "message_reference": { "message_id": "1", "channel_id": "20" } }),
+            ),
+        ]
+        .map(|(id, overrides)| {
+            let message = test_util::message(id, overrides);
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session))
+        });
+        let mut out = Vec::new();
+        for (message, depth) in nested(messages.into()) {
+            write_line(&mut out, &message, SchemaVersion::V1, Some(depth)).unwrap();
+        }
+        let lines: Vec<serde_json::Value> = out
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        let order: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["id"].as_str().unwrap(),
+                    line["reply_depth"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(order, [("1", 0), ("3", 1), ("2", 0)]);
+    }
+
+    #[test]
+    fn progress_shows_the_percentage_and_rate() {
+        let start = Instant::now();
+        let mut progress = Progress::new(Some(400), start);
+        for _ in 0..100 {
+            progress.advance(start);
+        }
+        assert_eq!(
+            progress.describe(start + Duration::from_secs(4)),
+            "Exported 100 of 400 messages (25.0%) in 4.0s, 25 messages/s"
+        );
+    }
+
+    #[test]
+    fn progress_without_a_total_only_counts() {
+        let start = Instant::now();
+        let mut progress = Progress::new(None, start);
+        progress.advance(start);
+        assert_eq!(
+            progress.describe(start),
+            "Exported 1 messages in 0.0s, 0 messages/s"
+        );
+    }
+
+    #[test]
+    fn progress_is_logged_every_so_many_messages_or_seconds() {
+        let start = Instant::now();
+        let mut progress = Progress::new(None, start);
+        let logged = (0..PROGRESS_EVERY)
+            .filter_map(|_| progress.advance(start))
+            .count();
+        assert_eq!(logged, 1);
+        assert_eq!(progress.advance(start), None);
+        assert!(progress.advance(start + PROGRESS_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn channels_are_exported_in_sent_order_once() {
+        let session = Session::new(None);
+        let [first, second, duplicate] = [(2, "first bot"), (1, "first bot"), (2, "second bot")]
+            .map(|(id, content)| {
+                let message = test_util::message(id, json!({ "content": content }));
+                ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session))
+            });
+        let messages = in_sent_order(vec![first, second, duplicate]);
+        let ids: Vec<_> = messages.iter().map(|message| message.id().0).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(messages[1].latest_iteration().unwrap().content, "first bot");
+    }
+
+    #[test]
+    fn filters_on_guild_and_channel() {
+        assert_eq!(export_filter(None, None), doc! {});
+        assert_eq!(
+            export_filter(Some(GuildId(30)), Some(ChannelId(20))),
+            doc! { "guild_id": "30", "channel_id": "20" }
+        );
+    }
+}
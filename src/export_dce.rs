@@ -0,0 +1,364 @@
+//! Exports in the JSON format of DiscordChatExporter, so archived channels
+//! can be opened in the viewers made for it
+//!
+//! Known limitations, since the archive doesn't have everything the format
+//! does:
+//!
+//! - Guilds, channels and users aren't archived by name, their ids are used as
+//!   names instead, and there are no avatars, nicknames, colors or roles
+//! - Reactions and mentions aren't archived, so they're always empty
+//! - Embeds and stickers are left out
+//! - Only the latest version of an edited message is exported, deleted messages
+//!   are exported as they were before the deletion and messages we only saw
+//!   being deleted are left out
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use serenity::model::id::{ChannelId, GuildId};
+
+use crate::archived_message::{ArchivedChannelType, ArchivedMessage, ArchivedMessageType};
+
+/// What DiscordChatExporter shows for direct messages, which have no guild
+const DIRECT_MESSAGES_GUILD_ID: &str = "0";
+const DIRECT_MESSAGES_GUILD_NAME: &str = "Direct Messages";
+const DEFAULT_AVATAR_URL: &str = "https://cdn.discordapp.com/embed/avatars/0.png";
+
+/// A channel as DiscordChatExporter exports it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceExport {
+    pub guild: DceGuild,
+    pub channel: DceChannel,
+    pub date_range: DceDateRange,
+    pub exported_at: String,
+    pub messages: Vec<DceMessage>,
+    pub message_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceGuild {
+    pub id: String,
+    pub name: String,
+    pub icon_url: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceChannel {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub category_id: Option<String>,
+    pub category: Option<String>,
+    pub name: String,
+    pub topic: Option<String>,
+}
+
+/// Every archived message is exported, so the range is always open
+#[derive(Debug, Default, Serialize)]
+pub struct DceDateRange {
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceMessage {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub timestamp: String,
+    pub timestamp_edited: Option<String>,
+    pub call_ended_timestamp: Option<String>,
+    pub is_pinned: bool,
+    pub content: String,
+    pub author: DceAuthor,
+    pub attachments: Vec<DceAttachment>,
+    pub embeds: Vec<serde_json::Value>,
+    pub stickers: Vec<serde_json::Value>,
+    pub reactions: Vec<serde_json::Value>,
+    pub mentions: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<DceReference>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceAuthor {
+    pub id: String,
+    pub name: String,
+    pub discriminator: String,
+    pub nickname: String,
+    pub color: Option<String>,
+    pub is_bot: bool,
+    pub roles: Vec<serde_json::Value>,
+    pub avatar_url: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceAttachment {
+    pub id: String,
+    pub url: String,
+    pub file_name: String,
+    pub file_size_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DceReference {
+    pub message_id: Option<String>,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+}
+
+/// Export the messages of a channel in the order given, usually the order
+/// they were sent in
+pub fn dce_export(
+    channel_id: ChannelId,
+    messages: &[ArchivedMessage],
+    exported_at: DateTime<Utc>,
+) -> DceExport {
+    let guild_id = messages.iter().find_map(ArchivedMessage::guild_id);
+    let channel_type = messages.iter().find_map(channel_type).unwrap_or_default();
+    let messages: Vec<_> = messages.iter().filter_map(dce_message).collect();
+    DceExport {
+        guild: dce_guild(guild_id),
+        channel: DceChannel {
+            id: channel_id.to_string(),
+            kind: dce_channel_type(channel_type),
+            category_id: None,
+            category: None,
+            name: channel_id.to_string(),
+            topic: None,
+        },
+        date_range: DceDateRange::default(),
+        exported_at: dce_timestamp(exported_at),
+        message_count: messages.len(),
+        messages,
+    }
+}
+
+fn dce_guild(guild_id: Option<GuildId>) -> DceGuild {
+    let (id, name) = match guild_id {
+        Some(guild_id) => (guild_id.to_string(), guild_id.to_string()),
+        None => (
+            DIRECT_MESSAGES_GUILD_ID.to_string(),
+            DIRECT_MESSAGES_GUILD_NAME.to_string(),
+        ),
+    };
+    DceGuild {
+        id,
+        name,
+        icon_url: DEFAULT_AVATAR_URL.to_string(),
+    }
+}
+
+/// A message as DiscordChatExporter exports it, `None` if we know nothing
+/// to show about it
+fn dce_message(message: &ArchivedMessage) -> Option<DceMessage> {
+    let (author_id, timestamp, kind, reference, pinned) = match message {
+        ArchivedMessage::Full(m) => (
+            Some(m.author_id),
+            m.timestamp,
+            m.kind,
+            m.message_reference.as_ref(),
+            m.pinned,
+        ),
+        ArchivedMessage::FullDeleted(m) => (
+            Some(m.author_id),
+            m.timestamp,
+            m.kind,
+            m.message_reference.as_ref(),
+            m.pinned,
+        ),
+        ArchivedMessage::Incomplete(m) => (
+            m.author_id,
+            m.timestamp,
+            ArchivedMessageType::Regular,
+            None,
+            false,
+        ),
+        ArchivedMessage::IncompleteDeleted(m) => (
+            m.author_id,
+            m.timestamp,
+            ArchivedMessageType::Regular,
+            None,
+            false,
+        ),
+        ArchivedMessage::UnknownDeleted(_) => return None,
+    };
+    let latest = message.latest_iteration()?;
+    let author_id = author_id.map_or_else(|| "0".to_string(), |id| id.to_string());
+    let reference = match kind {
+        ArchivedMessageType::InlineReply => reference.map(|reference| DceReference {
+            message_id: reference.message_id.map(|id| id.to_string()),
+            channel_id: reference.channel_id.to_string(),
+            guild_id: reference.guild_id.map(|id| id.to_string()),
+        }),
+        _ => None,
+    };
+    Some(DceMessage {
+        id: message.id().to_string(),
+        kind: dce_message_type(kind),
+        timestamp: dce_timestamp(timestamp),
+        timestamp_edited: message.edit_stats().last_edit_at.map(dce_timestamp),
+        call_ended_timestamp: None,
+        is_pinned: pinned,
+        content: latest.content.clone(),
+        author: DceAuthor {
+            id: author_id.clone(),
+            name: author_id.clone(),
+            discriminator: "0000".to_string(),
+            nickname: author_id,
+            color: None,
+            is_bot: false,
+            roles: vec![],
+            avatar_url: DEFAULT_AVATAR_URL.to_string(),
+        },
+        attachments: latest
+            .attachments
+            .iter()
+            .map(|attachment| DceAttachment {
+                id: attachment.id.to_string(),
+                url: attachment.url.clone(),
+                file_name: attachment.filename.clone(),
+                file_size_bytes: attachment.size,
+            })
+            .collect(),
+        embeds: vec![],
+        stickers: vec![],
+        reactions: vec![],
+        mentions: vec![],
+        reference,
+    })
+}
+
+fn channel_type(message: &ArchivedMessage) -> Option<ArchivedChannelType> {
+    let channel_type = match message {
+        ArchivedMessage::Full(m) => m.channel_type,
+        ArchivedMessage::FullDeleted(m) => m.channel_type,
+        ArchivedMessage::Incomplete(m) => m.channel_type,
+        ArchivedMessage::IncompleteDeleted(m) => m.channel_type,
+        ArchivedMessage::UnknownDeleted(_) => ArchivedChannelType::Unknown,
+    };
+    (channel_type != ArchivedChannelType::Unknown).then_some(channel_type)
+}
+
+fn dce_channel_type(channel_type: ArchivedChannelType) -> &'static str {
+    match channel_type {
+        ArchivedChannelType::Private => "DirectTextChat",
+        ArchivedChannelType::Voice => "GuildVoiceChat",
+        ArchivedChannelType::Category => "GuildCategory",
+        ArchivedChannelType::News => "GuildNews",
+        ArchivedChannelType::NewsThread => "GuildNewsThread",
+        ArchivedChannelType::PublicThread => "GuildPublicThread",
+        ArchivedChannelType::PrivateThread => "GuildPrivateThread",
+        ArchivedChannelType::Stage => "GuildStageVoice",
+        ArchivedChannelType::Text | ArchivedChannelType::Directory => "GuildTextChat",
+        ArchivedChannelType::Unknown => "GuildTextChat",
+    }
+}
+
+/// DiscordChatExporter only has types for some system messages, the rest
+/// are shown as regular ones
+fn dce_message_type(kind: ArchivedMessageType) -> &'static str {
+    match kind {
+        ArchivedMessageType::GroupRecipientAddition => "RecipientAdd",
+        ArchivedMessageType::GroupRecipientRemoval => "RecipientRemove",
+        ArchivedMessageType::GroupCallCreation => "Call",
+        ArchivedMessageType::GroupNameUpdate => "ChannelNameChange",
+        ArchivedMessageType::GroupIconUpdate => "ChannelIconChange",
+        ArchivedMessageType::PinsAdd => "ChannelPinnedMessage",
+        ArchivedMessageType::MemberJoin => "GuildMemberJoin",
+        ArchivedMessageType::ThreadCreated => "ThreadCreated",
+        ArchivedMessageType::InlineReply => "Reply",
+        _ => "Default",
+    }
+}
+
+fn dce_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp
+        .with_timezone(&chrono::FixedOffset::east_opt(0).expect("zero offset"))
+        .to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    fn archived(id: u64, overrides: serde_json::Value) -> ArchivedMessage {
+        let message = test_util::message(id, overrides);
+        let mut archived = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        archived.channel_type = ArchivedChannelType::Text;
+        ArchivedMessage::Full(archived)
+    }
+
+    #[test]
+    fn maps_a_reply_with_an_attachment() {
+        let messages = [
+            archived(1, json!({ "content": "hi" })),
+            archived(
+                2,
+                json!({
+                    "content": "look",
+                    "type": 19,
+                    "message_reference": { "message_id": "1", "channel_id": "20", "guild_id": "30" },
+                    "attachments": [test_util::attachment(5)],
+                }),
+            ),
+        ];
+        let exported_at = Utc::now();
+        let export = dce_export(ChannelId(20), &messages, exported_at);
+        let export = serde_json::to_value(export).unwrap();
+
+        assert_eq!(
+            export["guild"],
+            json!({ "id": "30", "name": "30", "iconUrl": DEFAULT_AVATAR_URL })
+        );
+        assert_eq!(export["channel"]["id"], "20");
+        assert_eq!(export["channel"]["type"], "GuildTextChat");
+        assert_eq!(
+            export["dateRange"],
+            json!({ "after": null, "before": null })
+        );
+        assert_eq!(export["messageCount"], 2);
+
+        let reply = &export["messages"][1];
+        assert_eq!(reply["id"], "2");
+        assert_eq!(reply["type"], "Reply");
+        assert_eq!(reply["timestamp"], "2023-11-14T22:13:20.000+00:00");
+        assert_eq!(reply["timestampEdited"], json!(null));
+        assert_eq!(reply["isPinned"], false);
+        assert_eq!(reply["content"], "look");
+        assert_eq!(reply["author"]["id"], "100");
+        assert_eq!(reply["author"]["isBot"], false);
+        assert_eq!(
+            reply["attachments"],
+            json!([{
+                "id": "5",
+                "url": "https://cdn.discordapp.com/cat.png",
+                "fileName": "cat.png",
+                "fileSizeBytes": 10,
+            }])
+        );
+        assert_eq!(reply["reactions"], json!([]));
+        assert_eq!(
+            reply["reference"],
+            json!({ "messageId": "1", "channelId": "20", "guildId": "30" })
+        );
+        assert_eq!(export["messages"][0].get("reference"), None);
+    }
+
+    #[test]
+    fn direct_messages_get_the_placeholder_guild() {
+        let messages = [archived(1, json!({ "guild_id": null }))];
+        let export = dce_export(ChannelId(20), &messages, Utc::now());
+        assert_eq!(export.guild.id, DIRECT_MESSAGES_GUILD_ID);
+        assert_eq!(export.guild.name, DIRECT_MESSAGES_GUILD_NAME);
+    }
+}
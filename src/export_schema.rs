@@ -0,0 +1,293 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serenity::model::channel::Attachment;
+
+use crate::archived_message::{ArchivedMessage, ArchivedMessageIteration};
+
+/// Which shape export mode writes messages in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SchemaVersion {
+    /// The documents as they're stored, which change along with the archiver
+    #[default]
+    Stored,
+    /// See [`MessageV1`]
+    V1,
+}
+
+/// A message in version 1 of the export schema
+///
+/// A version only ever gains fields, changing or removing one makes a new
+/// version, so consumers aren't affected by how messages are stored. Ids
+/// are strings and times RFC 3339 in UTC.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MessageV1 {
+    /// Always 1
+    pub schema_version: u32,
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    /// Unknown if we never saw the message being sent
+    pub author_id: Option<String>,
+    /// Unknown if we only saw the message being deleted
+    pub sent_at: Option<DateTime<Utc>>,
+    pub completeness: CompletenessV1,
+    /// The message this one replies to
+    pub reply_to: Option<String>,
+    /// How many replies deep the message is, only when exporting with
+    /// replies nested under what they reply to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_depth: Option<usize>,
+    pub deleted: bool,
+    /// Unknown for deleted messages whose deletion time we missed
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Oldest first, empty if we only saw the message being deleted
+    pub versions: Vec<VersionV1>,
+}
+
+/// How much of a message we saw
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletenessV1 {
+    /// We saw it being sent, or fetched it later
+    Full,
+    /// We only saw it being edited, so earlier versions are missing
+    EditsOnly,
+    /// We only saw it being deleted, nothing of it is known
+    DeletionOnly,
+}
+
+/// How a message looked at one point
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VersionV1 {
+    /// When the message was sent or edited to look like this
+    pub at: DateTime<Utc>,
+    pub content: String,
+    pub attachments: Vec<AttachmentV1>,
+    /// Whether the message may have looked different in between this and
+    /// the previous version without us seeing it
+    pub after_gap: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AttachmentV1 {
+    pub id: String,
+    pub file_name: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// Discord's link, which expires
+    pub url: String,
+}
+
+impl From<&ArchivedMessage> for MessageV1 {
+    fn from(message: &ArchivedMessage) -> Self {
+        let mut exported = Self {
+            schema_version: 1,
+            id: message.id().to_string(),
+            channel_id: message.channel_id().to_string(),
+            guild_id: message.guild_id().map(|id| id.to_string()),
+            author_id: None,
+            sent_at: None,
+            completeness: CompletenessV1::Full,
+            reply_to: message.replied_to().map(|id| id.to_string()),
+            reply_depth: None,
+            deleted: false,
+            deleted_at: None,
+            versions: vec![],
+        };
+        let iterations = match message {
+            ArchivedMessage::Full(m) => {
+                exported.author_id = Some(m.author_id.to_string());
+                exported.sent_at = Some(m.timestamp);
+                &m.iterations
+            }
+            ArchivedMessage::FullDeleted(m) => {
+                exported.author_id = Some(m.author_id.to_string());
+                exported.sent_at = Some(m.timestamp);
+                exported.deleted = true;
+                exported.deleted_at = m.deleted_timestamp;
+                &m.iterations
+            }
+            ArchivedMessage::Incomplete(m) => {
+                exported.author_id = m.author_id.map(|id| id.to_string());
+                exported.sent_at = Some(m.timestamp);
+                exported.completeness = CompletenessV1::EditsOnly;
+                &m.iterations
+            }
+            ArchivedMessage::IncompleteDeleted(m) => {
+                exported.author_id = m.author_id.map(|id| id.to_string());
+                exported.sent_at = Some(m.timestamp);
+                exported.completeness = CompletenessV1::EditsOnly;
+                exported.deleted = true;
+                exported.deleted_at = m.deleted_timestamp;
+                &m.iterations
+            }
+            ArchivedMessage::UnknownDeleted(m) => {
+                exported.completeness = CompletenessV1::DeletionOnly;
+                exported.deleted = true;
+                exported.deleted_at = m.deleted_timestamp;
+                return exported;
+            }
+        };
+        exported.versions = iterations.iter().map(VersionV1::from).collect();
+        exported
+    }
+}
+
+impl From<&ArchivedMessageIteration> for VersionV1 {
+    fn from(iteration: &ArchivedMessageIteration) -> Self {
+        Self {
+            at: iteration.timestamp,
+            content: iteration.content.clone(),
+            attachments: iteration
+                .attachments
+                .iter()
+                .map(AttachmentV1::from)
+                .collect(),
+            after_gap: iteration.may_contain_gap,
+        }
+    }
+}
+
+impl From<&Attachment> for AttachmentV1 {
+    fn from(attachment: &Attachment) -> Self {
+        Self {
+            id: attachment.id.to_string(),
+            file_name: attachment.filename.clone(),
+            size: attachment.size,
+            content_type: attachment.content_type.clone(),
+            url: attachment.url.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+    use serenity::model::id::{ChannelId, MessageId};
+
+    use super::*;
+    use crate::{
+        archived_message::{
+            ArchivedMessageFull, ArchivedMessageIncomplete, ArchivedMessageUnknownDeleted,
+        },
+        session::Session,
+        test_util,
+    };
+
+    fn sent_at() -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(1_700_000_000_000).unwrap()
+    }
+
+    fn deleted_at() -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(1_700_000_060_000).unwrap()
+    }
+
+    fn full() -> ArchivedMessageFull {
+        let message = test_util::message(
+            1,
+            json!({ "content": "hello", "attachments": [test_util::attachment(5)] }),
+        );
+        ArchivedMessageFull::from_gateway(message, &Session::new(None))
+    }
+
+    fn incomplete() -> ArchivedMessageIncomplete {
+        let update = test_util::update(
+            1,
+            json!({ "content": "edited", "timestamp": "2023-11-14T22:13:20Z" }),
+        );
+        ArchivedMessageIncomplete::from_gateway(update, sent_at(), &Session::new(None))
+    }
+
+    #[test]
+    fn maps_full_messages() {
+        let exported = MessageV1::from(&ArchivedMessage::Full(full()));
+        assert_eq!(exported.schema_version, 1);
+        assert_eq!(exported.id, "1");
+        assert_eq!(exported.channel_id, "20");
+        assert_eq!(exported.guild_id.as_deref(), Some("30"));
+        assert_eq!(exported.author_id.as_deref(), Some("100"));
+        assert_eq!(exported.sent_at, Some(sent_at()));
+        assert_eq!(exported.completeness, CompletenessV1::Full);
+        assert!(!exported.deleted);
+        assert_eq!(exported.versions.len(), 1);
+        let version = &exported.versions[0];
+        assert_eq!(version.content, "hello");
+        assert_eq!(
+            version.attachments,
+            [AttachmentV1 {
+                id: "5".to_string(),
+                file_name: "cat.png".to_string(),
+                size: 10,
+                content_type: Some("image/png".to_string()),
+                url: "https://cdn.discordapp.com/cat.png".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn maps_deleted_full_messages() {
+        let deleted = full().into_deleted(Some(deleted_at()));
+        let exported = MessageV1::from(&ArchivedMessage::FullDeleted(deleted));
+        assert_eq!(exported.completeness, CompletenessV1::Full);
+        assert!(exported.deleted);
+        assert_eq!(exported.deleted_at, Some(deleted_at()));
+        assert_eq!(exported.versions[0].content, "hello");
+    }
+
+    #[test]
+    fn maps_incomplete_messages() {
+        let exported = MessageV1::from(&ArchivedMessage::Incomplete(incomplete()));
+        assert_eq!(exported.completeness, CompletenessV1::EditsOnly);
+        assert_eq!(exported.author_id, None);
+        assert!(!exported.deleted);
+        assert_eq!(exported.versions[0].content, "edited");
+    }
+
+    #[test]
+    fn maps_deleted_incomplete_messages() {
+        let deleted = incomplete().into_deleted(None);
+        let exported = MessageV1::from(&ArchivedMessage::IncompleteDeleted(deleted));
+        assert_eq!(exported.completeness, CompletenessV1::EditsOnly);
+        assert!(exported.deleted);
+        assert_eq!(exported.deleted_at, None);
+    }
+
+    #[test]
+    fn maps_messages_only_seen_deleted() {
+        let deleted = ArchivedMessageUnknownDeleted {
+            id: MessageId(1),
+            channel_id: ChannelId(20),
+            guild_id: None,
+            deleted_timestamp: Some(deleted_at()),
+            channel_deleted_at: None,
+            archive_stopped_at: None,
+        };
+        let exported = MessageV1::from(&ArchivedMessage::UnknownDeleted(deleted));
+        assert_eq!(exported.completeness, CompletenessV1::DeletionOnly);
+        assert_eq!(exported.sent_at, None);
+        assert!(exported.deleted);
+        assert_eq!(exported.deleted_at, Some(deleted_at()));
+        assert!(exported.versions.is_empty());
+    }
+
+    #[test]
+    fn only_replies_reply_to_a_message() {
+        let reference = json!({ "message_id": "9", "channel_id": "20" });
+        let reply = test_util::message(1, json!({ "type": 19, "message_reference": reference }));
+        let pin = test_util::message(2, json!({ "type": 6, "message_reference": reference }));
+        let [reply, pin] = [reply, pin].map(|message| {
+            let archived = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+            MessageV1::from(&ArchivedMessage::Full(archived))
+        });
+        assert_eq!(reply.reply_to.as_deref(), Some("9"));
+        assert_eq!(pin.reply_to, None);
+    }
+
+    #[test]
+    fn writes_times_as_rfc_3339() {
+        let exported = serde_json::to_value(MessageV1::from(&ArchivedMessage::Full(full())));
+        assert_eq!(exported.unwrap()["sent_at"], "2023-11-14T22:13:20Z");
+    }
+}
@@ -0,0 +1,182 @@
+use bson::doc;
+use chrono::{serde::ts_milliseconds, Utc};
+use serde::Serialize;
+use serenity::model::id::UserId;
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, Write},
+    path::Path,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    archived_message::{ArchivedMessage, Timestamp},
+    config::Config,
+    mong::{get_mong, messages_collection},
+    MainError,
+};
+
+const MESSAGES_FILE: &str = "messages.ndjson";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes what's in an export bundle
+#[derive(Debug, Serialize)]
+struct Manifest {
+    user_id: UserId,
+    #[serde(with = "ts_milliseconds")]
+    exported_at: Timestamp,
+    message_count: u64,
+    /// The bundle only has links to attachments, ones downloaded into GridFS
+    /// are listed in the stored_attachments of their messages
+    attachment_count: u64,
+    files: Vec<&'static str>,
+}
+
+/// Write every archived message by `user_id`, from all guilds and the
+/// collections of all bots, into a ZIP bundle with one message per line and a
+/// manifest
+///
+/// The messages are streamed into the bundle, so large histories don't have
+/// to fit in memory. Messages we only know were deleted have no author and
+/// can't be attributed to anyone. A message archived by several bots is in
+/// the bundle once per bot.
+pub async fn run(config: Config, user_id: UserId, path: &Path) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+
+    let mut bundle = Bundle::new(BufWriter::new(File::create(path)?))?;
+    for location in config.message_locations() {
+        let filter = doc! {
+            "author_id": user_id.to_string(),
+        };
+        let mut cursor = messages_collection(&mong, &location)
+            .find(filter, None)
+            .await?;
+        while cursor.advance().await? {
+            bundle.add(&cursor.deserialize_current()?)?;
+        }
+    }
+    let message_count = bundle.finish(user_id, Utc::now())?;
+
+    println!(
+        "Exported {message_count} messages by {user_id} to {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// A bundle being written, messages first and the manifest once they're all
+/// in
+struct Bundle<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    options: FileOptions,
+    message_count: u64,
+    attachment_count: u64,
+}
+
+impl<W: Write + Seek> Bundle<W> {
+    fn new(writer: W) -> std::io::Result<Self> {
+        let mut zip = ZipWriter::new(writer);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file(MESSAGES_FILE, options)
+            .map_err(std::io::Error::from)?;
+        Ok(Self {
+            zip,
+            options,
+            message_count: 0,
+            attachment_count: 0,
+        })
+    }
+
+    fn add(&mut self, message: &ArchivedMessage) -> std::io::Result<()> {
+        if let Some(iteration) = message.latest_iteration() {
+            self.attachment_count += iteration.attachments.len() as u64;
+        }
+        serde_json::to_writer(&mut self.zip, message)?;
+        self.zip.write_all(b"\n")?;
+        self.message_count += 1;
+        Ok(())
+    }
+
+    /// Write the manifest, returning how many messages are in the bundle
+    fn finish(mut self, user_id: UserId, exported_at: Timestamp) -> std::io::Result<u64> {
+        let manifest = Manifest {
+            user_id,
+            exported_at,
+            message_count: self.message_count,
+            attachment_count: self.attachment_count,
+            files: vec![MESSAGES_FILE, MANIFEST_FILE],
+        };
+        self.zip
+            .start_file(MANIFEST_FILE, self.options)
+            .map_err(std::io::Error::from)?;
+        serde_json::to_writer_pretty(&mut self.zip, &manifest)?;
+        self.zip.finish().map_err(std::io::Error::from)?.flush()?;
+        Ok(self.message_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    #[test]
+    fn bundle_has_the_manifest_and_messages() {
+        let session = Session::new(None);
+        let full = |id, overrides| {
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+                test_util::message(id, overrides),
+                &session,
+            ))
+        };
+        let messages = [
+            full(1, json!({ "content": "hello" })),
+            full(
+                2,
+                json!({ "attachments": [test_util::attachment(5), test_util::attachment(6)] }),
+            ),
+        ];
+
+        let mut written = Vec::new();
+        let mut bundle = Bundle::new(Cursor::new(&mut written)).unwrap();
+        for message in &messages {
+            bundle.add(message).unwrap();
+        }
+        let exported_at = Utc::now();
+        assert_eq!(bundle.finish(UserId(100), exported_at).unwrap(), 2);
+
+        let mut zip = ZipArchive::new(Cursor::new(written)).unwrap();
+        let mut read = |name| {
+            let mut contents = String::new();
+            zip.by_name(name)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        let manifest: Value = serde_json::from_str(&read(MANIFEST_FILE)).unwrap();
+        assert_eq!(
+            manifest,
+            json!({
+                "user_id": "100",
+                "exported_at": exported_at.timestamp_millis(),
+                "message_count": 2,
+                "attachment_count": 2,
+                "files": [MESSAGES_FILE, MANIFEST_FILE],
+            })
+        );
+        let lines: Vec<Value> = read(MESSAGES_FILE)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected: Vec<Value> = messages
+            .iter()
+            .map(|message| serde_json::to_value(message).unwrap())
+            .collect();
+        assert_eq!(lines, expected);
+    }
+}
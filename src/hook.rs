@@ -0,0 +1,264 @@
+use async_trait::async_trait;
+use serenity::model::id::MessageId;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::{archived_message::ArchivedMessage, publisher::ArchiveNoticeKind};
+
+/// What a hook decided to do with a document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookOutcome {
+    /// Go on with the document as it is now
+    Write,
+    /// Don't store this version of the document at all
+    Veto,
+}
+
+/// Custom logic run on message documents the archiver is about to write,
+/// registered with [`crate::archiver::run_with_hooks`]
+///
+/// Hooks see new messages, edits and deletions received on the gateway,
+/// including bulk deletions. They don't see writes that only touch a few
+/// fields of documents already archived: pins, thread links, channel and
+/// guild deletions and deletions inferred by reconciliation, nor messages
+/// inserted by reconciliation or backfilling.
+///
+/// Hooks run one after another in the order they were registered, after
+/// the document is fully built and normalized and right before it's
+/// written. Each sees the changes of the ones before it and may change the
+/// document further, for updates and deletions that's the whole document
+/// including earlier iterations. The message id is read-only, a hook that
+/// changes it vetoes the write. The first veto wins: later hooks don't
+/// run, nothing is written, published or counted as stored, and for updates
+/// and deletions the archive keeps its previous version.
+///
+/// When the hooks run relative to the write, per path:
+///
+/// - New messages: before the insert, or with write batching before the message
+///   joins the batch, so a batch only holds documents the hooks already let
+///   through.
+/// - Edits and single deletions: after the archived version is read and the new
+///   one built from it, before the upsert replacing it.
+/// - Bulk deletions: for each message in turn, after all of them are read and
+///   before any is written. Messages already archived are then replaced only if
+///   they still are as they were read, so a deletion the hooks passed can still
+///   be skipped when an edit raced it. Those and the messages that weren't
+///   archived are written after every hook ran.
+///
+/// Attachments are downloaded and notices published only after the write
+/// succeeded, so they always reflect what the hooks let through.
+#[async_trait]
+pub trait ArchiveHook: Send + Sync {
+    /// Used in logs when the hook vetoes a write
+    fn name(&self) -> &str;
+
+    async fn before_write(
+        &self,
+        message: &mut ArchivedMessage,
+        kind: ArchiveNoticeKind,
+    ) -> HookOutcome;
+}
+
+/// Why a document passed through the hooks must not be written
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum HookRejection {
+    #[error("Hook {hook} vetoed writing message {id}")]
+    Vetoed { hook: String, id: MessageId },
+
+    #[error("Hook {hook} changed the id of message {id}, so it isn't written")]
+    ChangedId { hook: String, id: MessageId },
+}
+
+/// Pass a document through `hooks` in order, see [`ArchiveHook`]
+pub async fn run_hooks(
+    hooks: &[Arc<dyn ArchiveHook>],
+    message: &mut ArchivedMessage,
+    kind: ArchiveNoticeKind,
+) -> Result<(), HookRejection> {
+    let id = message.id();
+    for hook in hooks {
+        let outcome = hook.before_write(message, kind).await;
+        if message.id() != id {
+            return Err(HookRejection::ChangedId {
+                hook: hook.name().to_owned(),
+                id,
+            });
+        }
+        if outcome == HookOutcome::Veto {
+            return Err(HookRejection::Vetoed {
+                hook: hook.name().to_owned(),
+                id,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{archived_message::ArchivedMessageFull, session::Session, test_util};
+
+    /// Appends a tag to the latest content
+    struct Tag;
+
+    #[async_trait]
+    impl ArchiveHook for Tag {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        async fn before_write(
+            &self,
+            message: &mut ArchivedMessage,
+            _kind: ArchiveNoticeKind,
+        ) -> HookOutcome {
+            if let ArchivedMessage::Full(message) = message {
+                if let Some(iteration) = message.iterations.last_mut() {
+                    iteration.content.push_str(" #tagged");
+                }
+            }
+            HookOutcome::Write
+        }
+    }
+
+    /// Vetoes messages mentioning secrets
+    struct VetoSecrets;
+
+    #[async_trait]
+    impl ArchiveHook for VetoSecrets {
+        fn name(&self) -> &str {
+            "veto-secrets"
+        }
+
+        async fn before_write(
+            &self,
+            message: &mut ArchivedMessage,
+            _kind: ArchiveNoticeKind,
+        ) -> HookOutcome {
+            let secret = message
+                .latest_iteration()
+                .is_some_and(|i| i.content.contains("secret"));
+            if secret {
+                HookOutcome::Veto
+            } else {
+                HookOutcome::Write
+            }
+        }
+    }
+
+    /// Records whether it ran
+    #[derive(Default)]
+    struct Ran(AtomicBool);
+
+    #[async_trait]
+    impl ArchiveHook for Ran {
+        fn name(&self) -> &str {
+            "ran"
+        }
+
+        async fn before_write(
+            &self,
+            _message: &mut ArchivedMessage,
+            _kind: ArchiveNoticeKind,
+        ) -> HookOutcome {
+            self.0.store(true, Ordering::Relaxed);
+            HookOutcome::Write
+        }
+    }
+
+    struct ChangeId;
+
+    #[async_trait]
+    impl ArchiveHook for ChangeId {
+        fn name(&self) -> &str {
+            "change-id"
+        }
+
+        async fn before_write(
+            &self,
+            message: &mut ArchivedMessage,
+            _kind: ArchiveNoticeKind,
+        ) -> HookOutcome {
+            if let ArchivedMessage::Full(message) = message {
+                message.id = MessageId(2);
+            }
+            HookOutcome::Write
+        }
+    }
+
+    fn archived(content: &str) -> ArchivedMessage {
+        let message = test_util::message(1, json!({ "content": content }));
+        ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+            message,
+            &Session::new(None),
+        ))
+    }
+
+    fn latest_content(message: &ArchivedMessage) -> &str {
+        &message.latest_iteration().unwrap().content
+    }
+
+    #[tokio::test]
+    async fn tagging_hook_changes_the_document() {
+        let mut message = archived("hello");
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(Tag)];
+        let result = run_hooks(&hooks, &mut message, ArchiveNoticeKind::Created).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(latest_content(&message), "hello #tagged");
+    }
+
+    #[tokio::test]
+    async fn hooks_see_the_changes_of_earlier_ones() {
+        let mut message = archived("hello");
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(Tag), Arc::new(Tag)];
+        run_hooks(&hooks, &mut message, ArchiveNoticeKind::Created)
+            .await
+            .unwrap();
+        assert_eq!(latest_content(&message), "hello #tagged #tagged");
+    }
+
+    #[tokio::test]
+    async fn veto_stops_later_hooks() {
+        let mut message = archived("a secret");
+        let ran = Arc::new(Ran::default());
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(VetoSecrets), ran.clone()];
+        let result = run_hooks(&hooks, &mut message, ArchiveNoticeKind::Created).await;
+        assert_eq!(
+            result,
+            Err(HookRejection::Vetoed {
+                hook: "veto-secrets".to_owned(),
+                id: MessageId(1),
+            })
+        );
+        assert!(!ran.0.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn messages_the_veto_lets_through_are_tagged() {
+        let mut message = archived("nothing to hide");
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(VetoSecrets), Arc::new(Tag)];
+        assert_eq!(
+            run_hooks(&hooks, &mut message, ArchiveNoticeKind::Created).await,
+            Ok(())
+        );
+        assert_eq!(latest_content(&message), "nothing to hide #tagged");
+    }
+
+    #[tokio::test]
+    async fn changing_the_id_rejects_the_write() {
+        let mut message = archived("hello");
+        let hooks: Vec<Arc<dyn ArchiveHook>> = vec![Arc::new(ChangeId)];
+        let result = run_hooks(&hooks, &mut message, ArchiveNoticeKind::Created).await;
+        assert_eq!(
+            result,
+            Err(HookRejection::ChangedId {
+                hook: "change-id".to_owned(),
+                id: MessageId(1),
+            })
+        );
+    }
+}
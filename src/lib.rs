@@ -0,0 +1,110 @@
+use thiserror::Error;
+
+use crate::config::ConfigLoadSaveError;
+
+pub mod anonymize;
+pub mod archived_message;
+pub mod archiver;
+pub mod attachment_download;
+pub mod backfill;
+pub mod backup;
+pub mod check_whitelist;
+pub mod circuit_breaker;
+pub mod compact;
+pub mod compare;
+pub mod config;
+pub mod diff;
+pub mod export;
+pub mod export_dce;
+pub mod export_schema;
+pub mod export_user;
+pub mod hook;
+pub mod migrate_timestamps;
+pub mod mong;
+pub mod permission_snapshot;
+pub mod publisher;
+pub mod query;
+pub mod reconcile;
+pub mod recover_attachments;
+pub mod redact_old;
+pub mod reference;
+pub mod replay;
+pub mod reply_tree;
+pub mod request_pacer;
+pub mod role;
+pub mod session;
+pub mod show;
+pub mod system_event;
+#[cfg(test)]
+mod test_util;
+pub mod typing_event;
+pub mod util;
+pub mod voice_message;
+
+#[derive(Debug, Error)]
+pub enum MainError {
+    #[error(transparent)]
+    Serenity(#[from] serenity::Error),
+
+    #[error(transparent)]
+    Mongodb(#[from] mongodb::error::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("Failed to connect to the broker: {0}")]
+    Nats(#[from] async_nats::ConnectError),
+
+    #[error("Failed to load the config: {0}")]
+    Config(#[from] ConfigLoadSaveError),
+
+    #[error("{0} is required for this mode")]
+    MissingArg(&'static str),
+
+    #[error("{0} whitelisted guilds are missing")]
+    AbsentGuilds(usize),
+
+    #[error("{arg} {problem}")]
+    InvalidArg {
+        arg: &'static str,
+        problem: &'static str,
+    },
+}
+
+/// What the binary does when started
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    ArchiveNewMessages,
+    ShowMessage,
+    Backup,
+    Restore,
+    Reconcile,
+    Compact,
+    Diff,
+    AnonymizeGuild,
+    ExportUser,
+    RecoverAttachments,
+    Replay,
+    RedactOld,
+    MigrateTimestamps,
+    Export,
+    BackfillChannel,
+    CheckWhitelist,
+}
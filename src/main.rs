@@ -1,15 +1,14 @@
 use clap::Parser;
-use config::ConfigLoadSaveError;
+use discord_archive_selfbot::{
+    anonymize, archiver, backfill, backup, check_whitelist, compact, compare,
+    config::{Config, ConfigLoadSaveError},
+    export::{self, ExportFormat},
+    export_schema::SchemaVersion,
+    export_user, migrate_timestamps, reconcile, recover_attachments, redact_old, replay, show,
+    MainError, Mode,
+};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 use std::{path::PathBuf, process};
-use thiserror::Error;
-
-use crate::config::Config;
-
-mod archived_message;
-mod archiver;
-mod config;
-mod mong;
-mod util;
 
 #[tokio::main]
 async fn main() {
@@ -19,41 +18,210 @@ async fn main() {
     }
 }
 
-#[derive(Debug, Error)]
-pub enum MainError {
-    #[error(transparent)]
-    Serenity(#[from] serenity::Error),
+#[derive(Debug, clap::Parser)]
+struct Args {
+    /// Overrides `default_mode` from the config
+    #[arg(value_enum)]
+    pub mode: Option<Mode>,
 
-    #[error(transparent)]
-    Mongodb(#[from] mongodb::error::Error),
+    /// Print the effective configuration with secrets redacted and exit
+    #[arg(long)]
+    pub print_config: bool,
 
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
+    /// The message to show in show-message mode
+    #[arg(long, required_if_eq("mode", "show-message"))]
+    pub message_id: Option<u64>,
 
-    #[error(transparent)]
-    Json(#[from] serde_json::Error),
+    /// The backup file to write in backup mode or read in restore mode, or
+    /// the bundle to write in export-user mode
+    #[arg(
+        long,
+        required_if_eq_any([("mode", "backup"), ("mode", "restore"), ("mode", "export-user")])
+    )]
+    pub file: Option<PathBuf>,
 
-    #[error("Failed to load the config: {0}")]
-    Config(#[from] ConfigLoadSaveError),
-}
+    /// The connection string of the archive to compare against in diff mode
+    #[arg(long, required_if_eq("mode", "diff"))]
+    pub other_connstring: Option<String>,
 
-#[derive(Debug, clap::Parser)]
-struct Args {
-    #[arg(value_enum)]
-    pub mode: Mode,
-}
+    /// The guild whose archive anonymize-guild mode rewrites, or export mode
+    /// is limited to
+    #[arg(long, required_if_eq("mode", "anonymize-guild"))]
+    pub guild_id: Option<u64>,
+
+    /// The channel whose archive replay mode posts, whose history
+    /// backfill-channel mode archives, or export mode is limited to
+    #[arg(
+        long,
+        required_if_eq_any([("mode", "replay"), ("mode", "backfill-channel")])
+    )]
+    pub channel_id: Option<u64>,
+
+    /// The webhook replay mode posts through, in the target channel
+    #[arg(long, required_if_eq("mode", "replay"))]
+    pub webhook_url: Option<String>,
+
+    /// How many times faster than the original conversation to replay,
+    /// without it messages are posted back to back
+    #[arg(long)]
+    pub speed: Option<f64>,
+
+    /// The user whose messages export-user mode bundles
+    #[arg(long, required_if_eq("mode", "export-user"))]
+    pub user_id: Option<u64>,
+
+    /// Also blank message content and drop embeds when anonymizing, instead
+    /// of only replacing user ids
+    #[arg(long)]
+    pub redact_content: bool,
+
+    /// Go ahead with compacting, which can block writes while it runs
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Only estimate how much backfill-channel mode would fetch
+    #[arg(long)]
+    pub dry_run: bool,
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-enum Mode {
-    ArchiveNewMessages,
+    /// The schema export mode writes messages in, without it they're
+    /// written as stored
+    #[arg(long, value_enum)]
+    pub schema_version: Option<SchemaVersion>,
+
+    /// The format export mode writes, dce-json needs --channel-id
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+
+    /// Have export mode write replies right after what they reply to
+    #[arg(long)]
+    pub nest_replies: bool,
 }
 
 async fn run() -> Result<(), MainError> {
     let args = Args::parse();
 
-    let config = Config::load(&PathBuf::from("./config.toml")).await?;
+    // Printed before it's validated, so a broken config can be inspected
+    let config = Config::load_unvalidated(&PathBuf::from("./config.toml")).await?;
 
-    match args.mode {
+    if args.print_config {
+        let redacted =
+            toml::to_string_pretty(&config.redacted()).map_err(ConfigLoadSaveError::from)?;
+        print!("{redacted}");
+        return Ok(());
+    }
+    config.validate()?;
+
+    match effective_mode(args.mode, config.default_mode) {
         Mode::ArchiveNewMessages => archiver::run(config).await,
+        Mode::ShowMessage => {
+            let message_id = args
+                .message_id
+                .ok_or(MainError::MissingArg("--message-id"))?;
+            show::run(config, MessageId(message_id)).await
+        }
+        Mode::Backup => {
+            let file = args.file.ok_or(MainError::MissingArg("--file"))?;
+            backup::backup(config, &file).await
+        }
+        Mode::Restore => {
+            let file = args.file.ok_or(MainError::MissingArg("--file"))?;
+            backup::restore(config, &file).await
+        }
+        Mode::Reconcile => reconcile::run(config).await,
+        Mode::Compact => compact::run(config, args.yes).await,
+        Mode::Diff => {
+            let other = args
+                .other_connstring
+                .ok_or(MainError::MissingArg("--other-connstring"))?;
+            compare::run(config, &other).await
+        }
+        Mode::AnonymizeGuild => {
+            let guild_id = args.guild_id.ok_or(MainError::MissingArg("--guild-id"))?;
+            anonymize::run(config, GuildId(guild_id), args.redact_content).await
+        }
+        Mode::ExportUser => {
+            let user_id = args.user_id.ok_or(MainError::MissingArg("--user-id"))?;
+            let file = args.file.ok_or(MainError::MissingArg("--file"))?;
+            export_user::run(config, UserId(user_id), &file).await
+        }
+        Mode::RecoverAttachments => recover_attachments::run(config).await,
+        Mode::Replay => {
+            let channel_id = args
+                .channel_id
+                .ok_or(MainError::MissingArg("--channel-id"))?;
+            let webhook_url = args
+                .webhook_url
+                .ok_or(MainError::MissingArg("--webhook-url"))?;
+            replay::run(config, ChannelId(channel_id), &webhook_url, args.speed).await
+        }
+        Mode::RedactOld => redact_old::run(config).await,
+        Mode::MigrateTimestamps => migrate_timestamps::run(config).await,
+        Mode::BackfillChannel => {
+            let channel_id = args
+                .channel_id
+                .ok_or(MainError::MissingArg("--channel-id"))?;
+            backfill::run(config, ChannelId(channel_id), args.dry_run).await
+        }
+        Mode::Export => {
+            export::run(
+                config,
+                args.guild_id.map(GuildId),
+                args.channel_id.map(ChannelId),
+                args.format,
+                args.schema_version,
+                args.nest_replies,
+            )
+            .await
+        }
+        Mode::CheckWhitelist => check_whitelist::run(config).await,
+    }
+}
+
+/// The mode from the command line wins over the one from the config
+fn effective_mode(from_args: Option<Mode>, from_config: Option<Mode>) -> Mode {
+    from_args.or(from_config).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_mode_from_the_args_wins() {
+        assert_eq!(
+            effective_mode(Some(Mode::Export), Some(Mode::Reconcile)),
+            Mode::Export
+        );
+    }
+
+    #[test]
+    fn the_mode_from_the_config_is_used_without_one_from_the_args() {
+        assert_eq!(effective_mode(None, Some(Mode::Reconcile)), Mode::Reconcile);
+    }
+
+    #[test]
+    fn archiving_is_the_default_mode() {
+        assert_eq!(effective_mode(None, None), Mode::ArchiveNewMessages);
+    }
+
+    #[test]
+    fn the_mode_can_come_from_the_config_alone() {
+        let args = Args::try_parse_from(["iswyd"]).unwrap();
+        assert_eq!(args.mode, None);
+        let config: Config = toml::from_str(
+            r#"
+            default_mode = "reconcile"
+            ignored_guilds = []
+            ignored_channels = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            effective_mode(args.mode, config.default_mode),
+            Mode::Reconcile
+        );
+
+        let args = Args::try_parse_from(["iswyd", "export"]).unwrap();
+        assert_eq!(effective_mode(args.mode, config.default_mode), Mode::Export);
     }
 }
@@ -0,0 +1,64 @@
+use bson::doc;
+
+use crate::{
+    config::Config,
+    mong::{
+        get_mong, messages_collection, roles_collection, sessions_collection, MESSAGES, ROLES,
+        SESSIONS,
+    },
+    MainError,
+};
+
+/// Convert the times stored before they were BSON dates, milliseconds or
+/// strings, into dates
+///
+/// Both formats are read either way, this only matters for queries and
+/// indexes in Mongo itself. Documents that are already converted aren't
+/// touched, so it can be run again.
+pub async fn run(config: Config) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+
+    convert(
+        &messages_collection(&mong, &config.collection_location(MESSAGES)),
+        &[
+            "timestamp",
+            "deleted_timestamp",
+            "channel_deleted_at",
+            "archive_stopped_at",
+            "content_redacted_at",
+        ],
+    )
+    .await?;
+    convert(
+        &roles_collection(&mong, &config.collection_location(ROLES)),
+        &["deleted_at"],
+    )
+    .await?;
+    convert(
+        &sessions_collection(&mong, &config.collection_location(SESSIONS)),
+        &["ended_at"],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn convert<T>(
+    collection: &mongodb::Collection<T>,
+    fields: &[&str],
+) -> Result<(), mongodb::error::Error> {
+    for field in fields {
+        let filter = doc! {
+            *field: { "$type": ["number", "string"] },
+        };
+        let update = vec![doc! {
+            "$set": { *field: { "$toDate": format!("${field}") } },
+        }];
+        let result = collection.update_many(filter, update, None).await?;
+        println!(
+            "Converted {field} of {} documents in {}",
+            result.modified_count,
+            collection.name()
+        );
+    }
+    Ok(())
+}
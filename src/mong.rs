@@ -1,4 +1,376 @@
+use bson::{doc, Bson, Document};
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::{GridFsBucketOptions, IndexOptions, UpdateOptions},
+    IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    archived_message::ArchivedMessage, permission_snapshot::PermissionSnapshot, role::CachedRole,
+    session::Session, system_event::SystemEvent, typing_event::TypingEvent,
+};
+
+/// The database every collection lives in unless configured otherwise
+pub const DEFAULT_DATABASE: &str = "discor";
+
+// Logical names of the collections, which the config can map elsewhere
+pub const MESSAGES: &str = "messages";
+pub const SYSTEM_EVENTS: &str = "system_events";
+pub const TYPING_EVENTS: &str = "typing_events";
+pub const PERMISSION_SNAPSHOTS: &str = "permission_snapshots";
+pub const SESSIONS: &str = "sessions";
+pub const ROLES: &str = "roles";
+/// The GridFS bucket downloaded attachments go in
+pub const ATTACHMENTS: &str = "attachments";
+
+/// Where a logical collection is stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionLocation {
+    #[serde(default = "default_database")]
+    pub database: String,
+    pub collection: String,
+}
+
+impl CollectionLocation {
+    /// The location used when the config doesn't map a logical name, a
+    /// collection of the same name in the default database
+    pub fn default_for(name: &str) -> Self {
+        Self {
+            database: default_database(),
+            collection: name.to_string(),
+        }
+    }
+
+    fn get<T>(&self, mong: &mongodb::Client) -> mongodb::Collection<T> {
+        mong.database(&self.database).collection(&self.collection)
+    }
+}
+
+fn default_database() -> String {
+    DEFAULT_DATABASE.to_string()
+}
+
+/// Serialize a value the way the driver does when inserting it, so updates
+/// store it the same, like timestamps as dates
+pub fn to_stored_bson<T: Serialize + ?Sized>(value: &T) -> bson::ser::Result<Bson> {
+    // Only documents can be serialized that way
+    #[derive(Serialize)]
+    struct Wrapped<'a, T: ?Sized> {
+        value: &'a T,
+    }
+    let mut wrapped = to_stored_document(&Wrapped { value })?;
+    Ok(wrapped.remove("value").unwrap_or(Bson::Null))
+}
+
+/// [`to_stored_bson`] for values that are documents
+pub fn to_stored_document<T: Serialize>(value: &T) -> bson::ser::Result<Document> {
+    bson::to_raw_document_buf(value)?
+        .to_document()
+        .map_err(serde::ser::Error::custom)
+}
+
 pub async fn get_mong(connstring: &str) -> Result<mongodb::Client, mongodb::error::Error> {
     let mong_options = mongodb::options::ClientOptions::parse(connstring).await?;
     mongodb::Client::with_options(mong_options)
 }
+
+pub fn messages_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<ArchivedMessage> {
+    location.get(mong)
+}
+
+pub fn system_events_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<SystemEvent> {
+    location.get(mong)
+}
+
+pub fn typing_events_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<TypingEvent> {
+    location.get(mong)
+}
+
+pub fn permission_snapshots_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<PermissionSnapshot> {
+    location.get(mong)
+}
+
+pub fn sessions_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<Session> {
+    location.get(mong)
+}
+
+/// The GridFS bucket for downloaded attachments, named after the collection
+pub fn attachments_bucket(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::gridfs::GridFsBucket {
+    let options = GridFsBucketOptions::builder()
+        .bucket_name(location.collection.clone())
+        .build();
+    mong.database(&location.database).gridfs_bucket(options)
+}
+
+pub fn roles_collection(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+) -> mongodb::Collection<CachedRole> {
+    location.get(mong)
+}
+
+/// Run a query, logging it along with its filter if it took longer than
+/// `threshold`, a zero threshold disables this
+pub async fn log_if_slow<T, F>(
+    threshold: Duration,
+    operation: &str,
+    filter: Document,
+    query: impl FnOnce(Document) -> F,
+) -> T
+where
+    F: Future<Output = T>,
+{
+    let (result, slow) = time_query(threshold, operation, filter, query).await;
+    if let Some(slow) = slow {
+        println!("{slow}");
+    }
+    result
+}
+
+/// Run a query, along with what to log if it was slow
+async fn time_query<T, F>(
+    threshold: Duration,
+    operation: &str,
+    filter: Document,
+    query: impl FnOnce(Document) -> F,
+) -> (T, Option<String>)
+where
+    F: Future<Output = T>,
+{
+    if threshold.is_zero() {
+        return (query(filter).await, None);
+    }
+    let shown = filter.to_string();
+    let started = Instant::now();
+    let result = query(filter).await;
+    let elapsed = started.elapsed();
+    let slow = (elapsed > threshold).then(|| {
+        format!(
+            "Slow {operation} took {}ms, filter: {shown}",
+            elapsed.as_millis()
+        )
+    });
+    (result, slow)
+}
+
+/// Pipeline stages sorting messages by when they were sent, 1 for oldest
+/// first and -1 for newest first
+///
+/// Until migrate-timestamps has run some send times are milliseconds, which
+/// Mongo sorts before every date, so they're compared as dates. That can't
+/// use an index, so aggregations using these should allow disk use.
+pub fn sort_by_timestamp(direction: i32) -> [Document; 3] {
+    [
+        doc! { "$addFields": { "sort_timestamp": { "$toDate": "$timestamp" } } },
+        doc! { "$sort": { "sort_timestamp": direction, "_id": direction } },
+        doc! { "$unset": "sort_timestamp" },
+    ]
+}
+
+/// Make Mongo expire typing events after `ttl`
+///
+/// Changing the TTL later fails, the existing index has to be dropped first.
+pub async fn create_typing_events_ttl_index(
+    mong: &mongodb::Client,
+    location: &CollectionLocation,
+    ttl: Duration,
+) -> Result<(), mongodb::error::Error> {
+    let index = IndexModel::builder()
+        .keys(doc! { "timestamp": 1 })
+        .options(IndexOptions::builder().expire_after(ttl).build())
+        .build();
+    typing_events_collection(mong, location)
+        .create_index(index, None)
+        .await?;
+    Ok(())
+}
+
+/// Index a messages collection for looking messages up by id, which every
+/// update and deletion does, and for going through a channel or guild in
+/// order
+///
+/// Creating indexes that already exist does nothing, so this runs on every
+/// start. The id index isn't unique, older archives can hold duplicates.
+pub async fn create_message_indexes(
+    collection: &mongodb::Collection<ArchivedMessage>,
+) -> Result<(), mongodb::error::Error> {
+    let indexes = [
+        doc! { "id": 1 },
+        doc! { "channel_id": 1, "timestamp": 1 },
+        doc! { "guild_id": 1, "timestamp": 1 },
+    ]
+    .into_iter()
+    .map(|keys| IndexModel::builder().keys(keys).build());
+    collection.create_indexes(indexes, None).await?;
+    Ok(())
+}
+
+/// Make sure a cache collection holds at most one document per id
+pub async fn create_cache_index<T>(
+    collection: &mongodb::Collection<T>,
+) -> Result<(), mongodb::error::Error> {
+    let index = IndexModel::builder()
+        .keys(doc! { "id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+/// Idempotently store a cached entity (user, channel, guild, ...) under its id
+///
+/// Two concurrent upserts of an id that isn't stored yet can both try to
+/// insert it, the loser gets a duplicate key error from the unique index and
+/// retries, which then updates the document the winner inserted.
+pub async fn upsert_cached<T: Serialize>(
+    collection: &mongodb::Collection<T>,
+    id: impl ToString,
+    value: &T,
+) -> Result<(), mongodb::error::Error> {
+    let filter = doc! {
+        "id": id.to_string(),
+    };
+    let update = doc! {
+        "$set": to_stored_document(value)?,
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    match collection
+        .update_one(filter.clone(), update.clone(), options.clone())
+        .await
+    {
+        Err(err) if is_duplicate_key(&err) => {
+            collection.update_one(filter, update, options).await?;
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    const DUPLICATE_KEY: i32 = 11000;
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn slow_query(filter: Document) -> usize {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        filter.len()
+    }
+
+    #[tokio::test]
+    async fn reports_queries_slower_than_the_threshold() {
+        let filter = doc! { "channel_id": "20" };
+        let (result, slow) =
+            time_query(Duration::from_millis(10), "find", filter, slow_query).await;
+        assert_eq!(result, 1);
+        let slow = slow.unwrap();
+        assert!(slow.starts_with("Slow find took "));
+        assert!(slow.ends_with(r#"filter: { "channel_id": "20" }"#));
+    }
+
+    #[tokio::test]
+    async fn fast_queries_and_a_zero_threshold_are_not_reported() {
+        let filter = doc! { "channel_id": "20" };
+        let (_, slow) = time_query(
+            Duration::from_secs(60),
+            "find",
+            filter.clone(),
+            |filter| async move { filter.len() },
+        )
+        .await;
+        assert_eq!(slow, None);
+        let (result, slow) = time_query(Duration::ZERO, "find", filter, slow_query).await;
+        assert_eq!(result, 1);
+        assert_eq!(slow, None);
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn message_indexes_can_be_created_on_every_start() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let messages = mong
+            .database("iswyd_test")
+            .collection::<ArchivedMessage>(&format!("messages_{}", uuid::Uuid::new_v4()));
+
+        let first = create_message_indexes(&messages).await;
+        let again = create_message_indexes(&messages).await;
+        let names = messages.list_index_names().await;
+        messages.drop(None).await.unwrap();
+
+        first.unwrap();
+        again.unwrap();
+        let mut names = names.unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "_id_",
+                "channel_id_1_timestamp_1",
+                "guild_id_1_timestamp_1",
+                "id_1"
+            ]
+        );
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn concurrent_upserts_store_one_document() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let cache = mong
+            .database("iswyd_test")
+            .collection::<Document>(&format!("cache_{}", uuid::Uuid::new_v4()));
+        create_cache_index(&cache).await.unwrap();
+
+        let upserts = (0..16).map(|version| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                upsert_cached(&cache, 1, &doc! { "id": "1", "version": version }).await
+            })
+        });
+        let results = futures::future::join_all(upserts).await;
+        let count = cache.count_documents(None, None).await;
+        cache.drop(None).await.unwrap();
+
+        for result in results {
+            result.unwrap().unwrap();
+        }
+        assert_eq!(count.unwrap(), 1);
+    }
+}
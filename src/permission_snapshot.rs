@@ -0,0 +1,97 @@
+use chrono::{serde::ts_milliseconds, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    model::{
+        id::{ChannelId, GuildId, UserId},
+        permissions::Permissions,
+    },
+};
+
+use crate::archived_message::Timestamp;
+
+/// What the bot was allowed to do in a channel when it started archiving it,
+/// for explaining gaps in the archive later
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PermissionSnapshot {
+    pub channel_id: ChannelId,
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub permissions: Permissions,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: Timestamp,
+}
+
+impl PermissionSnapshot {
+    /// Work out the bot's effective permissions in a guild channel
+    ///
+    /// Nothing is cached, so this takes four requests.
+    pub async fn fetch(
+        http: &Http,
+        channel_id: ChannelId,
+        guild_id: GuildId,
+    ) -> serenity::Result<Self> {
+        let user_id = http.get_current_user().await?.id;
+        let channel = channel_id
+            .to_channel(http)
+            .await?
+            .guild()
+            .ok_or(serenity::Error::Model(
+                serenity::model::ModelError::InvalidChannelType,
+            ))?;
+        let guild = guild_id.to_partial_guild(http).await?;
+        let member = guild_id.member(http, user_id).await?;
+        let permissions = guild.user_permissions_in(&channel, &member)?;
+        Ok(Self {
+            channel_id,
+            guild_id,
+            user_id,
+            permissions,
+            timestamp: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::mong::to_stored_document;
+
+    fn snapshot() -> PermissionSnapshot {
+        PermissionSnapshot {
+            channel_id: ChannelId(20),
+            guild_id: GuildId(30),
+            user_id: UserId(100),
+            permissions: Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY,
+            timestamp: Utc.timestamp_millis_opt(1_700_000_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn stores_permissions_as_a_bit_string() {
+        let stored = to_stored_document(&snapshot()).unwrap();
+        assert_eq!(
+            stored,
+            bson::doc! {
+                "channel_id": "20",
+                "guild_id": "30",
+                "user_id": "100",
+                // Bits can be past what a signed 64-bit number holds
+                "permissions": "66560",
+                "timestamp": 1_700_000_000_000_i64,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_stored_snapshots_back() {
+        let stored = to_stored_document(&snapshot()).unwrap();
+        let read: PermissionSnapshot = bson::from_document(stored).unwrap();
+        assert_eq!(read.permissions, snapshot().permissions);
+        assert!(read.permissions.read_message_history());
+        assert!(!read.permissions.send_messages());
+        assert_eq!(read.timestamp, snapshot().timestamp);
+    }
+}
@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use thiserror::Error;
+
+use crate::archived_message::{ArchivedMessage, Timestamp};
+
+/// Where to publish a notice for every archived event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BrokerConfig {
+    Nats { url: String, subject: String },
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveNoticeKind {
+    Created,
+    Updated,
+    Deleted,
+    ChannelDeleted,
+}
+
+/// A compact description of a successful write to the archive
+#[derive(Clone, Debug, Serialize)]
+pub struct ArchiveNotice {
+    pub kind: ArchiveNoticeKind,
+    pub message_id: Option<MessageId>,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub timestamp: Timestamp,
+}
+
+impl ArchiveNotice {
+    pub fn for_message(
+        kind: ArchiveNoticeKind,
+        message: &ArchivedMessage,
+        timestamp: Timestamp,
+    ) -> Self {
+        Self {
+            kind,
+            message_id: Some(message.id()),
+            channel_id: message.channel_id(),
+            guild_id: message.guild_id(),
+            timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Nats(#[from] async_nats::PublishError),
+}
+
+/// Something archived events can be published to
+///
+/// Publishing happens after the write to Mongo succeeded, failures are logged
+/// and counted but never undo or block archiving.
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, notice: &ArchiveNotice) -> Result<(), PublishError>;
+}
+
+pub struct NatsPublisher {
+    client: async_nats::Client,
+    subject: String,
+}
+
+impl NatsPublisher {
+    pub async fn connect(url: &str, subject: String) -> Result<Self, async_nats::ConnectError> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self { client, subject })
+    }
+}
+
+#[async_trait]
+impl EventPublisher for NatsPublisher {
+    async fn publish(&self, notice: &ArchiveNotice) -> Result<(), PublishError> {
+        let payload = serde_json::to_vec(notice)?;
+        self.client
+            .publish(self.subject.clone(), payload.into())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Connect to the configured broker
+pub async fn connect(
+    broker: &BrokerConfig,
+) -> Result<Box<dyn EventPublisher>, async_nats::ConnectError> {
+    match broker {
+        BrokerConfig::Nats { url, subject } => Ok(Box::new(
+            NatsPublisher::connect(url, subject.clone()).await?,
+        )),
+    }
+}
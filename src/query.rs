@@ -0,0 +1,436 @@
+use bson::{doc, Bson, Document};
+use chrono::serde::ts_milliseconds;
+use mongodb::options::AggregateOptions;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+
+use crate::{
+    archived_message::{ts_bson_datetime_option, ArchivedMessage, Timestamp},
+    mong::sort_by_timestamp,
+};
+
+const DELETED_ARCHIVE_TYPES: [&str; 3] = ["FullDeleted", "IncompleteDeleted", "UnknownDeleted"];
+
+/// How many characters of the latest content are kept in a summary
+pub const SNIPPET_LENGTH: i32 = 100;
+
+/// A lightweight view of an archived message, without the iteration history
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MessageSummary {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    /// Unknown for messages we have only heard of when they were deleted
+    pub author_id: Option<UserId>,
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub timestamp: Option<Timestamp>,
+    /// The beginning of the latest known content
+    pub content_snippet: Option<String>,
+    pub marked_as_edited: bool,
+    pub deleted: bool,
+}
+
+/// Build the projection that turns a stored message into a [`MessageSummary`]
+/// on the server, so the iterations never leave Mongo
+fn summary_projection() -> Document {
+    doc! {
+        "_id": 0,
+        "id": 1,
+        "channel_id": 1,
+        "guild_id": { "$ifNull": ["$guild_id", null] },
+        "author_id": { "$ifNull": ["$author_id", null] },
+        "timestamp": 1,
+        "content_snippet": {
+            "$cond": [
+                { "$gt": [{ "$size": { "$ifNull": ["$iterations", []] } }, 0] },
+                {
+                    "$substrCP": [
+                        { "$arrayElemAt": ["$iterations.content", -1] },
+                        0,
+                        SNIPPET_LENGTH,
+                    ]
+                },
+                null,
+            ]
+        },
+        "marked_as_edited": { "$ifNull": ["$marked_as_edited", false] },
+        "deleted": {
+            "$in": ["$archive_type", DELETED_ARCHIVE_TYPES.to_vec()]
+        },
+    }
+}
+
+/// Fetch summaries of the messages matching `filter`, newest first
+pub async fn find_message_summaries(
+    messages: &mongodb::Collection<ArchivedMessage>,
+    filter: Document,
+    limit: Option<i64>,
+) -> Result<Vec<MessageSummary>, mongodb::error::Error> {
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(sort_by_timestamp(-1));
+    if let Some(limit) = limit {
+        pipeline.push(doc! { "$limit": limit });
+    }
+    pipeline.push(doc! { "$project": summary_projection() });
+
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let mut cursor = messages
+        .aggregate(pipeline, options)
+        .await?
+        .with_type::<MessageSummary>();
+    let mut summaries = Vec::new();
+    while cursor.advance().await? {
+        summaries.push(cursor.deserialize_current()?);
+    }
+    Ok(summaries)
+}
+
+/// A deleted message with what we last knew of it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeletedMessage {
+    pub id: MessageId,
+    pub archive_type: String,
+    /// Unknown for messages we have only heard of when they were deleted
+    pub author_id: Option<UserId>,
+    #[serde(with = "ts_milliseconds")]
+    pub deleted_timestamp: Timestamp,
+    /// None when no content was ever archived
+    pub last_content: Option<String>,
+}
+
+/// Fetch the messages of a channel deleted within `from..to`, oldest deletion
+/// first
+///
+/// Deletions without a known time can't be placed on the timeline and are
+/// left out. The time is a date, or in documents that weren't migrated
+/// milliseconds or a string, so it's converted before filtering on it.
+pub async fn find_deletion_timeline(
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channel_id: ChannelId,
+    from: Timestamp,
+    to: Timestamp,
+) -> Result<Vec<DeletedMessage>, mongodb::error::Error> {
+    let pipeline = vec![
+        // Narrowed down by the channel index before anything is computed
+        doc! {
+            "$match": {
+                "channel_id": channel_id.to_string(),
+                "archive_type": { "$in": DELETED_ARCHIVE_TYPES.to_vec() },
+                "deleted_timestamp": { "$ne": null },
+            }
+        },
+        doc! {
+            "$addFields": {
+                "deleted_timestamp": { "$toLong": { "$toDate": "$deleted_timestamp" } },
+            }
+        },
+        doc! {
+            "$match": {
+                "deleted_timestamp": {
+                    "$gte": from.timestamp_millis(),
+                    "$lt": to.timestamp_millis(),
+                }
+            }
+        },
+        doc! { "$sort": { "deleted_timestamp": 1 } },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "id": 1,
+                "archive_type": 1,
+                "author_id": { "$ifNull": ["$author_id", null] },
+                "deleted_timestamp": 1,
+                "last_content": {
+                    "$ifNull": [{ "$arrayElemAt": ["$iterations.content", -1] }, null]
+                },
+            }
+        },
+    ];
+
+    let mut cursor = messages
+        .aggregate(pipeline, None)
+        .await?
+        .with_type::<DeletedMessage>();
+    let mut deleted = Vec::new();
+    while cursor.advance().await? {
+        deleted.push(cursor.deserialize_current()?);
+    }
+    Ok(deleted)
+}
+
+/// How many distinct authors wrote in a channel, meant for threads
+///
+/// Computed when asked rather than kept up to date on every message, messages
+/// with an unknown author aren't counted.
+pub async fn count_participants(
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channel_id: ChannelId,
+) -> Result<u64, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! {
+            "$match": {
+                "channel_id": channel_id.to_string(),
+                "author_id": { "$ne": null },
+            }
+        },
+        doc! { "$group": { "_id": "$author_id" } },
+        doc! { "$count": "participants" },
+    ];
+
+    let mut cursor = messages.aggregate(pipeline, None).await?;
+    if !cursor.advance().await? {
+        // $count outputs nothing when nothing matched
+        return Ok(0);
+    }
+    Ok(participant_count(&cursor.deserialize_current()?))
+}
+
+/// Read the count from the output of `$count`, which is an int32 unless it
+/// doesn't fit
+fn participant_count(counted: &Document) -> u64 {
+    match counted.get("participants") {
+        Some(Bson::Int32(count)) => *count as u64,
+        Some(Bson::Int64(count)) => *count as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        archived_message::{
+            ArchivedMessageFull, ArchivedMessageIncomplete, ArchivedMessageUnknownDeleted,
+        },
+        mong::get_mong,
+        session::Session,
+        test_util,
+    };
+
+    #[test]
+    fn projection_covers_every_summary_field() {
+        let summary = MessageSummary {
+            id: MessageId(1),
+            channel_id: ChannelId(2),
+            guild_id: None,
+            author_id: None,
+            timestamp: None,
+            content_snippet: None,
+            marked_as_edited: false,
+            deleted: false,
+        };
+        let mut summary_fields: Vec<_> = bson::to_document(&summary)
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        let mut projected_fields: Vec<_> = summary_projection()
+            .keys()
+            .filter(|key| *key != "_id")
+            .cloned()
+            .collect();
+        summary_fields.sort();
+        projected_fields.sort();
+        assert_eq!(summary_fields, projected_fields);
+    }
+
+    #[test]
+    fn summary_reads_projected_document() {
+        let sent = bson::DateTime::from_millis(1_700_000_000_000);
+        let projected = doc! {
+            "id": "10",
+            "channel_id": "20",
+            "guild_id": "30",
+            "author_id": null,
+            "timestamp": sent,
+            "content_snippet": "hello",
+            "marked_as_edited": true,
+            "deleted": false,
+        };
+        let summary: MessageSummary = bson::from_document(projected).unwrap();
+        assert_eq!(summary.id, MessageId(10));
+        assert_eq!(summary.channel_id, ChannelId(20));
+        assert_eq!(summary.guild_id, Some(GuildId(30)));
+        assert_eq!(summary.author_id, None);
+        assert_eq!(summary.timestamp, Some(sent.to_chrono()));
+        assert_eq!(summary.content_snippet.as_deref(), Some("hello"));
+        assert!(summary.marked_as_edited);
+        assert!(!summary.deleted);
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn finds_messages_sent_in_a_date_range() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let messages = mong
+            .database("iswyd_test")
+            .collection::<ArchivedMessage>(&format!("messages_{}", uuid::Uuid::new_v4()));
+
+        let session = Session::new(None);
+        let sent = |id: u64, time: &str| {
+            let message = test_util::message(id, json!({ "timestamp": time }));
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(message, &session))
+        };
+        let stored = [
+            sent(1, "2023-11-14T10:00:00Z"),
+            sent(2, "2023-11-14T12:00:00Z"),
+            sent(3, "2023-11-14T14:00:00Z"),
+        ];
+        messages.insert_many(stored, None).await.unwrap();
+        // One not migrated yet, with the time in milliseconds, which date
+        // ranges don't match until migrate-timestamps has run
+        messages
+            .clone_with_type::<Document>()
+            .insert_one(
+                doc! {
+                    "archive_type": "Full",
+                    "id": "4",
+                    "channel_id": "20",
+                    "timestamp": 1_699_966_800_000_i64,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let from = Utc.with_ymd_and_hms(2023, 11, 14, 11, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2023, 11, 14, 15, 0, 0).unwrap();
+        let filter = doc! {
+            "timestamp": {
+                "$gte": bson::DateTime::from_chrono(from),
+                "$lt": bson::DateTime::from_chrono(to),
+            }
+        };
+        let found = find_message_summaries(&messages, filter, None).await;
+        messages.drop(None).await.unwrap();
+
+        let ids: Vec<_> = found.unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, [MessageId(3), MessageId(2)]);
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn deletion_timeline_mixes_every_deleted_variant() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let messages = mong
+            .database("iswyd_test")
+            .collection::<ArchivedMessage>(&format!("messages_{}", uuid::Uuid::new_v4()));
+
+        let session = Session::new(None);
+        let at = |hour| Utc.with_ymd_and_hms(2023, 11, 14, hour, 0, 0).unwrap();
+        let full = |id, content: &str| {
+            let message = test_util::message(id, json!({ "content": content }));
+            ArchivedMessageFull::from_gateway(message, &session)
+        };
+        let unknown = |id, channel_id, deleted_timestamp| {
+            ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
+                id: MessageId(id),
+                channel_id: ChannelId(channel_id),
+                guild_id: None,
+                deleted_timestamp,
+                channel_deleted_at: None,
+                archive_stopped_at: None,
+            })
+        };
+        let incomplete = ArchivedMessageIncomplete::from_gateway(
+            test_util::update(2, json!({ "content": "edited" })),
+            at(10),
+            &session,
+        );
+        let stored = [
+            ArchivedMessage::FullDeleted(full(1, "hello").into_deleted(Some(at(12)))),
+            ArchivedMessage::IncompleteDeleted(incomplete.into_deleted(Some(at(11)))),
+            unknown(3, 20, Some(at(13))),
+            // Left out: not deleted, deleted at an unknown time, deleted
+            // outside the range and deleted in another channel
+            ArchivedMessage::Full(full(4, "still here")),
+            unknown(5, 20, None),
+            unknown(6, 20, Some(at(16))),
+            unknown(7, 21, Some(at(12))),
+        ];
+        messages.insert_many(stored, None).await.unwrap();
+
+        let found = find_deletion_timeline(&messages, ChannelId(20), at(11), at(15)).await;
+        messages.drop(None).await.unwrap();
+
+        let found = found.unwrap();
+        let timeline: Vec<_> = found
+            .iter()
+            .map(|m| (m.id, m.archive_type.as_str(), m.deleted_timestamp))
+            .collect();
+        assert_eq!(
+            timeline,
+            [
+                (MessageId(2), "IncompleteDeleted", at(11)),
+                (MessageId(1), "FullDeleted", at(12)),
+                (MessageId(3), "UnknownDeleted", at(13)),
+            ]
+        );
+        assert_eq!(found[0].author_id, None);
+        assert_eq!(found[0].last_content.as_deref(), Some("edited"));
+        assert_eq!(found[1].author_id, Some(UserId(100)));
+        assert_eq!(found[1].last_content.as_deref(), Some("hello"));
+        assert_eq!(found[2].author_id, None);
+        assert_eq!(found[2].last_content, None);
+    }
+
+    #[test]
+    fn deleted_message_reads_unknown_deletions() {
+        let projected = doc! {
+            "id": "3",
+            "archive_type": "UnknownDeleted",
+            "author_id": null,
+            "deleted_timestamp": 1_700_000_000_000_i64,
+            "last_content": null,
+        };
+        let deleted: DeletedMessage = bson::from_document(projected).unwrap();
+        assert_eq!(deleted.id, MessageId(3));
+        assert_eq!(deleted.author_id, None);
+        assert_eq!(
+            deleted.deleted_timestamp.timestamp_millis(),
+            1_700_000_000_000
+        );
+        assert_eq!(deleted.last_content, None);
+    }
+
+    #[test]
+    fn reads_participant_count_of_either_width() {
+        assert_eq!(participant_count(&doc! { "participants": 3_i32 }), 3);
+        assert_eq!(
+            participant_count(&doc! { "participants": 5_000_000_000_i64 }),
+            5_000_000_000
+        );
+        assert_eq!(participant_count(&doc! {}), 0);
+    }
+
+    #[test]
+    fn summary_reads_millisecond_timestamps() {
+        let projected = doc! {
+            "id": "10",
+            "channel_id": "20",
+            "guild_id": null,
+            "author_id": "40",
+            "timestamp": 1_700_000_000_000_i64,
+            "content_snippet": null,
+            "marked_as_edited": false,
+            "deleted": true,
+        };
+        let summary: MessageSummary = bson::from_document(projected).unwrap();
+        assert_eq!(
+            summary.timestamp.map(|ts| ts.timestamp_millis()),
+            Some(1_700_000_000_000)
+        );
+        assert!(summary.deleted);
+    }
+}
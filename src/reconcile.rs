@@ -0,0 +1,380 @@
+use bson::{doc, Document};
+use chrono::Utc;
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+use serenity::{
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, MessageId},
+    },
+};
+use std::{collections::HashSet, time::Duration};
+
+use crate::{
+    archived_message::{
+        convert_ts, ArchivedChannelType, ArchivedMessage, ArchivedMessageFull, Timestamp,
+    },
+    circuit_breaker::CircuitBreaker,
+    config::Config,
+    mong::{get_mong, log_if_slow, messages_collection},
+    session::Session,
+    MainError,
+};
+
+/// Discord doesn't return more messages per request
+const MAX_SAMPLE_SIZE: u64 = 100;
+
+#[derive(Deserialize)]
+struct ArchivedId {
+    id: MessageId,
+}
+
+/// Periodically check that the latest messages of the configured channels
+/// are archived, inserting any the gateway didn't deliver
+///
+/// Messages count as archived if any bot's collection has them, missing ones
+/// go into the main bot's collection.
+pub async fn run(config: Config) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let archives: Vec<_> = config
+        .message_locations()
+        .iter()
+        .map(|location| messages_collection(&mong, location))
+        .collect();
+    let http = Http::new(&config.discor_token);
+    let session = Session::new(config.session_label.clone());
+    let sample_size = config.reconcile_sample_size.min(MAX_SAMPLE_SIZE);
+    let breaker = CircuitBreaker::new(
+        config.rest_breaker_threshold,
+        Duration::from_secs(config.rest_breaker_cooldown_secs),
+    );
+    let slow_query_threshold = Duration::from_millis(config.slow_query_threshold_ms);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.reconcile_interval_secs));
+    loop {
+        interval.tick().await;
+        for &channel_id in &config.reconcile_channels {
+            if !breaker.allow() {
+                println!("REST requests are paused, skipping channel {channel_id}");
+                continue;
+            }
+            if let Err(err) = reconcile_channel(
+                &http,
+                &breaker,
+                &archives,
+                channel_id,
+                sample_size,
+                &session,
+                slow_query_threshold,
+            )
+            .await
+            {
+                println!("Failed to reconcile channel {channel_id}: {err}");
+            }
+        }
+    }
+}
+
+/// `archives` are the collections of every bot, the main bot's first, which
+/// is where missing messages are inserted
+async fn reconcile_channel(
+    http: &Http,
+    breaker: &CircuitBreaker,
+    archives: &[mongodb::Collection<ArchivedMessage>],
+    channel_id: ChannelId,
+    sample_size: u64,
+    session: &Session,
+    slow_query_threshold: Duration,
+) -> Result<(), MainError> {
+    let fetched = channel_id
+        .messages(http, |retriever| retriever.limit(sample_size))
+        .await;
+    breaker.record(&fetched);
+    let fetched = fetched?;
+
+    let filter = doc! {
+        "id": { "$in": fetched.iter().map(|m| m.id.to_string()).collect::<Vec<_>>() },
+    };
+    let mut archived = HashSet::new();
+    for archive in archives {
+        let options = FindOptions::builder().projection(doc! { "id": 1 }).build();
+        let mut cursor = log_if_slow(slow_query_threshold, "find", filter.clone(), |filter| {
+            archive.find(filter, options)
+        })
+        .await?
+        .with_type::<ArchivedId>();
+        while cursor.advance().await? {
+            archived.insert(cursor.deserialize_current()?.id);
+        }
+    }
+
+    let missing = missing_messages(fetched, &archived);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "Archiving {} messages missing from channel {channel_id}",
+        missing.len()
+    );
+    let channel = http.get_channel(channel_id.0).await;
+    breaker.record(&channel);
+    let (channel_type, guild_id) = match channel {
+        Ok(channel) => (
+            ArchivedChannelType::from(&channel),
+            channel.guild().map(|channel| channel.guild_id),
+        ),
+        Err(err) => {
+            println!("Failed to fetch channel {channel_id}: {err}");
+            (ArchivedChannelType::Unknown, None)
+        }
+    };
+    let missing: Vec<_> = missing
+        .into_iter()
+        .map(|message| {
+            let mut archived = ArchivedMessageFull::from_gateway(message, session);
+            archived.channel_type = channel_type;
+            // Messages fetched over REST don't say which guild they're from
+            archived.guild_id = archived.guild_id.or(guild_id);
+            // We don't know what happened to it before we fetched it
+            for iteration in &mut archived.iterations {
+                iteration.may_contain_gap = true;
+            }
+            ArchivedMessage::Full(archived)
+        })
+        .collect();
+    archives[0].insert_many(missing, None).await?;
+    Ok(())
+}
+
+/// Check the channels with messages archived within `window` for messages
+/// that were deleted while we weren't listening, since Discord doesn't send
+/// those deletions after a restart
+///
+/// Only the latest `sample_size` messages of each channel are fetched, so
+/// deletions older than those go unnoticed.
+pub async fn infer_deletions_on_startup(
+    http: &Http,
+    messages: &mongodb::Collection<ArchivedMessage>,
+    breaker: &CircuitBreaker,
+    window: Duration,
+    sample_size: u64,
+) {
+    let since = Utc::now() - chrono::Duration::seconds(window.as_secs() as i64);
+    let filter = doc! {
+        "timestamp": { "$gte": bson::DateTime::from_chrono(since) },
+        "archive_type": { "$in": ["Full", "Incomplete"] },
+    };
+    let channels = match messages.distinct("channel_id", filter, None).await {
+        Ok(channels) => channels,
+        Err(err) => {
+            println!("Couldn't find recently active channels: {err}");
+            return;
+        }
+    };
+    let sample_size = sample_size.min(MAX_SAMPLE_SIZE);
+    for channel_id in channels.iter().filter_map(|id| id.as_str()?.parse().ok()) {
+        let channel_id = ChannelId(channel_id);
+        if !breaker.allow() {
+            println!("REST requests are paused, not checking channel {channel_id} for deletions");
+            continue;
+        }
+        match infer_channel_deletions(http, breaker, messages, channel_id, sample_size).await {
+            Ok(0) => {}
+            Ok(count) => println!("Marked {count} messages of channel {channel_id} as deleted"),
+            Err(err) => println!("Failed to check channel {channel_id} for deletions: {err}"),
+        }
+    }
+}
+
+/// An archived message that may turn out to be deleted
+#[derive(Deserialize)]
+struct DeletionCandidate {
+    id: MessageId,
+    archive_type: String,
+    iteration_count: i32,
+}
+
+async fn infer_channel_deletions(
+    http: &Http,
+    breaker: &CircuitBreaker,
+    messages: &mongodb::Collection<ArchivedMessage>,
+    channel_id: ChannelId,
+    sample_size: u64,
+) -> Result<u64, MainError> {
+    let fetched = channel_id
+        .messages(http, |retriever| retriever.limit(sample_size))
+        .await;
+    breaker.record(&fetched);
+    let fetched = fetched?;
+    let complete = (fetched.len() as u64) < sample_size;
+    let sent = fetched.iter().map(|m| convert_ts(m.timestamp));
+    let (Some(oldest), Some(newest)) = (sent.clone().min(), sent.max()) else {
+        // An empty channel can't be told apart from a failed fetch
+        return Ok(0);
+    };
+
+    // Only the messages the fetch can say anything about
+    let mut filter = sent_between((!complete).then_some(oldest), newest);
+    filter.insert("channel_id", channel_id.to_string());
+    filter.insert("archive_type", doc! { "$in": ["Full", "Incomplete"] });
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! {
+            "$project": {
+                "id": 1,
+                "archive_type": 1,
+                "iteration_count": { "$size": "$iterations" },
+            }
+        },
+    ];
+    let mut cursor = messages
+        .aggregate(pipeline, None)
+        .await?
+        .with_type::<DeletionCandidate>();
+    let mut count = 0;
+    while cursor.advance().await? {
+        let candidate = cursor.deserialize_current()?;
+        if !is_inferred_deletion(candidate.id, &fetched, complete) {
+            continue;
+        }
+        let deleted_type = match candidate.archive_type.as_str() {
+            "Full" => "FullDeleted",
+            "Incomplete" => "IncompleteDeleted",
+            _ => continue,
+        };
+        // Only if nothing else changed it in the meantime, an update would
+        // have added an iteration
+        let filter = doc! {
+            "id": candidate.id.to_string(),
+            "archive_type": &candidate.archive_type,
+            "iterations": { "$size": candidate.iteration_count },
+        };
+        let update = doc! {
+            "$set": {
+                "archive_type": deleted_type,
+                "deleted_timestamp": null,
+                "deletion_inferred": true,
+            }
+        };
+        let result = messages.update_one(filter, update, None).await?;
+        count += result.modified_count;
+    }
+    Ok(count)
+}
+
+/// Match messages sent within `from..=to`, or up to `to` without a `from`
+///
+/// Messages stored with the send time in milliseconds only match once
+/// migrate-timestamps has converted them.
+fn sent_between(from: Option<Timestamp>, to: Timestamp) -> Document {
+    let mut range = doc! { "$lte": bson::DateTime::from_chrono(to) };
+    if let Some(from) = from {
+        range.insert("$gte", bson::DateTime::from_chrono(from));
+    }
+    doc! { "timestamp": range }
+}
+
+/// Whether an archived message is gone from its channel, judging by the
+/// latest messages fetched from it
+///
+/// Messages older than the fetched ones can't be judged unless the fetch
+/// covered the whole channel, and ones newer than all of them may have been
+/// sent after the fetch.
+fn is_inferred_deletion(id: MessageId, fetched: &[Message], complete: bool) -> bool {
+    let (Some(newest), Some(oldest)) = (
+        fetched.iter().map(|m| m.id).max(),
+        fetched.iter().map(|m| m.id).min(),
+    ) else {
+        // An empty channel can't be told apart from a failed fetch
+        return false;
+    };
+    id <= newest && (complete || id >= oldest) && fetched.iter().all(|m| m.id != id)
+}
+
+/// The fetched messages that aren't in the archive
+fn missing_messages(fetched: Vec<Message>, archived: &HashSet<MessageId>) -> Vec<Message> {
+    fetched
+        .into_iter()
+        .filter(|message| !archived.contains(&message.id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::message;
+
+    fn fetched(ids: &[u64]) -> Vec<Message> {
+        ids.iter().map(|&id| message(id, json!({}))).collect()
+    }
+
+    #[test]
+    fn gaps_within_the_fetched_range_are_deletions() {
+        let fetched = fetched(&[10, 12, 13]);
+        assert!(is_inferred_deletion(MessageId(11), &fetched, false));
+        assert!(!is_inferred_deletion(MessageId(12), &fetched, false));
+    }
+
+    #[test]
+    fn messages_newer_than_the_fetch_are_kept() {
+        assert!(!is_inferred_deletion(
+            MessageId(14),
+            &fetched(&[10, 13]),
+            true
+        ));
+    }
+
+    #[test]
+    fn older_messages_are_only_judged_by_a_complete_fetch() {
+        let fetched = fetched(&[10, 13]);
+        assert!(!is_inferred_deletion(MessageId(9), &fetched, false));
+        assert!(is_inferred_deletion(MessageId(9), &fetched, true));
+    }
+
+    #[test]
+    fn an_empty_fetch_infers_nothing() {
+        assert!(!is_inferred_deletion(MessageId(9), &[], true));
+    }
+
+    #[test]
+    fn only_unarchived_messages_are_missing() {
+        let archived = HashSet::from([MessageId(10), MessageId(12)]);
+        let missing: Vec<_> = missing_messages(fetched(&[10, 11, 12, 13]), &archived)
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(missing, [MessageId(11), MessageId(13)]);
+    }
+
+    #[test]
+    fn nothing_is_missing_when_everything_is_archived() {
+        let archived = HashSet::from([MessageId(10), MessageId(11)]);
+        assert!(missing_messages(fetched(&[10, 11]), &archived).is_empty());
+    }
+
+    #[test]
+    fn sent_between_matches_dates() {
+        let from = Utc.timestamp_millis_opt(1_000).unwrap();
+        let to = Utc.timestamp_millis_opt(2_000).unwrap();
+        assert_eq!(
+            sent_between(Some(from), to),
+            doc! { "timestamp": {
+                "$lte": bson::DateTime::from_millis(2_000),
+                "$gte": bson::DateTime::from_millis(1_000),
+            } }
+        );
+    }
+
+    #[test]
+    fn sent_between_without_a_start_is_open_ended() {
+        let to = Utc.timestamp_millis_opt(2_000).unwrap();
+        assert_eq!(
+            sent_between(None, to),
+            doc! { "timestamp": { "$lte": bson::DateTime::from_millis(2_000) } }
+        );
+    }
+}
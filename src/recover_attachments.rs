@@ -0,0 +1,254 @@
+use bson::doc;
+use serenity::{
+    http::{Http, HttpError},
+    model::channel::Attachment,
+};
+use std::time::Duration;
+
+use crate::{
+    archived_message::{ArchivedMessage, ArchivedMessageIteration},
+    circuit_breaker::CircuitBreaker,
+    config::Config,
+    mong::{get_mong, messages_collection, to_stored_bson},
+    MainError,
+};
+
+/// What happened to the attachments of one message
+enum Recovery {
+    Refreshed(usize),
+    Lost,
+    Unchanged,
+}
+
+/// Go through every archived message with attachments, in the collections of
+/// all bots, refreshing the expired CDN links of messages that still exist and
+/// marking the attachments of deleted ones as lost
+///
+/// Attachments we never downloaded can't be brought back once the message is
+/// gone, the marker tells them apart from ones that just weren't fetched
+/// yet. Ones stored in GridFS aren't lost.
+pub async fn run(config: Config) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let http = Http::new(&config.discor_token);
+    let breaker = CircuitBreaker::new(
+        config.rest_breaker_threshold,
+        Duration::from_secs(config.rest_breaker_cooldown_secs),
+    );
+
+    let (mut refreshed, mut lost) = (0u64, 0u64);
+    for location in config.message_locations() {
+        let messages = messages_collection(&mong, &location);
+        let filter = doc! {
+            "iterations.attachments.0": { "$exists": true },
+        };
+        let mut cursor = messages.find(filter, None).await?;
+        while cursor.advance().await? {
+            let mut message = cursor.deserialize_current()?;
+            let id = message.id();
+            let recovery = match &mut message {
+                ArchivedMessage::Full(m) => {
+                    if !breaker.allow() {
+                        println!("REST requests are paused, skipping message {id}");
+                        continue;
+                    }
+                    let fetched = http.get_message(m.channel_id.0, id.0).await;
+                    breaker.record(&fetched);
+                    match fetched {
+                        Ok(fresh) => match m.iterations.last_mut() {
+                            Some(latest) => {
+                                Recovery::Refreshed(refresh_urls(latest, &fresh.attachments))
+                            }
+                            None => Recovery::Unchanged,
+                        },
+                        // Deleted while we weren't listening
+                        Err(err) if is_not_found(&err) => mark_lost(&mut m.iterations),
+                        Err(err) => {
+                            println!("Couldn't fetch message {id}: {err}");
+                            Recovery::Unchanged
+                        }
+                    }
+                }
+                ArchivedMessage::FullDeleted(m) => mark_lost(&mut m.iterations),
+                ArchivedMessage::IncompleteDeleted(m) => mark_lost(&mut m.iterations),
+                // Without a channel to fetch from in the archive there's
+                // nothing to do for the rest
+                ArchivedMessage::Incomplete(_) | ArchivedMessage::UnknownDeleted(_) => {
+                    Recovery::Unchanged
+                }
+            };
+            let iterations = match &message {
+                ArchivedMessage::Full(m) => &m.iterations,
+                ArchivedMessage::FullDeleted(m) => &m.iterations,
+                ArchivedMessage::IncompleteDeleted(m) => &m.iterations,
+                _ => continue,
+            };
+            match recovery {
+                Recovery::Refreshed(0) | Recovery::Unchanged => continue,
+                Recovery::Refreshed(count) => {
+                    println!("Refreshed {count} attachment links of message {id}");
+                    refreshed += 1;
+                }
+                Recovery::Lost => {
+                    println!("Marked the attachments of message {id} as lost");
+                    lost += 1;
+                }
+            }
+            let iterations = to_stored_bson(iterations).map_err(mongodb::error::Error::from)?;
+            messages
+                .update_one(
+                    doc! { "id": id.to_string() },
+                    doc! { "$set": { "iterations": iterations } },
+                    None,
+                )
+                .await?;
+        }
+    }
+
+    println!("Refreshed links of {refreshed} messages, marked attachments of {lost} as lost");
+    Ok(())
+}
+
+/// Take over the fresh links of the attachments that are still there
+fn refresh_urls(iteration: &mut ArchivedMessageIteration, fresh: &[Attachment]) -> usize {
+    let mut count = 0;
+    for attachment in &mut iteration.attachments {
+        if let Some(fresh) = fresh.iter().find(|f| f.id == attachment.id) {
+            if fresh.url != attachment.url || fresh.proxy_url != attachment.proxy_url {
+                attachment.url = fresh.url.clone();
+                attachment.proxy_url = fresh.proxy_url.clone();
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Record the attachments of a deleted message as lost for good, unless
+/// they were downloaded into GridFS
+fn mark_lost(iterations: &mut [ArchivedMessageIteration]) -> Recovery {
+    let mut changed = false;
+    for iteration in iterations {
+        let lost: Vec<_> = iteration
+            .attachments
+            .iter()
+            .map(|a| a.id)
+            .filter(|id| {
+                !iteration
+                    .stored_attachments
+                    .iter()
+                    .any(|s| s.attachment_id == *id)
+            })
+            .collect();
+        if iteration.lost_attachments != lost {
+            iteration.lost_attachments = lost;
+            changed = true;
+        }
+    }
+    if changed {
+        Recovery::Lost
+    } else {
+        Recovery::Unchanged
+    }
+}
+
+fn is_not_found(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => matches!(
+            err.as_ref(),
+            HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 404
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use serenity::model::id::AttachmentId;
+
+    use super::*;
+    use crate::{
+        archived_message::ArchivedMessageFull, attachment_download::StoredAttachment,
+        session::Session, test_util,
+    };
+
+    fn with_attachments(ids: &[u64]) -> ArchivedMessageFull {
+        let attachments: Vec<_> = ids.iter().map(|&id| test_util::attachment(id)).collect();
+        let message = test_util::message(1, json!({ "attachments": attachments }));
+        ArchivedMessageFull::from_gateway(message, &Session::new(None))
+    }
+
+    #[test]
+    fn attachments_of_deleted_messages_are_marked_lost() {
+        let mut archived = with_attachments(&[5]);
+        let mut edited = with_attachments(&[5, 6]).iterations.remove(0);
+        edited.content = "added one".to_string();
+        archived.iterations.push(edited);
+        let mut deleted = archived.into_deleted(None);
+
+        assert!(matches!(mark_lost(&mut deleted.iterations), Recovery::Lost));
+        assert_eq!(deleted.iterations[0].lost_attachments, [AttachmentId(5)]);
+        assert_eq!(
+            deleted.iterations[1].lost_attachments,
+            [AttachmentId(5), AttachmentId(6)]
+        );
+    }
+
+    #[test]
+    fn messages_without_attachments_are_left_alone() {
+        let mut archived = with_attachments(&[]);
+        assert!(matches!(
+            mark_lost(&mut archived.iterations),
+            Recovery::Unchanged
+        ));
+        assert!(archived.iterations[0].lost_attachments.is_empty());
+    }
+
+    #[test]
+    fn only_changed_links_are_refreshed() {
+        let mut archived = with_attachments(&[5, 6]);
+        let mut fresh: Vec<Attachment> = [5, 6, 7]
+            .into_iter()
+            .map(|id| serde_json::from_value(test_util::attachment(id)).unwrap())
+            .collect();
+        fresh[0].url = "https://cdn.discordapp.com/cat.png?ex=fresh".to_string();
+        assert_eq!(refresh_urls(&mut archived.iterations[0], &fresh), 1);
+        let urls: Vec<_> = archived.iterations[0]
+            .attachments
+            .iter()
+            .map(|a| a.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://cdn.discordapp.com/cat.png?ex=fresh",
+                "https://cdn.discordapp.com/cat.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn stored_attachments_are_not_lost() {
+        let message = test_util::message(
+            1,
+            json!({ "attachments": [test_util::attachment(5), test_util::attachment(6)] }),
+        );
+        let mut archived = ArchivedMessageFull::from_gateway(message, &Session::new(None));
+        archived.iterations[0]
+            .stored_attachments
+            .push(StoredAttachment {
+                attachment_id: AttachmentId(5),
+                file_id: bson::oid::ObjectId::new(),
+            });
+        assert!(matches!(
+            mark_lost(&mut archived.iterations),
+            Recovery::Lost
+        ));
+        assert_eq!(archived.iterations[0].lost_attachments, [AttachmentId(6)]);
+        // Already marked
+        assert!(matches!(
+            mark_lost(&mut archived.iterations),
+            Recovery::Unchanged
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use bson::{doc, oid::ObjectId, Bson, Document};
+use chrono::{Duration, Utc};
+use mongodb::options::FindOptions;
+
+use crate::{
+    archived_message::Timestamp,
+    config::{Config, ConfigLoadSaveError},
+    mong::{attachments_bucket, get_mong, messages_collection, ATTACHMENTS},
+    MainError,
+};
+
+/// Blank the content, attachments and embeds of every message sent longer
+/// than `redact_content_after_days` ago, keeping ids, timestamps, authors
+/// and types for statistics, in the collections of all bots
+///
+/// Messages whose timestamp is still stored as milliseconds are only
+/// matched after running migrate-timestamps. Content is set to an empty string
+/// rather than removed, so the documents still read as archived messages.
+/// Redacted messages get a `content_redacted_at` marker and are skipped on
+/// later runs. Attachments downloaded into GridFS are deleted along with
+/// the links to them.
+pub async fn run(config: Config) -> Result<(), MainError> {
+    let days = config
+        .redact_content_after_days
+        .ok_or(ConfigLoadSaveError::Missing {
+            field: "redact_content_after_days",
+        })?;
+    let cutoff = Utc::now() - Duration::days(days as i64);
+    let mong = get_mong(&config.mong_connstring).await?;
+
+    let (filter, update) = redaction(cutoff, Utc::now());
+    let bucket = attachments_bucket(&mong, &config.collection_location(ATTACHMENTS));
+    for location in config.message_locations() {
+        let messages = messages_collection(&mong, &location);
+
+        // Found first, the update takes the links to them
+        let mut with_files = filter.clone();
+        with_files.insert("iterations.stored_attachments.0", doc! { "$exists": true });
+        let options = FindOptions::builder()
+            .projection(doc! { "iterations.stored_attachments.file_id": 1 })
+            .build();
+        let mut cursor = messages
+            .clone_with_type::<Document>()
+            .find(with_files, options)
+            .await?;
+        let mut files = Vec::new();
+        while cursor.advance().await? {
+            files.extend(stored_file_ids(&cursor.deserialize_current()?));
+        }
+        files.sort();
+        files.dedup();
+
+        let result = messages
+            .update_many(filter.clone(), update.clone(), None)
+            .await?;
+        println!(
+            "Redacted the content of {} messages in {} sent before {cutoff}",
+            result.modified_count, location.collection
+        );
+
+        let mut deleted = 0u64;
+        for file_id in files {
+            match bucket.delete(Bson::ObjectId(file_id)).await {
+                Ok(()) => deleted += 1,
+                Err(err) => println!("Failed to delete attachment file {file_id}: {err}"),
+            }
+        }
+        println!("Deleted {deleted} attachment files of redacted messages");
+    }
+    Ok(())
+}
+
+/// The filter matching the messages sent before `cutoff` that weren't
+/// redacted yet, and the update pipeline blanking their content
+fn redaction(cutoff: Timestamp, redacted_at: Timestamp) -> (Document, Vec<Document>) {
+    let filter = doc! {
+        "timestamp": { "$lt": bson::DateTime::from_chrono(cutoff) },
+        "content_redacted_at": { "$exists": false },
+        "archive_type": { "$ne": "UnknownDeleted" },
+    };
+    let blanked = doc! {
+        "content": "",
+        "normalized_content": null,
+        "effective_content": null,
+        "attachments": [],
+        "embeds": [],
+        "voice_attachments": [],
+        "stored_attachments": [],
+    };
+    let update = vec![doc! {
+        "$set": {
+            "iterations": {
+                "$map": {
+                    "input": "$iterations",
+                    "in": { "$mergeObjects": ["$$this", blanked] },
+                }
+            },
+            "referenced_message": {
+                "$cond": [
+                    { "$eq": [{ "$type": "$referenced_message" }, "object"] },
+                    { "$mergeObjects": ["$referenced_message", { "content": "" }] },
+                    "$referenced_message",
+                ]
+            },
+            "content_redacted_at": bson::DateTime::from_chrono(redacted_at),
+        }
+    }];
+    (filter, update)
+}
+
+/// The GridFS files of the attachments stored for a message, from a
+/// document projected to them
+fn stored_file_ids(message: &Document) -> Vec<ObjectId> {
+    let Ok(iterations) = message.get_array("iterations") else {
+        return vec![];
+    };
+    iterations
+        .iter()
+        .filter_map(Bson::as_document)
+        .filter_map(|iteration| iteration.get_array("stored_attachments").ok())
+        .flatten()
+        .filter_map(Bson::as_document)
+        .filter_map(|stored| stored.get_object_id("file_id").ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use serde_json::json;
+    use serenity::model::id::{ChannelId, MessageId, UserId};
+
+    use super::*;
+    use crate::{
+        archived_message::{ArchivedMessage, ArchivedMessageFull, ArchivedMessageUnknownDeleted},
+        session::Session,
+        test_util,
+    };
+
+    #[test]
+    fn file_ids_of_every_iteration_are_found() {
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        let message = doc! {
+            "iterations": [
+                { "stored_attachments": [{ "file_id": first }] },
+                { "stored_attachments": [{ "file_id": first }, { "file_id": second }] },
+                {},
+            ],
+        };
+        assert_eq!(stored_file_ids(&message), [first, first, second]);
+    }
+
+    #[test]
+    fn messages_without_iterations_have_no_files() {
+        assert!(stored_file_ids(&doc! {}).is_empty());
+    }
+
+    /// Needs a server to run against, like
+    /// `ISWYD_TEST_MONGO_CONNSTRING=mongodb://localhost cargo test --
+    /// --ignored`
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server in ISWYD_TEST_MONGO_CONNSTRING"]
+    async fn old_content_is_blanked_and_metadata_kept() {
+        let connstring = std::env::var("ISWYD_TEST_MONGO_CONNSTRING").unwrap();
+        let mong = get_mong(&connstring).await.unwrap();
+        let messages = mong
+            .database("iswyd_test")
+            .collection::<ArchivedMessage>(&format!("messages_{}", uuid::Uuid::new_v4()));
+
+        let session = Session::new(None);
+        let full = |id, overrides| {
+            ArchivedMessage::Full(ArchivedMessageFull::from_gateway(
+                test_util::message(id, overrides),
+                &session,
+            ))
+        };
+        let stored = [
+            // Sent in 2023
+            full(
+                1,
+                json!({
+                    "content": "old",
+                    "attachments": [test_util::attachment(5)],
+                    "embeds": [{ "type": "rich", "title": "Embedded" }],
+                }),
+            ),
+            full(
+                2,
+                json!({ "content": "recent", "timestamp": Utc::now().to_rfc3339() }),
+            ),
+            ArchivedMessage::UnknownDeleted(ArchivedMessageUnknownDeleted {
+                id: MessageId(3),
+                channel_id: ChannelId(20),
+                guild_id: None,
+                deleted_timestamp: None,
+                channel_deleted_at: None,
+                archive_stopped_at: None,
+            }),
+        ];
+        messages.insert_many(&stored, None).await.unwrap();
+
+        let redacted_at = Utc::now();
+        let (filter, update) = redaction(redacted_at - Duration::days(30), redacted_at);
+        let result = messages.update_many(filter, update, None).await;
+        let after: Vec<Document> = messages
+            .clone_with_type::<Document>()
+            .find(None, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        messages.drop(None).await.unwrap();
+
+        assert_eq!(result.unwrap().modified_count, 1);
+        let find = |id: &str| {
+            let stored = after.iter().find(|m| m.get_str("id") == Ok(id)).unwrap();
+            (
+                stored.contains_key("content_redacted_at"),
+                bson::from_document::<ArchivedMessage>(stored.clone()).unwrap(),
+            )
+        };
+
+        let (marked, ArchivedMessage::Full(old)) = find("1") else {
+            panic!("expected the old message to stay full");
+        };
+        assert!(marked);
+        assert_eq!(old.author_id, UserId(100));
+        assert_eq!(old.timestamp.timestamp_millis(), 1_700_000_000_000);
+        let iteration = &old.iterations[0];
+        assert_eq!(iteration.content, "");
+        assert!(iteration.attachments.is_empty());
+        assert!(iteration.embeds.is_empty());
+
+        let (marked, ArchivedMessage::Full(recent)) = find("2") else {
+            panic!("expected the recent message to stay full");
+        };
+        assert!(!marked);
+        assert_eq!(recent.iterations[0].content, "recent");
+        // Nothing to redact
+        assert!(!find("3").0);
+    }
+}
@@ -0,0 +1,231 @@
+use chrono::serde::ts_milliseconds;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    model::{
+        channel::{Message, MessageFlags, MessageType},
+        id::{ChannelId, GuildId, MessageId, UserId},
+    },
+};
+
+use crate::archived_message::{convert_ts, Timestamp};
+
+/// What a referenced message looked like when the message referencing it was
+/// archived
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReferencedMessage {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub author_id: UserId,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: Timestamp,
+    pub content: String,
+}
+
+impl From<&Message> for ReferencedMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            id: message.id,
+            channel_id: message.channel_id,
+            author_id: message.author.id,
+            timestamp: convert_ts(message.timestamp),
+            content: message.content.clone(),
+        }
+    }
+}
+
+/// Where a crossposted announcement was originally posted
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CrosspostSource {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+}
+
+impl CrosspostSource {
+    /// `None` for everything but crossposts, including replies, which
+    /// reference a message in the same guild
+    pub fn from_message(message: &Message) -> Option<Self> {
+        let reference = message.message_reference.as_ref()?;
+        let guild_id = reference.guild_id?;
+        let is_crosspost = message
+            .flags
+            .is_some_and(|flags| flags.contains(MessageFlags::IS_CROSSPOST));
+        if !is_crosspost && message.guild_id == Some(guild_id) {
+            return None;
+        }
+        Some(Self {
+            guild_id,
+            channel_id: reference.channel_id,
+            message_id: reference.message_id?,
+        })
+    }
+
+    pub fn link(&self) -> String {
+        format!(
+            "https://discord.com/channels/{}/{}/{}",
+            self.guild_id, self.channel_id, self.message_id
+        )
+    }
+}
+
+/// Where to find the message a message refers to
+#[derive(Debug)]
+enum Lookup {
+    /// Discord sent it along
+    Included(ReferencedMessage),
+    Fetch {
+        channel_id: ChannelId,
+        message_id: MessageId,
+    },
+}
+
+/// Decide how to find the message `message` refers to
+///
+/// - Replies usually come with the message they reply to, otherwise it's
+///   fetched from the reply's channel
+/// - Thread starter messages point at the message the thread was created from,
+///   which lives in the parent channel
+/// - Other references, like pins and channel follows, aren't resolved
+fn reference_lookup(message: &Message) -> Option<Lookup> {
+    let reference = message.message_reference.as_ref()?;
+    match message.kind {
+        MessageType::InlineReply => {
+            if let Some(referenced) = &message.referenced_message {
+                return Some(Lookup::Included(referenced.as_ref().into()));
+            }
+        }
+        MessageType::ThreadStarterMessage => {}
+        _ => return None,
+    }
+    Some(Lookup::Fetch {
+        channel_id: reference.channel_id,
+        message_id: reference.message_id?,
+    })
+}
+
+/// Look up the message a message refers to, see [`reference_lookup`] for how
+///
+/// `None` if there's nothing to resolve or the message is gone.
+pub async fn resolve_reference(http: &Http, message: &Message) -> Option<ReferencedMessage> {
+    let (channel_id, message_id) = match reference_lookup(message)? {
+        Lookup::Included(referenced) => return Some(referenced),
+        Lookup::Fetch {
+            channel_id,
+            message_id,
+        } => (channel_id, message_id),
+    };
+    match http.get_message(channel_id.0, message_id.0).await {
+        Ok(referenced) => Some((&referenced).into()),
+        Err(err) => {
+            println!(
+                "Couldn't resolve message {message_id} referenced by {}: {err}",
+                message.id
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util;
+
+    fn referencing(kind: u8, channel_id: &str, extra: serde_json::Value) -> Message {
+        let mut overrides = json!({
+            "type": kind,
+            "message_reference": {
+                "message_id": "5",
+                "channel_id": channel_id,
+                "guild_id": "30",
+            },
+        });
+        if let (Some(overrides), Some(extra)) = (overrides.as_object_mut(), extra.as_object()) {
+            overrides.extend(extra.clone());
+        }
+        test_util::message(10, overrides)
+    }
+
+    #[test]
+    fn replies_use_the_message_discord_sent_along() {
+        let replied_to =
+            serde_json::to_value(test_util::message(5, json!({ "content": "original" }))).unwrap();
+        let reply = referencing(19, "20", json!({ "referenced_message": replied_to }));
+        match reference_lookup(&reply) {
+            Some(Lookup::Included(referenced)) => {
+                assert_eq!(referenced.id, MessageId(5));
+                assert_eq!(referenced.author_id, UserId(100));
+                assert_eq!(referenced.content, "original");
+            }
+            other => panic!("expected the included message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn replies_without_the_message_fetch_it_from_their_channel() {
+        let reply = referencing(19, "20", json!({}));
+        assert!(matches!(
+            reference_lookup(&reply),
+            Some(Lookup::Fetch {
+                channel_id: ChannelId(20),
+                message_id: MessageId(5),
+            })
+        ));
+    }
+
+    #[test]
+    fn thread_starters_fetch_from_the_parent_channel() {
+        // Sent in the thread, 20, pointing at the message in the parent, 21
+        let starter = referencing(21, "21", json!({}));
+        assert!(matches!(
+            reference_lookup(&starter),
+            Some(Lookup::Fetch {
+                channel_id: ChannelId(21),
+                message_id: MessageId(5),
+            })
+        ));
+    }
+
+    #[test]
+    fn other_references_are_not_resolved() {
+        let pin = referencing(6, "20", json!({}));
+        assert!(reference_lookup(&pin).is_none());
+        assert!(reference_lookup(&test_util::message(10, json!({ "type": 19 }))).is_none());
+    }
+
+    #[test]
+    fn crossposts_keep_their_source() {
+        // Flag 2 is IS_CROSSPOST, the source is in another guild
+        let crosspost = test_util::message(
+            10,
+            json!({
+                "flags": 2,
+                "message_reference": { "message_id": "5", "channel_id": "41", "guild_id": "40" },
+            }),
+        );
+        let source = CrosspostSource::from_message(&crosspost).unwrap();
+        assert_eq!(
+            source,
+            CrosspostSource {
+                guild_id: GuildId(40),
+                channel_id: ChannelId(41),
+                message_id: MessageId(5),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&source).unwrap(),
+            json!({ "guild_id": "40", "channel_id": "41", "message_id": "5" })
+        );
+        assert_eq!(source.link(), "https://discord.com/channels/40/41/5");
+    }
+
+    #[test]
+    fn same_guild_replies_are_not_crossposts() {
+        let reply = referencing(19, "20", json!({}));
+        assert!(CrosspostSource::from_message(&reply).is_none());
+        assert!(CrosspostSource::from_message(&test_util::message(10, json!({}))).is_none());
+    }
+}
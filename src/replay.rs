@@ -0,0 +1,167 @@
+use bson::doc;
+use mongodb::options::AggregateOptions;
+use serenity::{
+    http::Http,
+    model::id::{ChannelId, UserId},
+};
+use std::time::Duration;
+
+use crate::{
+    archived_message::{ArchivedMessage, ArchivedMessageIteration, Timestamp},
+    config::Config,
+    mong::{get_mong, messages_collection, sort_by_timestamp, MESSAGES},
+    MainError,
+};
+
+/// Post the archived messages of a channel through a webhook, in the order
+/// they were sent, recreating the conversation
+///
+/// With a `speed` the original gaps between messages are kept, divided by
+/// it, otherwise the messages are posted back to back. Without a user cache
+/// authors are shown by id, and attachments are linked rather than uploaded
+/// again since their files aren't archived. Mentions never ping anyone.
+/// Messages Discord refuses, like ones over the length limit, are logged and
+/// skipped.
+pub async fn run(
+    config: Config,
+    channel_id: ChannelId,
+    webhook_url: &str,
+    speed: Option<f64>,
+) -> Result<(), MainError> {
+    if speed.is_some_and(|speed| !is_usable_speed(speed)) {
+        return Err(MainError::InvalidArg {
+            arg: "--speed",
+            problem: "has to be a number of at least 0.001",
+        });
+    }
+    let mong = get_mong(&config.mong_connstring).await?;
+    let http = Http::new(&config.discor_token);
+    let webhook = http.get_webhook_from_url(webhook_url).await?;
+
+    let filter = doc! {
+        "channel_id": channel_id.to_string(),
+        "archive_type": { "$ne": "UnknownDeleted" },
+    };
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(sort_by_timestamp(1));
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let mut cursor = messages_collection(&mong, &config.collection_location(MESSAGES))
+        .aggregate(pipeline, options)
+        .await?
+        .with_type::<ArchivedMessage>();
+
+    let mut previous = None;
+    let (mut count, mut failed) = (0u64, 0u64);
+    while cursor.advance().await? {
+        let message = cursor.deserialize_current()?;
+        let Some((author_id, timestamp, iteration)) = replayable(&message) else {
+            continue;
+        };
+        let content = replay_content(iteration);
+        if content.is_empty() {
+            continue;
+        }
+        if let Some(previous) = previous {
+            tokio::time::sleep(replay_delay(previous, timestamp, speed)).await;
+        }
+        previous = Some(timestamp);
+
+        let username = match author_id {
+            Some(author_id) => format!("User {author_id}"),
+            None => "Unknown user".to_string(),
+        };
+        let executed = webhook
+            .execute(&http, false, |w| {
+                w.username(username)
+                    .content(content)
+                    .allowed_mentions(|m| m.empty_parse())
+            })
+            .await;
+        match executed {
+            Ok(_) => count += 1,
+            Err(err) => {
+                println!(
+                    "Failed to replay message {}, skipping it: {err}",
+                    message.id()
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    println!("Replayed {count} messages of channel {channel_id}, {failed} failed");
+    Ok(())
+}
+
+/// The author, send time and latest version of a message, `None` for
+/// messages we know nothing about
+fn replayable(
+    message: &ArchivedMessage,
+) -> Option<(Option<UserId>, Timestamp, &ArchivedMessageIteration)> {
+    match message {
+        ArchivedMessage::Full(m) => Some((Some(m.author_id), m.timestamp, m.iterations.last()?)),
+        ArchivedMessage::FullDeleted(m) => {
+            Some((Some(m.author_id), m.timestamp, m.iterations.last()?))
+        }
+        ArchivedMessage::Incomplete(m) => Some((m.author_id, m.timestamp, m.iterations.last()?)),
+        ArchivedMessage::IncompleteDeleted(m) => {
+            Some((m.author_id, m.timestamp, m.iterations.last()?))
+        }
+        ArchivedMessage::UnknownDeleted(_) => None,
+    }
+}
+
+/// The content followed by links to the attachments
+fn replay_content(iteration: &ArchivedMessageIteration) -> String {
+    std::iter::once(iteration.content.as_str())
+        .chain(iteration.attachments.iter().map(|a| a.url.as_str()))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Slower speeds would wait for ages between messages
+const MIN_SPEED: f64 = 0.001;
+
+fn is_usable_speed(speed: f64) -> bool {
+    speed.is_finite() && speed >= MIN_SPEED
+}
+
+/// How long to wait before posting a message sent at `current`, after one
+/// sent at `previous`
+fn replay_delay(previous: Timestamp, current: Timestamp, speed: Option<f64>) -> Duration {
+    let Some(speed) = speed.filter(|speed| *speed > 0.0) else {
+        return Duration::ZERO;
+    };
+    let gap = (current - previous).to_std().unwrap_or_default();
+    gap.div_f64(speed)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn rejects_unusable_speeds() {
+        for speed in [0.0, -1.0, 1e-12, f64::NAN, f64::INFINITY] {
+            assert!(!is_usable_speed(speed), "{speed} was accepted");
+        }
+        assert!(is_usable_speed(MIN_SPEED));
+        assert!(is_usable_speed(60.0));
+    }
+
+    #[test]
+    fn delay_is_gap_divided_by_speed() {
+        let previous = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let current = previous + chrono::Duration::seconds(60);
+        assert_eq!(replay_delay(previous, current, None), Duration::ZERO);
+        assert_eq!(
+            replay_delay(previous, current, Some(2.0)),
+            Duration::from_secs(30)
+        );
+        // Messages out of order don't make us wait
+        assert_eq!(replay_delay(current, previous, Some(2.0)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,135 @@
+use serenity::model::id::MessageId;
+use std::collections::HashMap;
+
+/// A message in reply order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Nested {
+    /// Where the message is in the list given to [`reply_order`]
+    pub index: usize,
+    /// How many replies deep it is, 0 for messages that don't reply to one
+    /// in the list
+    pub depth: usize,
+}
+
+/// Order messages so replies come right after what they reply to, each
+/// reply tree depth first
+///
+/// Takes every message's id and the message it replies to, in the order
+/// they were sent, which is kept among siblings and trees. Replies to
+/// messages that aren't in the list start trees of their own. Replies can't
+/// form cycles on Discord, but a broken archive could have them, so the
+/// first message sent that no tree reaches starts one too.
+pub fn reply_order(messages: &[(MessageId, Option<MessageId>)]) -> Vec<Nested> {
+    let index_of: HashMap<MessageId, usize> = messages
+        .iter()
+        .enumerate()
+        .map(|(index, &(id, _))| (id, index))
+        .collect();
+    let parent: Vec<Option<usize>> = messages
+        .iter()
+        .map(|&(id, reply_to)| {
+            reply_to
+                .filter(|&reply_to| reply_to != id)
+                .and_then(|reply_to| index_of.get(&reply_to).copied())
+        })
+        .collect();
+    let mut children = vec![Vec::new(); messages.len()];
+    for (index, parent) in parent.iter().enumerate() {
+        if let Some(parent) = *parent {
+            children[parent].push(index);
+        }
+    }
+
+    let mut is_root: Vec<bool> = parent.iter().map(Option::is_none).collect();
+    // Whatever isn't reachable from a root hangs off a cycle
+    let mut reached = vec![false; messages.len()];
+    for index in 0..messages.len() {
+        if is_root[index] {
+            mark_reached(index, &children, &is_root, &mut reached);
+        }
+    }
+    for index in 0..messages.len() {
+        if !reached[index] {
+            is_root[index] = true;
+            mark_reached(index, &children, &is_root, &mut reached);
+        }
+    }
+
+    let mut order = Vec::with_capacity(messages.len());
+    for root in (0..messages.len()).filter(|&index| is_root[index]) {
+        let mut stack = vec![Nested {
+            index: root,
+            depth: 0,
+        }];
+        while let Some(nested) = stack.pop() {
+            order.push(nested);
+            for &child in children[nested.index].iter().rev() {
+                if !is_root[child] {
+                    stack.push(Nested {
+                        index: child,
+                        depth: nested.depth + 1,
+                    });
+                }
+            }
+        }
+    }
+    order
+}
+
+fn mark_reached(root: usize, children: &[Vec<usize>], is_root: &[bool], reached: &mut [bool]) {
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        if reached[index] {
+            continue;
+        }
+        reached[index] = true;
+        stack.extend(children[index].iter().filter(|&&child| !is_root[child]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ordered(messages: &[(u64, Option<u64>)]) -> Vec<(u64, usize)> {
+        let messages: Vec<_> = messages
+            .iter()
+            .map(|&(id, reply_to)| (MessageId(id), reply_to.map(MessageId)))
+            .collect();
+        reply_order(&messages)
+            .into_iter()
+            .map(|nested| (messages[nested.index].0 .0, nested.depth))
+            .collect()
+    }
+
+    #[test]
+    fn replies_are_nested_under_what_they_reply_to() {
+        let order = ordered(&[
+            (1, None),
+            (2, None),
+            (3, Some(1)),
+            (4, Some(3)),
+            (5, Some(2)),
+            (6, Some(1)),
+        ]);
+        assert_eq!(order, [(1, 0), (3, 1), (4, 2), (6, 1), (2, 0), (5, 1)]);
+    }
+
+    #[test]
+    fn replies_to_missing_messages_start_a_tree() {
+        let order = ordered(&[(1, None), (2, Some(99)), (3, Some(2))]);
+        assert_eq!(order, [(1, 0), (2, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn cycles_are_broken_at_their_first_message() {
+        let order = ordered(&[
+            (1, None),
+            (2, Some(4)),
+            (3, Some(2)),
+            (4, Some(3)),
+            (5, Some(5)),
+        ]);
+        assert_eq!(order, [(1, 0), (2, 0), (3, 1), (4, 2), (5, 0)]);
+    }
+}
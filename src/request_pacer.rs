@@ -0,0 +1,79 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Spaces out the REST requests of every task sharing it, so together they
+/// make at most a configured number a second
+///
+/// Serenity only waits once Discord says a bucket is used up, this keeps
+/// long running work like backfilling from getting there.
+pub struct RequestPacer {
+    /// `None` doesn't limit requests
+    interval: Option<Duration>,
+    /// When the next request may be made
+    next: Mutex<Instant>,
+}
+
+impl RequestPacer {
+    /// At most `per_sec` requests a second, 0 for no limit
+    pub fn new(per_sec: u32) -> Self {
+        Self {
+            interval: (per_sec > 0).then(|| Duration::from_secs(1) / per_sec),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the next request may be made
+    pub async fn wait(&self) {
+        let at = self.reserve(Instant::now());
+        tokio::time::sleep_until(at.into()).await;
+    }
+
+    /// Take the next free slot at or after `now`
+    fn reserve(&self, now: Instant) -> Instant {
+        let Some(interval) = self.interval else {
+            return now;
+        };
+        let mut next = self.next.lock().expect("pacer lock poisoned");
+        let at = (*next).max(now);
+        *next = at + interval;
+        at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_at_once_are_spaced_out() {
+        let pacer = RequestPacer::new(10);
+        let now = Instant::now();
+        let slots: Vec<_> = (0..3).map(|_| pacer.reserve(now) - now).collect();
+        assert_eq!(
+            slots,
+            [
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_quiet_pacer_doesnt_make_up_for_lost_time() {
+        let pacer = RequestPacer::new(10);
+        let later = Instant::now() + Duration::from_secs(5);
+        assert_eq!(pacer.reserve(later), later);
+        assert_eq!(pacer.reserve(later), later + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn zero_doesnt_limit() {
+        let pacer = RequestPacer::new(0);
+        let now = Instant::now();
+        assert_eq!(pacer.reserve(now), now);
+        assert_eq!(pacer.reserve(now), now);
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{serde::ts_milliseconds, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    guild::Role,
+    id::{GuildId, RoleId},
+};
+
+use crate::archived_message::{ts_bson_datetime_option, Timestamp};
+
+/// The latest known state of a role, kept after the role is deleted so
+/// role ids stored elsewhere can still be named
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CachedRole {
+    pub id: RoleId,
+    pub guild_id: GuildId,
+    pub name: String,
+    pub color: u32,
+    pub position: i64,
+    pub permissions: u64,
+    #[serde(with = "ts_milliseconds")]
+    pub updated_at: Timestamp,
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub deleted_at: Option<Timestamp>,
+}
+
+impl From<Role> for CachedRole {
+    fn from(role: Role) -> Self {
+        Self {
+            id: role.id,
+            guild_id: role.guild_id,
+            name: role.name,
+            color: role.colour.0,
+            position: role.position,
+            permissions: role.permissions.bits(),
+            updated_at: Utc::now(),
+            deleted_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mong::to_stored_document;
+
+    #[test]
+    fn stores_the_role_record() {
+        let role: Role = serde_json::from_value(json!({
+            "id": "5",
+            "guild_id": "30",
+            "color": 0xff0000,
+            "hoist": true,
+            "managed": false,
+            "name": "Moderators",
+            "permissions": "8",
+            "position": 3,
+            "icon": null,
+            "unicode_emoji": null,
+        }))
+        .unwrap();
+        let mut cached = CachedRole::from(role);
+        assert_eq!(cached.deleted_at, None);
+        let deleted_at = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        cached.deleted_at = Some(deleted_at);
+
+        let stored = to_stored_document(&cached).unwrap();
+        assert_eq!(stored.get_str("id"), Ok("5"));
+        assert_eq!(stored.get_str("guild_id"), Ok("30"));
+        assert_eq!(stored.get_str("name"), Ok("Moderators"));
+        assert_eq!(stored.get_i64("color"), Ok(0xff0000));
+        assert_eq!(stored.get_i64("position"), Ok(3));
+        assert_eq!(stored.get_i64("permissions"), Ok(8));
+        assert_eq!(
+            stored.get_datetime("deleted_at"),
+            Ok(&bson::DateTime::from_millis(1_700_000_000_000))
+        );
+
+        let read: CachedRole = bson::from_document(stored).unwrap();
+        assert_eq!(read.id, RoleId(5));
+        assert_eq!(read.permissions, 8);
+        assert_eq!(read.deleted_at, Some(deleted_at));
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::{serde::ts_milliseconds, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+use crate::archived_message::{ts_bson_datetime_option, Timestamp};
+
+/// One run of the archiver, iterations saved by different sessions may have
+/// missed updates in between
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub session_id: Uuid,
+    /// Set by the operator to tell runs apart, like "post-migration"
+    pub label: Option<String>,
+    #[serde(with = "ts_milliseconds")]
+    pub started_at: Timestamp,
+    /// Only set when the session was shut down cleanly
+    #[serde(default, with = "ts_bson_datetime_option")]
+    pub ended_at: Option<Timestamp>,
+    #[serde(default)]
+    pub summary: Option<SessionSummary>,
+}
+
+/// What happened during a session, counted over all bots
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SessionSummary {
+    pub created: u64,
+    pub updated: u64,
+    pub deleted: u64,
+    pub skipped: u64,
+    pub errored: u64,
+    pub gap_flagged: u64,
+    pub duration_secs: u64,
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Session lasted {}s: {} created, {} updated, {} deleted, {} skipped, {} errored, {} gap-flagged iterations",
+            self.duration_secs,
+            self.created,
+            self.updated,
+            self.deleted,
+            self.skipped,
+            self.errored,
+            self.gap_flagged
+        )
+    }
+}
+
+impl Session {
+    pub fn new(label: Option<String>) -> Self {
+        Self {
+            session_id: Uuid::new_v4(),
+            label,
+            started_at: Utc::now(),
+            ended_at: None,
+            summary: None,
+        }
+    }
+}
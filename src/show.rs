@@ -0,0 +1,240 @@
+use bson::doc;
+use serenity::model::id::MessageId;
+use std::{io::IsTerminal, time::Duration};
+
+use crate::{
+    archived_message::{message_link, ArchivedMessage, ArchivedMessageIteration},
+    config::Config,
+    diff::{diff_lines, render_diff},
+    mong::{get_mong, log_if_slow, messages_collection, MESSAGES},
+    MainError,
+};
+
+/// Print a message's metadata and the changes between its iterations
+pub async fn run(config: Config, message_id: MessageId) -> Result<(), MainError> {
+    let mong = get_mong(&config.mong_connstring).await?;
+    let filter = doc! {
+        "id": message_id.to_string(),
+    };
+    let messages = messages_collection(&mong, &config.collection_location(MESSAGES));
+    let threshold = Duration::from_millis(config.slow_query_threshold_ms);
+    let message = log_if_slow(threshold, "find_one", filter, |filter| {
+        messages.find_one(filter, None)
+    })
+    .await?;
+
+    match message {
+        Some(message) => print!(
+            "{}",
+            render_message(&message, std::io::stdout().is_terminal())
+        ),
+        None => println!("Message {message_id} is not in the archive"),
+    }
+
+    Ok(())
+}
+
+pub fn render_message(message: &ArchivedMessage, color: bool) -> String {
+    // What every variant has in common, author and timestamp are unknown for
+    // messages we only heard of when they were deleted
+    let (label, id, channel_id, guild_id, sent, deleted, iterations, marked_as_edited) =
+        match message {
+            ArchivedMessage::Full(m) => (
+                "full",
+                m.id,
+                m.channel_id,
+                m.guild_id,
+                Some((Some(m.author_id), m.timestamp)),
+                None,
+                m.iterations.as_slice(),
+                m.marked_as_edited,
+            ),
+            ArchivedMessage::FullDeleted(m) => (
+                "full",
+                m.id,
+                m.channel_id,
+                m.guild_id,
+                Some((Some(m.author_id), m.timestamp)),
+                Some(m.deleted_timestamp),
+                m.iterations.as_slice(),
+                m.marked_as_edited,
+            ),
+            ArchivedMessage::Incomplete(m) => (
+                "incomplete",
+                m.id,
+                m.channel_id,
+                m.guild_id,
+                Some((m.author_id, m.timestamp)),
+                None,
+                m.iterations.as_slice(),
+                m.marked_as_edited,
+            ),
+            ArchivedMessage::IncompleteDeleted(m) => (
+                "incomplete",
+                m.id,
+                m.channel_id,
+                m.guild_id,
+                Some((m.author_id, m.timestamp)),
+                Some(m.deleted_timestamp),
+                m.iterations.as_slice(),
+                m.marked_as_edited,
+            ),
+            ArchivedMessage::UnknownDeleted(m) => (
+                "unknown",
+                m.id,
+                m.channel_id,
+                m.guild_id,
+                None,
+                Some(m.deleted_timestamp),
+                [].as_slice(),
+                false,
+            ),
+        };
+
+    let mut out = format!("Message {id} ({label})\n");
+    out.push_str(&format!("Channel: {channel_id}, guild: {guild_id:?}\n"));
+    out.push_str(&format!("Link: {}\n", message_link(message)));
+    if let Some(source) = message.crosspost_source() {
+        out.push_str(&format!(
+            "Originally posted in guild {}: {}\n",
+            source.guild_id,
+            source.link()
+        ));
+    }
+    if let Some(thread_id) = message.started_thread_id() {
+        out.push_str(&format!("Started thread {thread_id}\n"));
+    }
+    if let Some((author_id, timestamp)) = sent {
+        match author_id {
+            Some(author_id) => out.push_str(&format!("Author: {author_id}, sent: {timestamp}\n")),
+            None => out.push_str(&format!("Author: unknown, sent: {timestamp}\n")),
+        }
+    }
+    match deleted {
+        Some(Some(timestamp)) => out.push_str(&format!("Deleted at {timestamp}\n")),
+        Some(None) => out.push_str("Deleted at an unknown time\n"),
+        None => out.push_str("Not deleted\n"),
+    }
+    if iterations.is_empty() {
+        out.push_str("No content was archived\n");
+        return out;
+    }
+    out.push_str(&format!("Marked as edited: {marked_as_edited}\n"));
+
+    let mut previous: Option<&ArchivedMessageIteration> = None;
+    for (index, iteration) in iterations.iter().enumerate() {
+        out.push('\n');
+        out.push_str(&iteration_header(index, iteration));
+        let diff = diff_lines(
+            previous.map(|p| p.content.as_str()).unwrap_or_default(),
+            &iteration.content,
+        );
+        out.push_str(&render_diff(&diff, color));
+        if let Some(previous) = previous {
+            let changes = &iteration.attachment_changes;
+            if !changes.is_empty() {
+                out.push_str(&format!(
+                    "Attachments: {} added, {} removed\n",
+                    changes.added.len(),
+                    changes.removed.len()
+                ));
+            } else if previous.attachments.len() != iteration.attachments.len() {
+                // Iterations stored before changes were tracked
+                out.push_str(&format!(
+                    "Attachments: {} -> {}\n",
+                    previous.attachments.len(),
+                    iteration.attachments.len()
+                ));
+            }
+            if previous.embeds.len() != iteration.embeds.len() {
+                out.push_str(&format!(
+                    "Embeds: {} -> {}\n",
+                    previous.embeds.len(),
+                    iteration.embeds.len()
+                ));
+            }
+        }
+        previous = Some(iteration);
+    }
+    out
+}
+
+fn iteration_header(index: usize, iteration: &ArchivedMessageIteration) -> String {
+    let mut notes = vec![match &iteration.session_label {
+        Some(label) => format!("session {} ({label})", iteration.session_id),
+        None => format!("session {}", iteration.session_id),
+    }];
+    if iteration.may_contain_gap {
+        notes.push("may contain gap".to_string());
+    }
+    if iteration.auto_embed {
+        notes.push("auto embed".to_string());
+    }
+    if iteration.clock_adjusted {
+        notes.push("clock adjusted".to_string());
+    }
+    format!(
+        "Iteration {} at {} ({})\n",
+        index + 1,
+        iteration.timestamp,
+        notes.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        archived_message::{push_iteration, ArchivedMessageFull},
+        session::Session,
+        test_util,
+    };
+
+    fn edited_once() -> ArchivedMessageFull {
+        let session = Session::new(None);
+        let message = test_util::message(1, json!({ "content": "hello\nworld" }));
+        let mut message = ArchivedMessageFull::from_gateway(message, &session);
+        let update = test_util::update(
+            1,
+            json!({
+                "content": "hello\nthere",
+                "edited_timestamp": "2023-11-14T22:20:00.000000+00:00",
+            }),
+        );
+        let iteration = ArchivedMessageIteration::from_gateway(
+            update,
+            message.iterations.last(),
+            Utc::now(),
+            &session,
+        );
+        push_iteration(&mut message.iterations, iteration);
+        message
+    }
+
+    #[test]
+    fn renders_the_diff_between_iterations() {
+        let rendered = render_message(&ArchivedMessage::Full(edited_once()), false);
+        let (first, second) = rendered.split_once("Iteration 2").unwrap();
+        assert!(first.contains("Iteration 1"));
+        assert!(first.ends_with("+ hello\n+ world\n\n"));
+        assert!(second.ends_with("  hello\n- world\n+ there\n"));
+        assert!(rendered.contains("Not deleted\n"));
+    }
+
+    #[test]
+    fn renders_deleted_messages() {
+        let deleted = edited_once().into_deleted(None);
+        let rendered = render_message(&ArchivedMessage::FullDeleted(deleted), false);
+        assert!(rendered.contains("Deleted at an unknown time\n"));
+        assert!(rendered.contains("- world\n+ there\n"));
+    }
+
+    #[test]
+    fn colors_the_diff_for_terminals() {
+        let rendered = render_message(&ArchivedMessage::Full(edited_once()), true);
+        assert!(rendered.contains("\x1b[31m- world\x1b[0m\n\x1b[32m+ there\x1b[0m\n"));
+    }
+}
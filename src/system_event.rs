@@ -0,0 +1,144 @@
+use chrono::serde::ts_milliseconds;
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    channel::{Message, MessageType},
+    id::{ChannelId, GuildId, MessageId, UserId},
+};
+
+use crate::archived_message::{convert_ts, ArchivedMessageFull, ArchivedMessageType, Timestamp};
+
+/// Structured data extracted from a system message, stored next to the
+/// message itself for analytics
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "event_type")]
+pub enum SystemEvent {
+    MemberJoin(MemberJoin),
+    ThreadStarterDeleted(ThreadStarterDeleted),
+    GuildLeft(GuildLeft),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MemberJoin {
+    pub user_id: UserId,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: Timestamp,
+}
+
+/// The starter message of a thread was deleted, which usually means the
+/// message the thread was created from is gone and the thread may be too
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThreadStarterDeleted {
+    pub thread_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    pub message_id: MessageId,
+    /// The message in the parent channel the thread was created from
+    pub parent_message_id: Option<MessageId>,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: Timestamp,
+}
+
+impl ThreadStarterDeleted {
+    /// `None` unless `message` is the starter message of a thread
+    pub fn from_deleted(message: &ArchivedMessageFull, timestamp: Timestamp) -> Option<Self> {
+        if message.kind != ArchivedMessageType::ThreadStarterMessage {
+            return None;
+        }
+        Some(Self {
+            thread_id: message.channel_id,
+            guild_id: message.guild_id,
+            message_id: message.id,
+            parent_message_id: message
+                .message_reference
+                .as_ref()
+                .and_then(|reference| reference.message_id),
+            timestamp,
+        })
+    }
+}
+
+/// The bot was removed from a guild, not just cut off by an outage
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuildLeft {
+    pub guild_id: GuildId,
+    #[serde(with = "ts_milliseconds")]
+    pub left_at: Timestamp,
+}
+
+impl SystemEvent {
+    /// Most message types, system or not, have nothing to extract
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message.kind {
+            MessageType::MemberJoin => Some(Self::MemberJoin(MemberJoin {
+                user_id: message.author.id,
+                guild_id: message.guild_id?,
+                channel_id: message.channel_id,
+                message_id: message.id,
+                timestamp: convert_ts(message.timestamp),
+            })),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{mong::to_stored_document, session::Session, test_util};
+
+    #[test]
+    fn extracts_member_joins() {
+        let message = test_util::message(1, json!({ "type": 7 }));
+        let Some(SystemEvent::MemberJoin(join)) = SystemEvent::from_message(&message) else {
+            panic!("expected a member join");
+        };
+        assert_eq!(join.user_id, UserId(100));
+        assert_eq!(join.guild_id, GuildId(30));
+        assert_eq!(join.channel_id, ChannelId(20));
+        assert_eq!(join.message_id, MessageId(1));
+        assert_eq!(join.timestamp.timestamp_millis(), 1_700_000_000_000);
+
+        let stored = to_stored_document(&SystemEvent::MemberJoin(join)).unwrap();
+        assert_eq!(stored.get_str("event_type"), Ok("MemberJoin"));
+        assert_eq!(stored.get_str("user_id"), Ok("100"));
+    }
+
+    #[test]
+    fn messages_without_anything_to_extract_are_skipped() {
+        let regular = test_util::message(1, json!({}));
+        assert!(SystemEvent::from_message(&regular).is_none());
+        let pin = test_util::message(2, json!({ "type": 6 }));
+        assert!(SystemEvent::from_message(&pin).is_none());
+        // Joins can only happen in guilds
+        let join_without_guild = test_util::message(3, json!({ "type": 7, "guild_id": null }));
+        assert!(SystemEvent::from_message(&join_without_guild).is_none());
+    }
+
+    #[test]
+    fn deleted_thread_starters_are_recorded() {
+        // Sent in thread 20, created from message 5 in its parent channel
+        let starter = test_util::message(
+            1,
+            json!({
+                "type": 21,
+                "message_reference": { "message_id": "5", "channel_id": "21", "guild_id": "30" },
+            }),
+        );
+        let starter = ArchivedMessageFull::from_gateway(starter, &Session::new(None));
+        let deleted_at = convert_ts(starter.id.created_at());
+        let event = ThreadStarterDeleted::from_deleted(&starter, deleted_at).unwrap();
+        assert_eq!(event.thread_id, ChannelId(20));
+        assert_eq!(event.guild_id, Some(GuildId(30)));
+        assert_eq!(event.message_id, MessageId(1));
+        assert_eq!(event.parent_message_id, Some(MessageId(5)));
+        assert_eq!(event.timestamp, deleted_at);
+
+        let regular = test_util::message(2, json!({}));
+        let regular = ArchivedMessageFull::from_gateway(regular, &Session::new(None));
+        assert!(ThreadStarterDeleted::from_deleted(&regular, deleted_at).is_none());
+    }
+}
@@ -0,0 +1,62 @@
+//! Discord models for tests, built from the JSON the gateway would send
+
+use serde_json::{json, Value};
+use serenity::model::{channel::Message, event::MessageUpdateEvent};
+
+/// A plain message by user 100 in channel 20 of guild 30, with the fields in
+/// `overrides` replacing the defaults
+pub fn message(id: u64, overrides: Value) -> Message {
+    let mut message = json!({
+        "id": id.to_string(),
+        "channel_id": "20",
+        "guild_id": "30",
+        "author": {
+            "id": "100",
+            "username": "someone",
+            "discriminator": "0",
+            "avatar": null,
+        },
+        "content": "",
+        "attachments": [],
+        "embeds": [],
+        "type": 0,
+        "mention_everyone": false,
+        "mention_roles": [],
+        "mentions": [],
+        "pinned": false,
+        "timestamp": "2023-11-14T22:13:20.000000+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+    });
+    if let (Value::Object(message), Value::Object(overrides)) = (&mut message, overrides) {
+        message.extend(overrides);
+    }
+    serde_json::from_value(message).expect("test message should deserialize")
+}
+
+/// A 10 byte image attachment, as it appears in a message's JSON
+pub fn attachment(id: u64) -> Value {
+    json!({
+        "id": id.to_string(),
+        "filename": "cat.png",
+        "height": null,
+        "width": null,
+        "proxy_url": "https://media.discordapp.net/cat.png",
+        "url": "https://cdn.discordapp.com/cat.png",
+        "size": 10,
+        "content_type": "image/png",
+    })
+}
+
+/// An edit of message `id` in channel 20, with only the fields in `fields`
+/// set
+pub fn update(id: u64, fields: Value) -> MessageUpdateEvent {
+    let mut update = json!({
+        "id": id.to_string(),
+        "channel_id": "20",
+    });
+    if let (Value::Object(update), Value::Object(fields)) = (&mut update, fields) {
+        update.extend(fields);
+    }
+    serde_json::from_value(update).expect("test update should deserialize")
+}
@@ -0,0 +1,79 @@
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    event::TypingStartEvent,
+    id::{ChannelId, GuildId, UserId},
+};
+
+use crate::archived_message::Timestamp;
+
+/// Someone started typing in a channel
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TypingEvent {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    /// Stored as a BSON date so the TTL index can expire the event
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub timestamp: Timestamp,
+}
+
+impl TypingEvent {
+    pub fn from_gateway(event: TypingStartEvent) -> Self {
+        Self {
+            user_id: event.user_id,
+            channel_id: event.channel_id,
+            guild_id: event.guild_id,
+            timestamp: i64::try_from(event.timestamp)
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+                .unwrap_or_else(Utc::now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mong::to_stored_document;
+
+    fn typing_start(timestamp: u64) -> TypingStartEvent {
+        serde_json::from_value(json!({
+            "channel_id": "20",
+            "guild_id": "30",
+            "user_id": "100",
+            "timestamp": timestamp,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn stores_the_timestamp_as_a_date_for_the_ttl_index() {
+        let event = TypingEvent::from_gateway(typing_start(1_700_000_000));
+        let stored = to_stored_document(&event).unwrap();
+        assert_eq!(stored.get_str("user_id"), Ok("100"));
+        assert_eq!(stored.get_str("channel_id"), Ok("20"));
+        assert_eq!(stored.get_str("guild_id"), Ok("30"));
+        assert_eq!(
+            stored.get("timestamp"),
+            Some(&Bson::DateTime(bson::DateTime::from_millis(
+                1_700_000_000_000
+            )))
+        );
+
+        let read: TypingEvent = bson::from_document(stored).unwrap();
+        assert_eq!(read.timestamp, event.timestamp);
+    }
+
+    #[test]
+    fn out_of_range_timestamps_fall_back_to_now() {
+        let before = Utc::now();
+        for timestamp in [i64::MAX as u64, u64::MAX] {
+            let event = TypingEvent::from_gateway(typing_start(timestamp));
+            assert!(event.timestamp >= before, "timestamp {timestamp}");
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use serde::Serialize;
+use serenity::model::id::MessageId;
 
 #[allow(dead_code)]
 fn serde_print<T>(value: &T)
@@ -10,3 +11,103 @@ where
         Err(e) => println!("Failed to serialize for print: {e}"),
     }
 }
+
+/// Longest file name most filesystems accept
+const MAX_FILE_NAME_LENGTH: usize = 255;
+/// Extensions longer than this are cut off like the rest of the name
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// Derive a name that is safe to store an attachment under on disk
+///
+/// The name uploaded to Discord is user-controlled, so only ASCII
+/// alphanumerics, `-`, `_` and inner dots are kept, and it is prefixed with the
+/// message id and attachment index so names never collide. The original name
+/// stays in the attachment record.
+pub fn safe_attachment_file_name(message_id: MessageId, index: usize, file_name: &str) -> String {
+    let sanitized: String = file_name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    // No hidden files, `.` or `..`
+    let sanitized = match sanitized.trim_start_matches('.') {
+        "" => "attachment",
+        sanitized => sanitized,
+    };
+
+    let prefix = format!("{message_id}_{index}_");
+    let budget = MAX_FILE_NAME_LENGTH - prefix.len();
+    if sanitized.len() <= budget {
+        return prefix + sanitized;
+    }
+    let (stem, extension) = match sanitized.rfind('.') {
+        Some(dot) if sanitized.len() - dot <= MAX_EXTENSION_LENGTH => sanitized.split_at(dot),
+        _ => (sanitized, ""),
+    };
+    // Everything is ASCII at this point, so any byte index is a char boundary
+    let stem = &stem[..stem.len().min(budget - extension.len())];
+    format!("{prefix}{stem}{extension}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: MessageId = MessageId(1234);
+
+    #[test]
+    fn keeps_plain_names() {
+        assert_eq!(
+            safe_attachment_file_name(MESSAGE, 0, "cat.png"),
+            "1234_0_cat.png"
+        );
+    }
+
+    #[test]
+    fn defuses_path_traversal() {
+        let name = safe_attachment_file_name(MESSAGE, 1, "../../etc/passwd");
+        assert_eq!(name, "1234_1__.._etc_passwd");
+        assert!(!name.contains('/'));
+        assert_eq!(
+            safe_attachment_file_name(MESSAGE, 1, "..\\..\\boot.ini"),
+            "1234_1__.._boot.ini"
+        );
+    }
+
+    #[test]
+    fn replaces_null_bytes() {
+        assert_eq!(
+            safe_attachment_file_name(MESSAGE, 2, "evil.php\0.png"),
+            "1234_2_evil.php_.png"
+        );
+    }
+
+    #[test]
+    fn never_yields_dot_names() {
+        assert_eq!(
+            safe_attachment_file_name(MESSAGE, 3, ".."),
+            "1234_3_attachment"
+        );
+        assert_eq!(
+            safe_attachment_file_name(MESSAGE, 3, ".bashrc"),
+            "1234_3_bashrc"
+        );
+    }
+
+    #[test]
+    fn shortens_long_names_keeping_the_extension() {
+        let name = safe_attachment_file_name(MESSAGE, 4, &format!("{}.png", "a".repeat(400)));
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH);
+        assert!(name.starts_with("1234_4_aaa"));
+        assert!(name.ends_with("a.png"));
+    }
+
+    #[test]
+    fn shortens_long_extensions_with_the_name() {
+        let name = safe_attachment_file_name(MESSAGE, 5, &format!("a.{}", "b".repeat(400)));
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH);
+        assert!(name.starts_with("1234_5_a.bbb"));
+    }
+}
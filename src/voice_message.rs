@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::{request::RequestBuilder, routing::RouteInfo, Http},
+    model::{
+        channel::Attachment,
+        id::{AttachmentId, ChannelId, MessageId},
+    },
+};
+
+/// The extra data Discord sends with the recording of a voice message
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VoiceAttachment {
+    #[serde(rename = "id")]
+    pub attachment_id: AttachmentId,
+    pub duration_secs: f64,
+    /// Base64 encoded samples of the volume, one byte each
+    pub waveform: String,
+}
+
+/// Voice messages are sent as a single ogg file with a fixed name
+pub fn looks_like_voice_message(attachment: &Attachment) -> bool {
+    attachment.filename == "voice-message.ogg"
+        && attachment.content_type.as_deref() == Some("audio/ogg")
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    attachments: Vec<RawAttachment>,
+}
+
+#[derive(Deserialize)]
+struct RawAttachment {
+    id: AttachmentId,
+    duration_secs: Option<f64>,
+    waveform: Option<String>,
+}
+
+/// Fetch the duration and waveform of a message's voice attachments
+///
+/// Serenity drops these fields when parsing attachments, so the message is
+/// requested again and read without it.
+pub async fn fetch_voice_attachments(
+    http: &Http,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> serenity::Result<Vec<VoiceAttachment>> {
+    let request = RequestBuilder::new(RouteInfo::GetMessage {
+        channel_id: channel_id.0,
+        message_id: message_id.0,
+    })
+    .build();
+    let message: RawMessage = http.request(request).await?.json().await?;
+    Ok(voice_attachments(message))
+}
+
+/// The attachments of a message that came with a duration and waveform
+fn voice_attachments(message: RawMessage) -> Vec<VoiceAttachment> {
+    message
+        .attachments
+        .into_iter()
+        .filter_map(|attachment| {
+            Some(VoiceAttachment {
+                attachment_id: attachment.id,
+                duration_secs: attachment.duration_secs?,
+                waveform: attachment.waveform?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn keeps_duration_and_waveform_of_voice_attachments_only() {
+        let mut voice = test_util::attachment(1);
+        voice["filename"] = json!("voice-message.ogg");
+        voice["content_type"] = json!("audio/ogg");
+        voice["duration_secs"] = json!(3.5);
+        voice["waveform"] = json!("AAECAw==");
+        let message: RawMessage = serde_json::from_value(json!({
+            "attachments": [voice, test_util::attachment(2)],
+        }))
+        .unwrap();
+
+        let found = voice_attachments(message);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].attachment_id, AttachmentId(1));
+        assert_eq!(found[0].duration_secs, 3.5);
+        assert_eq!(found[0].waveform, "AAECAw==");
+    }
+
+    #[test]
+    fn serializes_voice_attachment() {
+        let voice = VoiceAttachment {
+            attachment_id: AttachmentId(1),
+            duration_secs: 3.5,
+            waveform: "AAECAw==".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&voice).unwrap(),
+            json!({ "id": "1", "duration_secs": 3.5, "waveform": "AAECAw==" })
+        );
+    }
+
+    #[test]
+    fn recognizes_voice_message_recordings() {
+        let mut voice = test_util::attachment(1);
+        voice["filename"] = json!("voice-message.ogg");
+        voice["content_type"] = json!("audio/ogg");
+        let voice: Attachment = serde_json::from_value(voice).unwrap();
+        let image: Attachment = serde_json::from_value(test_util::attachment(2)).unwrap();
+        assert!(looks_like_voice_message(&voice));
+        assert!(!looks_like_voice_message(&image));
+    }
+}
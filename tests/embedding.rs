@@ -0,0 +1,43 @@
+//! The library as an embedder uses it, run with `--no-default-features` to
+//! check it builds without the `cli` feature
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use discord_archive_selfbot::{
+    archived_message::ArchivedMessage,
+    archiver,
+    config::Config,
+    hook::{ArchiveHook, HookOutcome},
+    publisher::ArchiveNoticeKind,
+    Mode,
+};
+
+struct KeepEverything;
+
+#[async_trait]
+impl ArchiveHook for KeepEverything {
+    fn name(&self) -> &str {
+        "keep-everything"
+    }
+
+    async fn before_write(
+        &self,
+        _message: &mut ArchivedMessage,
+        _kind: ArchiveNoticeKind,
+    ) -> HookOutcome {
+        HookOutcome::Write
+    }
+}
+
+#[test]
+fn archiver_can_be_started_with_hooks() {
+    // Only built, not polled, there's nothing to connect to
+    let _archiving = archiver::run_with_hooks(Config::default(), vec![Arc::new(KeepEverything)]);
+}
+
+#[test]
+fn modes_are_plain_serde_enums() {
+    let mode: Mode = serde_json::from_str("\"export-user\"").unwrap();
+    assert_eq!(mode, Mode::ExportUser);
+}